@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+use crate::normalize::album_dir_of;
+use crate::schema::{Album, Artist, Track};
+
+/// Artwork file names looked up in each album directory, in order of preference.
+const COVER_FILE_NAMES: &[&str] = &[
+    "cover.jpg",
+    "cover.jpeg",
+    "cover.png",
+    "folder.jpg",
+    "folder.png",
+    "front.jpg",
+    "front.png",
+];
+
+/// Groups indexed tracks into albums keyed by their album directory relative
+/// to `root`, so the discs of an album in `CD1`, `CD2`... are one album.
+///
+/// Album-level values (title, album artist, year) use the most common value
+/// among the album's tracks so one mistagged track does not split the album.
+pub fn build_albums(root: &Path, tracks: &BTreeMap<String, Track>) -> BTreeMap<String, Album> {
+    let mut groups: BTreeMap<String, Vec<(&String, &Track)>> = BTreeMap::new();
+    for (path, track) in tracks {
+        groups
+            .entry(album_key(path))
+            .or_default()
+            .push((path, track));
+    }
+
+    groups
+        .into_iter()
+        .map(|(key, tracks)| {
+            let cover = find_cover(root, &key);
+            (key, build_album(&tracks, cover))
+        })
+        .collect()
+}
+
+/// Groups albums by album artist.
+pub fn build_artists(albums: &BTreeMap<String, Album>) -> BTreeMap<String, Artist> {
+    let mut artists: BTreeMap<String, Artist> = BTreeMap::new();
+    for (key, album) in albums {
+        let artist = artists.entry(album.album_artist.clone()).or_default();
+        artist.albums.push(key.clone());
+        artist.track_count += album.track_count;
+        artist.duration += album.duration;
+    }
+    artists
+}
+
+/// Returns the album key for a track path: its parent directory, or the
+/// parent's parent when the parent is a disc directory.
+pub fn album_key(path: &str) -> String {
+    album_dir_of(Path::new(path))
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn build_album(tracks: &[(&String, &Track)], cover: Option<String>) -> Album {
+    let mut discs: BTreeMap<u32, (BTreeSet<u32>, Option<u32>)> = BTreeMap::new();
+    for (_, track) in tracks {
+        let disc = discs.entry(track.disk_number.unwrap_or(1)).or_default();
+        if let Some(number) = track.track_number {
+            disc.0.insert(number);
+        }
+        disc.1 = disc.1.max(track.track_number_total);
+    }
+
+    let missing_tracks = discs
+        .iter()
+        .filter_map(|(disc, (numbers, total))| {
+            let last = numbers.iter().max().copied().max(*total)?;
+            let missing = (1..=last)
+                .filter(|number| !numbers.contains(number))
+                .collect::<Vec<_>>();
+            (!missing.is_empty()).then_some((*disc, missing))
+        })
+        .collect();
+
+    let disc_total = tracks.iter().filter_map(|(_, t)| t.disk_number_total).max();
+    let duration_ms: u64 = tracks
+        .iter()
+        .map(|(_, t)| {
+            t.duration_ms
+                .or(t.duration.map(|secs| secs * 1000))
+                .unwrap_or(0)
+        })
+        .sum();
+
+    Album {
+        title: most_common(tracks.iter().map(|(_, t)| t.album.as_str()))
+            .unwrap_or_default()
+            .to_owned(),
+        album_artist: most_common(
+            tracks
+                .iter()
+                .map(|(_, t)| t.album_artist.as_deref().unwrap_or(&t.artist)),
+        )
+        .unwrap_or_default()
+        .to_owned(),
+        year: most_common(tracks.iter().filter_map(|(_, t)| t.year)),
+        compilation: tracks.iter().any(|(_, t)| t.compilation),
+        duration: duration_ms / 1000,
+        track_count: tracks.len(),
+        disc_count: disc_total.unwrap_or(0).max(discs.len() as u32),
+        missing_tracks,
+        cover,
+        tracks: tracks.iter().map(|(path, _)| (*path).clone()).collect(),
+    }
+}
+
+fn find_cover(root: &Path, key: &str) -> Option<String> {
+    COVER_FILE_NAMES
+        .iter()
+        .map(|name| Path::new(key).join(name))
+        .find(|relative| root.join(relative).is_file())
+        .map(|relative| relative.to_string_lossy().into_owned())
+}
+
+/// Returns the most frequent value, preferring the first seen on ties.
+pub(crate) fn most_common<T, I>(values: I) -> Option<T>
+where
+    T: Eq + std::hash::Hash,
+    I: Iterator<Item = T>,
+{
+    let mut counts: HashMap<T, (usize, usize)> = HashMap::new();
+    for (order, value) in values.enumerate() {
+        counts.entry(value).or_insert((0, order)).0 += 1;
+    }
+    counts
+        .into_iter()
+        .max_by(|(_, (a_count, a_order)), (_, (b_count, b_order))| {
+            a_count.cmp(b_count).then(b_order.cmp(a_order))
+        })
+        .map(|(value, _)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::{album_key, build_albums, build_artists, most_common};
+    use crate::schema::Track;
+
+    fn track(album_artist: &str, disc: u32, number: u32, total: u32) -> Track {
+        Track {
+            album: "Album".to_owned(),
+            artist: "Someone".to_owned(),
+            album_artist: Some(album_artist.to_owned()),
+            year: Some(1999),
+            duration: Some(100),
+            track_number: Some(number),
+            track_number_total: Some(total),
+            disk_number: Some(disc),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn most_common_prefers_the_first_seen_on_ties() {
+        assert_eq!(most_common(vec!["a", "b", "b"].into_iter()), Some("b"));
+        assert_eq!(most_common(vec!["a", "b"].into_iter()), Some("a"));
+        assert_eq!(most_common(std::iter::empty::<&str>()), None);
+    }
+
+    #[test]
+    fn groups_tracks_by_directory_with_consensus_values() {
+        let dir = tempdir().expect("tempdir");
+        fs::create_dir_all(dir.path().join("artist/album")).expect("mkdirs");
+        fs::write(dir.path().join("artist/album/cover.jpg"), b"jpg").expect("write");
+        let tracks = BTreeMap::from([
+            ("artist/album/01.flac".to_owned(), track("Artist", 1, 1, 4)),
+            ("artist/album/02.flac".to_owned(), track("Artist", 1, 2, 4)),
+            ("artist/album/04.flac".to_owned(), track("Artsit", 1, 4, 4)),
+            (
+                "artist/album/2-01.flac".to_owned(),
+                track("Artist", 2, 2, 2),
+            ),
+        ]);
+
+        let albums = build_albums(dir.path(), &tracks);
+
+        let album = &albums["artist/album"];
+        assert_eq!(album.title, "Album");
+        assert_eq!(album.album_artist, "Artist");
+        assert_eq!(album.year, Some(1999));
+        assert_eq!(album.duration, 400);
+        assert_eq!(album.track_count, 4);
+        assert_eq!(album.disc_count, 2);
+        assert_eq!(
+            album.missing_tracks,
+            BTreeMap::from([(1, vec![3]), (2, vec![1])])
+        );
+        assert_eq!(album.cover.as_deref(), Some("artist/album/cover.jpg"));
+        assert_eq!(album.tracks.len(), 4);
+    }
+
+    #[test]
+    fn disc_directories_belong_to_their_album() {
+        assert_eq!(album_key("artist/album/CD2/01.flac"), "artist/album");
+        assert_eq!(album_key("artist/album/01.flac"), "artist/album");
+        assert_eq!(album_key("01.flac"), "");
+
+        let dir = tempdir().expect("tempdir");
+        let tracks = BTreeMap::from([
+            (
+                "artist/album/CD1/01.flac".to_owned(),
+                track("Artist", 1, 1, 1),
+            ),
+            (
+                "artist/album/CD2/01.flac".to_owned(),
+                track("Artist", 2, 1, 1),
+            ),
+        ]);
+
+        let albums = build_albums(dir.path(), &tracks);
+
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums["artist/album"].disc_count, 2);
+    }
+
+    #[test]
+    fn groups_albums_by_album_artist() {
+        let dir = tempdir().expect("tempdir");
+        let tracks = BTreeMap::from([
+            ("artist/one/01.flac".to_owned(), track("Artist", 1, 1, 1)),
+            ("artist/two/01.flac".to_owned(), track("Artist", 1, 1, 1)),
+            ("other/one/01.flac".to_owned(), track("Other", 1, 1, 1)),
+        ]);
+
+        let artists = build_artists(&build_albums(dir.path(), &tracks));
+
+        assert_eq!(artists.len(), 2);
+        assert_eq!(artists["Artist"].albums, vec!["artist/one", "artist/two"]);
+        assert_eq!(artists["Artist"].track_count, 2);
+        assert_eq!(artists["Artist"].duration, 200);
+    }
+}
@@ -0,0 +1,200 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::fs_utils::write_atomic;
+use crate::schema::{FieldSet, Track, INDEX_VERSION};
+
+/// Number of recorded tracks between flushes of the journal to disk.
+const FLUSH_INTERVAL: usize = 50;
+
+/// First line of a journal, describing how its tracks were extracted.
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+struct Header {
+    version: u32,
+    fields: FieldSet,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    path: String,
+    track: Track,
+}
+
+#[derive(Serialize)]
+struct EntryRef<'a> {
+    path: &'a str,
+    track: &'a Track,
+}
+
+/// Append-only journal of tracks extracted during an index run.
+///
+/// Each track is appended as a JSON line as soon as it is extracted, so an
+/// interrupted scan can pick up the finished tracks on the next run instead
+/// of extracting them again. The journal is removed once the index itself
+/// has been written.
+pub struct Checkpoint {
+    path: PathBuf,
+    writer: Mutex<(BufWriter<File>, usize)>,
+}
+
+/// Returns the journal path used while building the index at `index_path`.
+pub fn checkpoint_path(index_path: &Path) -> PathBuf {
+    let mut name = index_path
+        .file_name()
+        .map(OsString::from)
+        .unwrap_or_default();
+    name.push(".partial");
+    index_path.with_file_name(name)
+}
+
+impl Checkpoint {
+    /// Opens the journal at `path`, returning the tracks recovered from an
+    /// earlier interrupted run.
+    ///
+    /// Journals written by another index version or field set are discarded.
+    /// A torn final line from a crash mid-write is ignored.
+    pub fn open(
+        path: &Path,
+        fields: FieldSet,
+    ) -> io::Result<(Checkpoint, BTreeMap<String, Track>)> {
+        let header = Header {
+            version: INDEX_VERSION,
+            fields,
+        };
+        let recovered = load(path, &header);
+
+        // Rewrite the journal so it starts with a valid header and contains
+        // only complete lines before appending to it
+        let mut contents = serde_json::to_string(&header)?;
+        contents.push('\n');
+        for (path, track) in &recovered {
+            contents.push_str(&serde_json::to_string(&EntryRef { path, track })?);
+            contents.push('\n');
+        }
+        write_atomic(path, contents.as_bytes())?;
+
+        let file = OpenOptions::new().append(true).open(path)?;
+        let checkpoint = Checkpoint {
+            path: path.to_path_buf(),
+            writer: Mutex::new((BufWriter::new(file), 0)),
+        };
+        Ok((checkpoint, recovered))
+    }
+
+    /// Appends an extracted track, flushing every few tracks.
+    pub fn record(&self, path: &str, track: &Track) -> io::Result<()> {
+        let line = serde_json::to_string(&EntryRef { path, track })?;
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let (file, pending) = &mut *writer;
+        writeln!(file, "{}", line)?;
+        *pending += 1;
+        if *pending >= FLUSH_INTERVAL {
+            *pending = 0;
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Removes the journal once its tracks are safely in the index.
+    pub fn finish(self) -> io::Result<()> {
+        drop(self.writer);
+        remove(&self.path)
+    }
+}
+
+/// Deletes a journal, if there is one.
+pub fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn load(path: &Path, expected: &Header) -> BTreeMap<String, Track> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(_) => return BTreeMap::new(),
+    };
+    let mut lines = contents.lines();
+    let header = lines
+        .next()
+        .and_then(|line| serde_json::from_str::<Header>(line).ok());
+    if header.as_ref() != Some(expected) {
+        return BTreeMap::new();
+    }
+    lines
+        .filter_map(|line| serde_json::from_str::<Entry>(line).ok())
+        .map(|entry| (entry.path, entry.track))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::Path;
+
+    use tempfile::tempdir;
+
+    use super::{checkpoint_path, Checkpoint};
+    use crate::schema::{FieldSet, Track};
+
+    fn track(mtime: u64) -> Track {
+        Track {
+            mtime: Some(mtime),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn names_journal_after_index() {
+        assert_eq!(
+            checkpoint_path(Path::new("/music/index.json")),
+            Path::new("/music/index.json.partial")
+        );
+    }
+
+    #[test]
+    fn recovers_recorded_tracks_after_interruption() {
+        let dir = tempdir().expect("tempdir");
+        let path = dir.path().join("index.json.partial");
+
+        let (checkpoint, recovered) = Checkpoint::open(&path, FieldSet::Minimal).expect("open");
+        assert!(recovered.is_empty());
+        checkpoint.record("a.flac", &track(1)).expect("record");
+        checkpoint.record("b.flac", &track(2)).expect("record");
+        // Dropping without `finish` simulates an interrupted run
+        drop(checkpoint);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(b"{\"path\":\"c.fl"))
+            .expect("append torn line");
+
+        let (checkpoint, recovered) = Checkpoint::open(&path, FieldSet::Minimal).expect("reopen");
+        assert_eq!(recovered.len(), 2);
+        assert_eq!(recovered["b.flac"].mtime, Some(2));
+
+        checkpoint.finish().expect("finish");
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn discards_journal_from_other_field_set() {
+        let dir = tempdir().expect("tempdir");
+        let path = dir.path().join("index.json.partial");
+        let (checkpoint, _) = Checkpoint::open(&path, FieldSet::Minimal).expect("open");
+        checkpoint.record("a.flac", &track(1)).expect("record");
+        drop(checkpoint);
+
+        let (_, recovered) = Checkpoint::open(&path, FieldSet::Full).expect("reopen");
+
+        assert!(recovered.is_empty());
+        assert_eq!(fs::read_to_string(&path).expect("read").lines().count(), 1);
+    }
+}
@@ -0,0 +1,348 @@
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::fs_utils::write_atomic;
+use crate::text::{FsProfile, TidyPolicy};
+
+/// File in the destination root recording the names given to sources.
+pub const NAMES_FILE: &str = ".loot-names.json";
+
+fn default_separator() -> char {
+    TidyPolicy::default().separator
+}
+
+fn default_max_length() -> usize {
+    TidyPolicy::default().max_length
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NamesFile {
+    filesystem: FsProfile,
+    /// Replaces the characters the filesystem forbids.
+    #[serde(default = "default_separator")]
+    separator: char,
+    #[serde(default = "default_max_length")]
+    max_length: usize,
+    /// Destination path of each source path, both relative to their roots
+    /// and with the source's extension.
+    names: BTreeMap<String, String>,
+}
+
+/// Names of the files `transcode` writes to a destination whose filesystem
+/// restricts names, such as a FAT32 SD card.
+///
+/// A source is given a sanitized name the first time it is transcoded. The
+/// name is recorded in the destination and reused on later runs, so it stays
+/// stable when the rules change, and `prune` uses the record to find the
+/// source of a sanitized file. Without a destination filesystem or a record,
+/// names are used verbatim.
+#[derive(Debug, Default)]
+pub struct DestNames {
+    /// Filesystem, separator and maximum length of new names.
+    policy: TidyPolicy,
+    active: bool,
+    names: BTreeMap<PathBuf, PathBuf>,
+    /// Source of each recorded name, keyed by `key`.
+    sources: BTreeMap<PathBuf, PathBuf>,
+    changed: bool,
+}
+
+impl DestNames {
+    /// Reads the record in `dest`. New names follow the filesystem,
+    /// separator and maximum length of `policy`, or the recorded ones when it
+    /// is `None`.
+    pub fn open(dest: &Path, policy: Option<&TidyPolicy>) -> DestNames {
+        let path = dest.join(NAMES_FILE);
+        let file = fs::read_to_string(&path).ok().and_then(|contents| {
+            serde_json::from_str::<NamesFile>(&contents)
+                .map_err(|e| eprintln!("{} Ignoring {}: {}", "✗".red().bold(), path.display(), e))
+                .ok()
+        });
+        let active = file.is_some()
+            || matches!(policy, Some(policy) if policy.filesystem != FsProfile::Ext4);
+        let policy = match (policy, &file) {
+            (Some(policy), _) => policy.clone(),
+            (None, Some(file)) => TidyPolicy {
+                filesystem: file.filesystem,
+                separator: file.separator,
+                max_length: file.max_length,
+                ..Default::default()
+            },
+            (None, None) => TidyPolicy::default(),
+        };
+        let mut names = DestNames {
+            policy,
+            active,
+            ..Default::default()
+        };
+        for (source, name) in file.map(|file| file.names).unwrap_or_default() {
+            names.insert(PathBuf::from(source), PathBuf::from(name));
+        }
+        names
+    }
+
+    /// Key under which a destination name is looked up: the name without
+    /// extension, case-folded when the filesystem ignores case, so names of
+    /// files or directories differing only in case are the same.
+    fn key(&self, name: &Path) -> PathBuf {
+        let key = name.with_extension("");
+        if self.policy.filesystem.is_windows() {
+            PathBuf::from(key.to_string_lossy().to_lowercase())
+        } else {
+            key
+        }
+    }
+
+    fn insert(&mut self, source: PathBuf, name: PathBuf) {
+        self.sources.insert(self.key(&name), source.clone());
+        self.names.insert(source, name);
+    }
+
+    fn sanitize(&self, relative: &Path) -> PathBuf {
+        relative
+            .components()
+            .map(|component| match component {
+                Component::Normal(name) => {
+                    PathBuf::from(self.policy.component(&name.to_string_lossy()))
+                }
+                other => PathBuf::from(other.as_os_str()),
+            })
+            .collect()
+    }
+
+    /// Returns the destination path of the file or directory at `relative`
+    /// below a source root, keeping its extension.
+    pub fn name(&self, relative: &Path) -> PathBuf {
+        if !self.active {
+            return relative.to_path_buf();
+        }
+        match self.names.get(relative) {
+            Some(name) => name.clone(),
+            None => self.sanitize(relative),
+        }
+    }
+
+    /// Like `name`, but records the name of a source file seen for the first
+    /// time, numbering it when another source already has the name.
+    pub fn assign(&mut self, relative: &Path) -> PathBuf {
+        if !self.active || self.names.contains_key(relative) {
+            return self.name(relative);
+        }
+        let sanitized = self.sanitize(relative);
+        let mut name = sanitized.clone();
+        let mut number = 1;
+        while self.sources.contains_key(&self.key(&name)) {
+            number += 1;
+            let separator = self.policy.separator;
+            let mut suffix = format!("{}{}", separator, number);
+            if let Some(extension) = sanitized.extension() {
+                suffix.push('.');
+                suffix.push_str(&extension.to_string_lossy());
+            }
+            // Truncate the stem so the number always survives
+            let filesystem = self.policy.filesystem;
+            let max_length = self.policy.max_length.min(filesystem.max_length());
+            let stem = filesystem.sanitize_component(
+                &sanitized.file_stem().unwrap_or_default().to_string_lossy(),
+                separator,
+                max_length.saturating_sub(filesystem.length(&suffix)),
+            );
+            // A stem ending in a replaced character already ends with the separator
+            if stem.ends_with(separator) {
+                suffix.remove(0);
+            }
+            name = sanitized.with_file_name(stem + &suffix);
+        }
+        self.insert(relative.to_path_buf(), name.clone());
+        self.changed = true;
+        name
+    }
+
+    /// Returns the source recorded for the destination file at `relative`,
+    /// whatever its extension.
+    pub fn source_of(&self, relative: &Path) -> Option<&Path> {
+        self.sources.get(&self.key(relative)).map(PathBuf::as_path)
+    }
+
+    /// Drops the record of the source at `relative`.
+    pub fn forget(&mut self, relative: &Path) {
+        if let Some(name) = self.names.remove(relative) {
+            let key = self.key(&name);
+            self.sources.remove(&key);
+            self.changed = true;
+        }
+    }
+
+    /// Saves the record unless this is a dry run, reporting errors.
+    pub fn finish(&self, dest: &Path, dry_run: bool) {
+        if dry_run {
+            return;
+        }
+        if let Err(e) = self.save(dest) {
+            eprintln!(
+                "{} Error recording destination names: {}",
+                "✗".red().bold(),
+                e
+            );
+        }
+    }
+
+    /// Writes the record to `dest` if names were added or dropped.
+    pub fn save(&self, dest: &Path) -> io::Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        let file = NamesFile {
+            filesystem: self.policy.filesystem,
+            separator: self.policy.separator,
+            max_length: self.policy.max_length,
+            names: self
+                .names
+                .iter()
+                .map(|(source, name)| {
+                    (
+                        source.to_string_lossy().into_owned(),
+                        name.to_string_lossy().into_owned(),
+                    )
+                })
+                .collect(),
+        };
+        fs::create_dir_all(dest)?;
+        write_atomic(
+            &dest.join(NAMES_FILE),
+            serde_json::to_string_pretty(&file)?.as_bytes(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tempfile::tempdir;
+
+    use super::DestNames;
+    use crate::text::{FsProfile, TidyPolicy};
+
+    fn policy(filesystem: FsProfile) -> TidyPolicy {
+        TidyPolicy {
+            filesystem,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn names_are_verbatim_without_a_destination_filesystem() {
+        let dest = tempdir().expect("tempdir");
+        let mut names = DestNames::open(dest.path(), None);
+
+        assert_eq!(
+            names.assign(Path::new("A/Why?.flac")),
+            Path::new("A/Why?.flac")
+        );
+        names.save(dest.path()).expect("save");
+        assert!(!dest.path().join(super::NAMES_FILE).exists());
+    }
+
+    #[test]
+    fn sanitized_names_are_numbered_and_recorded() {
+        let dest = tempdir().expect("tempdir");
+        let mut names = DestNames::open(dest.path(), Some(&policy(FsProfile::Fat32)));
+
+        assert_eq!(
+            names.assign(Path::new("AC:DC/Live.../01 Why?.flac")),
+            Path::new("AC_DC/Live/01 Why_.flac")
+        );
+        assert_eq!(
+            names.assign(Path::new("AC:DC/Live.../01 Why*.flac")),
+            Path::new("AC_DC/Live/01 Why_2.flac")
+        );
+        names.save(dest.path()).expect("save");
+
+        // Later runs reuse the record, whatever the filesystem asked for
+        let reopened = DestNames::open(dest.path(), None);
+        assert_eq!(
+            reopened.name(Path::new("AC:DC/Live.../01 Why*.flac")),
+            Path::new("AC_DC/Live/01 Why_2.flac")
+        );
+        assert_eq!(
+            reopened.source_of(Path::new("AC_DC/Live/01 Why_2.opus")),
+            Some(Path::new("AC:DC/Live.../01 Why*.flac"))
+        );
+        assert_eq!(reopened.name(Path::new("Lists<1>")), Path::new("Lists_1_"));
+    }
+
+    #[test]
+    fn numbered_names_fit_the_length_limit_in_filesystem_units() {
+        let dest = tempdir().expect("tempdir");
+        let policy = TidyPolicy {
+            separator: '·',
+            max_length: 10,
+            ..policy(FsProfile::Fat32)
+        };
+        let mut names = DestNames::open(dest.path(), Some(&policy));
+
+        assert_eq!(
+            names.assign(Path::new("abcdefgh.flac")),
+            Path::new("abcde.flac")
+        );
+        // The separator is one UTF-16 unit, though two bytes
+        assert_eq!(
+            names.assign(Path::new("abcdefgh?.flac")),
+            Path::new("abc·2.flac")
+        );
+    }
+
+    #[test]
+    fn names_differing_in_case_collide_on_windows_filesystems() {
+        let dest = tempdir().expect("tempdir");
+        let mut names = DestNames::open(dest.path(), Some(&policy(FsProfile::Ntfs)));
+
+        assert_eq!(
+            names.assign(Path::new("AC:DC/Song.flac")),
+            Path::new("AC_DC/Song.flac")
+        );
+        assert_eq!(
+            names.assign(Path::new("Ac:Dc/SONG.flac")),
+            Path::new("Ac_Dc/SONG_2.flac")
+        );
+        assert_eq!(
+            names.source_of(Path::new("ac_dc/song.opus")),
+            Some(Path::new("AC:DC/Song.flac"))
+        );
+    }
+
+    #[test]
+    fn names_follow_the_separator_and_max_length() {
+        let dest = tempdir().expect("tempdir");
+        let custom = TidyPolicy {
+            separator: ' ',
+            max_length: 16,
+            ..policy(FsProfile::Exfat)
+        };
+        let mut names = DestNames::open(dest.path(), Some(&custom));
+
+        assert_eq!(
+            names.assign(Path::new("Live: Take/Take: 1.flac")),
+            Path::new("Live Take/Take 1.flac")
+        );
+        assert_eq!(
+            names.assign(Path::new("Live: Take/Take? 1.flac")),
+            Path::new("Live Take/Take 1 2.flac")
+        );
+        assert_eq!(
+            names.assign(Path::new("Live: Take/Champagne Supernova.flac")),
+            Path::new("Live Take/Champagne S.flac")
+        );
+        names.save(dest.path()).expect("save");
+
+        // New names keep following the recorded rules
+        let reopened = DestNames::open(dest.path(), None);
+        assert_eq!(reopened.name(Path::new("A: B")), Path::new("A B"));
+    }
+}
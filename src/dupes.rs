@@ -0,0 +1,626 @@
+use colored::*;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+use crate::aggregate::{album_key, build_albums};
+use crate::fingerprint;
+use crate::query::load_library_tracks;
+use crate::schema::{Album, Track};
+use crate::text::tidy_string;
+
+/// Extensions and codecs treated as lossless when choosing which copy to keep.
+const LOSSLESS: &[&str] = &["flac", "wav", "ape", "wv", "wavpack", "alac", "aiff", "pcm"];
+
+/// How `loot dupes` prints the groups it finds.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum DupesOutput {
+    /// A readable listing with the reasons for each proposal.
+    #[default]
+    Table,
+    /// One JSON object per group.
+    Json,
+}
+
+/// Options controlling a `dupes` run.
+#[derive(Copy, Clone, Debug)]
+pub struct DupesOptions {
+    /// Maximum difference in duration, per track, between copies.
+    pub tolerance_secs: u64,
+    pub output: DupesOutput,
+}
+
+impl Default for DupesOptions {
+    fn default() -> Self {
+        DupesOptions {
+            tolerance_secs: 2,
+            output: DupesOutput::Table,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateKind {
+    Album,
+    Track,
+}
+
+/// Copies of one album or track, best copy first.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateGroup {
+    pub kind: DuplicateKind,
+    pub artist: String,
+    pub title: String,
+    /// Album directory or track path proposed to keep.
+    pub keep: String,
+    /// The remaining copies, best first.
+    pub others: Vec<String>,
+}
+
+/// Ranking of a copy: lossless first, then bitrate, then tag completeness.
+#[derive(Copy, Clone, Debug, Default, PartialEq, PartialOrd)]
+struct Quality {
+    lossless: bool,
+    bitrate: u32,
+    tags: usize,
+    cover: bool,
+}
+
+fn is_lossless(path: &str, track: &Track) -> bool {
+    let extension = Path::new(path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    track
+        .codec
+        .as_deref()
+        .map(|codec| LOSSLESS.contains(&codec))
+        .unwrap_or_else(|| LOSSLESS.contains(&extension.as_str()))
+}
+
+/// Number of the commonly expected tags the track carries.
+fn tag_count(track: &Track) -> usize {
+    [
+        track.title.is_some(),
+        !track.artist.is_empty(),
+        !track.album.is_empty(),
+        track.album_artist.is_some(),
+        track.year.is_some(),
+        track.track_number.is_some(),
+        track.genre.is_some(),
+    ]
+    .iter()
+    .filter(|present| **present)
+    .count()
+}
+
+/// Minimum fingerprint similarity for tracks with differing tags to match.
+const FINGERPRINT_MATCH: f64 = 0.9;
+
+/// Leading fingerprint items indexed to find the tracks worth comparing.
+const INDEX_ITEMS: usize = 120;
+
+/// Items shared by more tracks than this, such as those of silence, are too
+/// common to narrow the search and are left out of the index.
+const MAX_POSTINGS: usize = 64;
+
+/// Maximum value of `tag_count`.
+const TAG_COUNT: usize = 7;
+
+fn track_quality(path: &str, track: &Track) -> Quality {
+    Quality {
+        lossless: is_lossless(path, track),
+        bitrate: track.bitrate.unwrap_or(0),
+        tags: tag_count(track),
+        cover: track.has_picture.unwrap_or(false),
+    }
+}
+
+fn album_quality(album: &Album, tracks: &BTreeMap<String, Track>) -> Quality {
+    let members = album
+        .tracks
+        .iter()
+        .filter_map(|path| Some((path, tracks.get(path)?)))
+        .collect::<Vec<_>>();
+    let count = members.len().max(1);
+    Quality {
+        lossless: members.iter().all(|(path, track)| is_lossless(path, track)),
+        bitrate: (members
+            .iter()
+            .map(|(_, track)| u64::from(track.bitrate.unwrap_or(0)))
+            .sum::<u64>()
+            / count as u64) as u32,
+        tags: members
+            .iter()
+            .map(|(_, track)| tag_count(track))
+            .sum::<usize>()
+            / count,
+        cover: album.cover.is_some(),
+    }
+}
+
+fn track_duration_ms(track: &Track) -> u64 {
+    track
+        .duration_ms
+        .or_else(|| track.duration.map(|seconds| seconds * 1000))
+        .unwrap_or(0)
+}
+
+/// Groups items by duration so that no cluster spans more than
+/// `tolerance_ms`, keeping only clusters with more than one item.
+///
+/// Each item is compared with the shortest item of the current cluster, so a
+/// chain of near neighbours cannot drift into one cluster.
+fn cluster_by_duration<T>(mut items: Vec<(u64, T)>, tolerance_ms: u64) -> Vec<Vec<T>> {
+    items.sort_by_key(|(duration, _)| *duration);
+    let mut clusters: Vec<Vec<(u64, T)>> = Vec::new();
+    for item in items {
+        match clusters.last_mut() {
+            Some(cluster) if item.0 - cluster[0].0 <= tolerance_ms => cluster.push(item),
+            _ => clusters.push(vec![item]),
+        }
+    }
+    clusters
+        .into_iter()
+        .filter(|cluster| cluster.len() > 1)
+        .map(|cluster| cluster.into_iter().map(|(_, item)| item).collect())
+        .collect()
+}
+
+/// Orders copies best first and splits off the one to keep.
+fn propose(mut copies: Vec<(&String, Quality)>) -> (String, Vec<String>) {
+    copies.sort_by(|(a_path, a), (b_path, b)| {
+        b.partial_cmp(a)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a_path.cmp(b_path))
+    });
+    let mut paths = copies.into_iter().map(|(path, _)| path.clone());
+    let keep = paths.next().unwrap_or_default();
+    (keep, paths.collect())
+}
+
+fn duplicate_albums(
+    albums: &BTreeMap<String, Album>,
+    tracks: &BTreeMap<String, Track>,
+    tolerance_secs: u64,
+) -> Vec<DuplicateGroup> {
+    let mut candidates: BTreeMap<(String, String), Vec<(u64, &String)>> = BTreeMap::new();
+    for (key, album) in albums {
+        if album.title.is_empty() {
+            continue;
+        }
+        candidates
+            .entry((tidy_string(&album.album_artist), tidy_string(&album.title)))
+            .or_default()
+            .push((album.duration * 1000, key));
+    }
+
+    let mut groups = Vec::new();
+    for (_, copies) in candidates {
+        let track_count = copies
+            .iter()
+            .map(|(_, key)| albums[*key].track_count as u64)
+            .max()
+            .unwrap_or(1);
+        for cluster in cluster_by_duration(copies, tolerance_secs * 1000 * track_count) {
+            let album = &albums[cluster[0]];
+            let (keep, others) = propose(
+                cluster
+                    .into_iter()
+                    .map(|key| (key, album_quality(&albums[key], tracks)))
+                    .collect(),
+            );
+            groups.push(DuplicateGroup {
+                kind: DuplicateKind::Album,
+                artist: album.album_artist.clone(),
+                title: album.title.clone(),
+                keep,
+                others,
+            });
+        }
+    }
+    groups
+}
+
+fn duplicate_tracks(
+    tracks: &BTreeMap<String, Track>,
+    skip_albums: &BTreeSet<String>,
+    tolerance_secs: u64,
+) -> Vec<DuplicateGroup> {
+    let mut candidates: BTreeMap<(String, String), Vec<(u64, &String)>> = BTreeMap::new();
+    for (path, track) in tracks {
+        let title = match &track.title {
+            Some(title) if !skip_albums.contains(&album_key(path)) => title,
+            _ => continue,
+        };
+        candidates
+            .entry((tidy_string(&track.artist), tidy_string(title)))
+            .or_default()
+            .push((track_duration_ms(track), path));
+    }
+
+    let mut groups = candidates
+        .into_values()
+        .flat_map(|copies| cluster_by_duration(copies, tolerance_secs * 1000))
+        .map(|cluster| track_group(tracks, cluster))
+        .collect::<Vec<_>>();
+    let grouped = groups
+        .iter()
+        .flat_map(|group| std::iter::once(&group.keep).chain(&group.others))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    groups.extend(fingerprint_duplicates(
+        tracks,
+        &grouped,
+        skip_albums,
+        tolerance_secs,
+    ));
+    groups
+}
+
+/// Groups tracks whose fingerprints match although their tags do not.
+fn fingerprint_duplicates(
+    tracks: &BTreeMap<String, Track>,
+    skip_paths: &BTreeSet<String>,
+    skip_albums: &BTreeSet<String>,
+    tolerance_secs: u64,
+) -> Vec<DuplicateGroup> {
+    let mut candidates = tracks
+        .iter()
+        .filter(|(path, _)| !skip_paths.contains(*path) && !skip_albums.contains(&album_key(path)))
+        .filter_map(|(path, track)| {
+            let raw = fingerprint::decode(track.fingerprint.as_deref()?)?;
+            Some((track_duration_ms(track), path, raw))
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|(duration, _, _)| *duration);
+
+    // Index a few items of each fingerprint, as AcoustID does, so only
+    // tracks sharing an item are compared rather than every pair
+    let mut postings: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (index, (_, _, raw)) in candidates.iter().enumerate() {
+        let items = raw.iter().take(INDEX_ITEMS).collect::<BTreeSet<_>>();
+        for item in items {
+            postings.entry(*item).or_default().push(index);
+        }
+    }
+    let pairs = postings
+        .values()
+        .filter(|indices| indices.len() <= MAX_POSTINGS)
+        .flat_map(|indices| {
+            indices
+                .iter()
+                .enumerate()
+                .flat_map(move |(n, i)| indices[n + 1..].iter().map(move |j| (*i, *j)))
+        })
+        .collect::<BTreeSet<_>>();
+
+    // Union-find over the candidates, comparing only pairs close in duration
+    fn find(parent: &mut [usize], mut index: usize) -> usize {
+        while parent[index] != index {
+            parent[index] = parent[parent[index]];
+            index = parent[index];
+        }
+        index
+    }
+    let mut parent = (0..candidates.len()).collect::<Vec<_>>();
+    for (i, j) in pairs {
+        if candidates[j].0 - candidates[i].0 > tolerance_secs * 1000 {
+            continue;
+        }
+        if fingerprint::similarity(&candidates[i].2, &candidates[j].2) >= FINGERPRINT_MATCH {
+            let (a, b) = (find(&mut parent, i), find(&mut parent, j));
+            parent[b] = a;
+        }
+    }
+
+    let mut components: BTreeMap<usize, Vec<&String>> = BTreeMap::new();
+    for (index, (_, path, _)) in candidates.iter().enumerate() {
+        components
+            .entry(find(&mut parent, index))
+            .or_default()
+            .push(path);
+    }
+    components
+        .into_values()
+        .filter(|cluster| cluster.len() > 1)
+        .map(|cluster| track_group(tracks, cluster))
+        .collect()
+}
+
+fn track_group(tracks: &BTreeMap<String, Track>, cluster: Vec<&String>) -> DuplicateGroup {
+    let (keep, others) = propose(
+        cluster
+            .into_iter()
+            .map(|path| (path, track_quality(path, &tracks[path])))
+            .collect(),
+    );
+    let track = &tracks[&keep];
+    let title = track.title.clone().unwrap_or_else(|| {
+        Path::new(&keep)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    });
+    DuplicateGroup {
+        kind: DuplicateKind::Track,
+        artist: track.artist.clone(),
+        title,
+        keep,
+        others,
+    }
+}
+
+/// Finds albums and tracks that appear more than once under `root`.
+///
+/// Copies match when their normalized artist and title agree and their
+/// durations are within `tolerance_secs` per track. Fingerprinted tracks
+/// also match on their audio regardless of their tags. Tracks of duplicate
+/// albums are only reported as part of their album.
+pub fn find_duplicates(
+    root: &Path,
+    tracks: &BTreeMap<String, Track>,
+    tolerance_secs: u64,
+) -> Vec<DuplicateGroup> {
+    let albums = build_albums(root, tracks);
+    let mut groups = duplicate_albums(&albums, tracks, tolerance_secs);
+    let duplicated = groups
+        .iter()
+        .flat_map(|group| std::iter::once(&group.keep).chain(&group.others))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    groups.extend(duplicate_tracks(tracks, &duplicated, tolerance_secs));
+    groups
+}
+
+fn describe(quality: Quality, extra: String) -> String {
+    format!(
+        "{}{}, {} kbps, {}/{} tags{}",
+        extra,
+        if quality.lossless {
+            "lossless"
+        } else {
+            "lossy"
+        },
+        quality.bitrate,
+        quality.tags,
+        TAG_COUNT,
+        if quality.cover { ", cover" } else { "" }
+    )
+}
+
+fn print_group(
+    group: &DuplicateGroup,
+    tracks: &BTreeMap<String, Track>,
+    albums: &BTreeMap<String, Album>,
+) {
+    println!(
+        "  {} – {}",
+        group.artist.bright_white().bold(),
+        group.title.bright_white()
+    );
+    for (index, copy) in std::iter::once(&group.keep)
+        .chain(&group.others)
+        .enumerate()
+    {
+        let details = match group.kind {
+            DuplicateKind::Album => albums.get(copy).map(|album| {
+                describe(
+                    album_quality(album, tracks),
+                    format!("{} tracks, ", album.track_count),
+                )
+            }),
+            DuplicateKind::Track => tracks.get(copy).map(|track| {
+                let seconds = track_duration_ms(track) / 1000;
+                describe(
+                    track_quality(copy, track),
+                    format!("{}:{:02}, ", seconds / 60, seconds % 60),
+                )
+            }),
+        }
+        .unwrap_or_default();
+        if index == 0 {
+            println!(
+                "    {} {}  {}",
+                "✓ keep".bright_green(),
+                copy,
+                details.dimmed()
+            );
+        } else {
+            println!("    {}       {}  {}", "✗".red(), copy, details.dimmed());
+        }
+    }
+}
+
+/// Reports likely duplicate albums and tracks in the index of `library`.
+pub fn dupes(library: &str, options: DupesOptions) {
+    let root = Path::new(library);
+    let tracks = match load_library_tracks(root) {
+        Ok(tracks) => tracks,
+        Err(e) => {
+            eprintln!("{} Error loading index: {}", "✗".red().bold(), e);
+            return;
+        }
+    };
+
+    let groups = find_duplicates(root, &tracks, options.tolerance_secs);
+    match options.output {
+        DupesOutput::Json => {
+            for group in &groups {
+                match serde_json::to_string(group) {
+                    Ok(json) => println!("{}", json),
+                    Err(e) => eprintln!("{} Error serializing group: {}", "✗".red().bold(), e),
+                }
+            }
+        }
+        DupesOutput::Table => {
+            let albums = build_albums(root, &tracks);
+            for (kind, heading) in [
+                (DuplicateKind::Album, "💿 Duplicate albums"),
+                (DuplicateKind::Track, "🎵 Duplicate tracks"),
+            ] {
+                let matching = groups
+                    .iter()
+                    .filter(|group| group.kind == kind)
+                    .collect::<Vec<_>>();
+                println!(
+                    "{}: {}",
+                    heading.bright_blue(),
+                    matching.len().to_string().bright_yellow().bold()
+                );
+                for group in matching {
+                    print_group(group, &tracks, &albums);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    use super::{cluster_by_duration, find_duplicates, is_lossless, DuplicateKind};
+    use crate::fingerprint::encode;
+    use crate::schema::Track;
+
+    fn track(artist: &str, album: &str, title: &str, duration: u64, bitrate: u32) -> Track {
+        Track {
+            artist: artist.to_owned(),
+            album: album.to_owned(),
+            title: Some(title.to_owned()),
+            duration: Some(duration),
+            bitrate: Some(bitrate),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn prefers_lossless_copy_of_duplicate_album() {
+        let mut tracks = BTreeMap::new();
+        for (dir, extension, bitrate, offset) in [("mp3", "mp3", 320, 1), ("flac", "flac", 900, 0)]
+        {
+            tracks.insert(
+                format!("Band/Record ({})/01.{}", dir, extension),
+                track("Band", "Record", "One", 200 + offset, bitrate),
+            );
+            tracks.insert(
+                format!("Band/Record ({})/02.{}", dir, extension),
+                track("Band", "Record", "Two", 180, bitrate),
+            );
+        }
+
+        let groups = find_duplicates(Path::new("/nonexistent"), &tracks, 2);
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].kind, DuplicateKind::Album);
+        assert_eq!(groups[0].keep, "Band/Record (flac)");
+        assert_eq!(groups[0].others, vec!["Band/Record (mp3)".to_owned()]);
+    }
+
+    #[test]
+    fn wavpack_codec_counts_as_lossless() {
+        let track = Track {
+            codec: Some("wavpack".to_owned()),
+            ..Default::default()
+        };
+
+        assert!(is_lossless("Band/Record/01.wv", &track));
+    }
+
+    #[test]
+    fn duration_clusters_do_not_chain_past_the_tolerance() {
+        let items = vec![(0, "a"), (1500, "b"), (3000, "c"), (4000, "d"), (9000, "e")];
+
+        assert_eq!(
+            cluster_by_duration(items, 2000),
+            vec![vec!["a", "b"], vec!["c", "d"]]
+        );
+    }
+
+    #[test]
+    fn groups_tracks_by_normalized_tags_and_duration() {
+        let mut tracks = BTreeMap::new();
+        tracks.insert(
+            "A/Single/01.mp3".to_owned(),
+            track("The Band", "Single", "Song!", 240, 192),
+        );
+        tracks.insert(
+            "A/Best Of/07.mp3".to_owned(),
+            track("the band", "Best Of", "song", 241, 256),
+        );
+        tracks.insert(
+            "A/Live/03.mp3".to_owned(),
+            track("The Band", "Live", "Song", 300, 320),
+        );
+
+        let groups = find_duplicates(Path::new("/nonexistent"), &tracks, 2);
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].kind, DuplicateKind::Track);
+        assert_eq!(groups[0].keep, "A/Best Of/07.mp3");
+        assert_eq!(groups[0].others, vec!["A/Single/01.mp3".to_owned()]);
+    }
+
+    #[test]
+    fn groups_tracks_with_matching_fingerprints_despite_tags() {
+        let fingerprint = encode(
+            &(0..64u32)
+                .map(|n| n.wrapping_mul(0x9E37_79B9))
+                .collect::<Vec<_>>(),
+        );
+        let mut tracks = BTreeMap::new();
+        for (path, title) in [("Inbox/track01.mp3", None), ("A/B/01.flac", Some("Song"))] {
+            tracks.insert(
+                path.to_owned(),
+                Track {
+                    title: title.map(str::to_owned),
+                    duration: Some(200),
+                    fingerprint: Some(fingerprint.clone()),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let groups = find_duplicates(Path::new("/nonexistent"), &tracks, 2);
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].keep, "A/B/01.flac");
+        assert_eq!(groups[0].title, "Song");
+    }
+
+    #[test]
+    fn matches_fingerprints_offset_by_a_few_items() {
+        let raw = (0..64u32)
+            .map(|n| n.wrapping_mul(0x9E37_79B9))
+            .collect::<Vec<_>>();
+        let other = (0..64u32)
+            .map(|n| n.wrapping_mul(0x85EB_CA6B))
+            .collect::<Vec<_>>();
+        let mut tracks = BTreeMap::new();
+        for (path, fingerprint) in [
+            ("Inbox/a.mp3", encode(&raw)),
+            ("Inbox/b.mp3", encode(&raw[3..])),
+            ("Inbox/c.mp3", encode(&other)),
+        ] {
+            tracks.insert(
+                path.to_owned(),
+                Track {
+                    duration: Some(200),
+                    fingerprint: Some(fingerprint),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let groups = find_duplicates(Path::new("/nonexistent"), &tracks, 2);
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].keep, "Inbox/a.mp3");
+        assert_eq!(groups[0].others, vec!["Inbox/b.mp3".to_owned()]);
+    }
+}
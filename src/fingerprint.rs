@@ -0,0 +1,294 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use colored::*;
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use rusty_chromaprint::{Configuration, FingerprintCompressor, Fingerprinter};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+
+use crate::index::{self, configure_thread_pool, update_stored_tracks, IndexOptions};
+use crate::query::load_library_tracks;
+
+/// Seconds of audio fingerprinted per track, as `fpcalc` does by default.
+const MAX_SECONDS: u32 = 120;
+
+/// Rate Chromaprint analyses audio at; decoding straight to it avoids resampling twice.
+const SAMPLE_RATE: u32 = 11025;
+
+/// Largest shift, in fingerprint items (about 0.12s each), tried when
+/// comparing fingerprints of copies with different leading silence.
+const MAX_OFFSET: usize = 16;
+
+/// Minimum number of overlapping items for a comparison to count.
+const MIN_OVERLAP: usize = 32;
+
+/// Decodes the first audio stream to mono 16-bit PCM at `SAMPLE_RATE`.
+fn decode_pcm(path: &Path) -> Result<Vec<i16>, String> {
+    let output = Command::new("ffmpeg")
+        .arg("-nostdin")
+        .arg("-v")
+        .arg("error")
+        .arg("-i")
+        .arg(path)
+        .arg("-map")
+        .arg("0:a:0")
+        .arg("-t")
+        .arg(MAX_SECONDS.to_string())
+        .arg("-ac")
+        .arg("1")
+        .arg("-ar")
+        .arg(SAMPLE_RATE.to_string())
+        .arg("-f")
+        .arg("s16le")
+        .arg("-")
+        .output()
+        .map_err(|e| format!("failed to run ffmpeg: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = stderr.trim().lines().next().unwrap_or_default();
+        return Err(if message.is_empty() {
+            format!("ffmpeg exited with {}", output.status)
+        } else {
+            message.to_owned()
+        });
+    }
+    Ok(output
+        .stdout
+        .chunks_exact(2)
+        .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+        .collect())
+}
+
+/// Computes the raw Chromaprint fingerprint of mono samples at `SAMPLE_RATE`.
+fn raw_fingerprint(samples: &[i16]) -> Result<Vec<u32>, String> {
+    let mut printer = Fingerprinter::new(&Configuration::preset_test2());
+    printer
+        .start(SAMPLE_RATE, 1)
+        .map_err(|e| format!("{:?}", e))?;
+    printer.consume(samples);
+    printer.finish();
+    Ok(printer.fingerprint().to_vec())
+}
+
+/// Compresses and base64-encodes a raw fingerprint the way `fpcalc` prints it.
+pub fn encode(raw: &[u32]) -> String {
+    let config = Configuration::preset_test2();
+    URL_SAFE_NO_PAD.encode(FingerprintCompressor::from(&config).compress(raw))
+}
+
+/// Reads the `index`th unsigned value of `bits` bits from an array packed
+/// least significant bit first, starting at byte `offset`.
+fn packed_value(bytes: &[u8], offset: usize, bits: usize, index: usize) -> Option<u8> {
+    (0..bits).try_fold(0u8, |value, bit| {
+        let position = index * bits + bit;
+        let byte = bytes.get(offset + position / 8)?;
+        Some(value | (((byte >> (position % 8)) & 1) << bit))
+    })
+}
+
+/// Decodes a fingerprint produced by `encode` (or `fpcalc`) into raw items.
+pub fn decode(fingerprint: &str) -> Option<Vec<u32>> {
+    let bytes = URL_SAFE_NO_PAD.decode(fingerprint.trim()).ok()?;
+    if bytes.len() < 4 {
+        return None;
+    }
+    let size = (usize::from(bytes[1]) << 16) | (usize::from(bytes[2]) << 8) | usize::from(bytes[3]);
+
+    // Each item is a run of 3-bit bit-position deltas ending in a zero
+    let mut normal = Vec::new();
+    let mut items = 0;
+    while items < size {
+        let value = packed_value(&bytes, 4, 3, normal.len())?;
+        if value == 0 {
+            items += 1;
+        }
+        normal.push(value);
+    }
+    let exceptional_offset = 4 + (normal.len() * 3).div_ceil(8);
+    let exceptional_count = normal.iter().filter(|value| **value == 7).count();
+    let mut exceptional = (0..exceptional_count)
+        .map(|index| packed_value(&bytes, exceptional_offset, 5, index))
+        .collect::<Option<Vec<_>>>()?
+        .into_iter();
+
+    let mut raw = Vec::with_capacity(size);
+    let (mut previous, mut current, mut last_bit) = (0u32, 0u32, 0u32);
+    for value in normal {
+        let mut delta = u32::from(value);
+        if value == 7 {
+            delta += u32::from(exceptional.next()?);
+        }
+        if delta == 0 {
+            previous ^= current;
+            raw.push(previous);
+            current = 0;
+            last_bit = 0;
+        } else {
+            last_bit += delta;
+            current |= 1u32.checked_shl(last_bit - 1)?;
+        }
+    }
+    Some(raw)
+}
+
+/// Fraction of matching bits between two fingerprints at the best alignment,
+/// from about 0.5 for unrelated audio to 1.0 for identical audio.
+pub fn similarity(a: &[u32], b: &[u32]) -> f64 {
+    let compare = |a: &[u32], b: &[u32]| {
+        let overlap = a.len().min(b.len());
+        if overlap < MIN_OVERLAP {
+            return 0.0;
+        }
+        let errors = a
+            .iter()
+            .zip(b)
+            .map(|(x, y)| (x ^ y).count_ones())
+            .sum::<u32>();
+        1.0 - f64::from(errors) / (overlap as f64 * 32.0)
+    };
+    (0..=MAX_OFFSET)
+        .flat_map(|offset| {
+            [
+                compare(a.get(offset..).unwrap_or_default(), b),
+                compare(a, b.get(offset..).unwrap_or_default()),
+            ]
+        })
+        .fold(0.0, f64::max)
+}
+
+/// Computes the fingerprint of an audio file.
+pub fn compute(path: &Path) -> Result<String, String> {
+    let samples = decode_pcm(path)?;
+    let raw = raw_fingerprint(&samples)?;
+    if raw.is_empty() {
+        return Err("too little audio to fingerprint".to_owned());
+    }
+    Ok(encode(&raw))
+}
+
+/// Prints the fingerprint of a single file, or brings the index of a
+/// directory up to date and fingerprints the tracks that lack one.
+pub fn fingerprint(path: &str, options: IndexOptions) {
+    if Command::new("ffmpeg").arg("-version").output().is_err() {
+        eprintln!(
+            "{} ffmpeg is required to decode files but could not be run",
+            "✗".red().bold()
+        );
+        return;
+    }
+
+    let root = Path::new(path);
+    if root.is_file() {
+        match compute(root) {
+            Ok(fingerprint) => println!("FINGERPRINT={}", fingerprint),
+            Err(e) => eprintln!("{} {}: {}", "✗".red().bold(), path, e),
+        }
+        return;
+    }
+
+    // Changed files are re-extracted without a fingerprint, so only they and
+    // new files are fingerprinted below
+    index::index_directory(path, options);
+    let tracks = match load_library_tracks(root) {
+        Ok(tracks) => tracks,
+        Err(e) => {
+            if !options.dry_run {
+                eprintln!("{} Error loading index: {}", "✗".red().bold(), e);
+            }
+            return;
+        }
+    };
+    let missing = tracks
+        .iter()
+        .filter(|(_, track)| track.fingerprint.is_none())
+        .map(|(path, _)| path)
+        .collect::<Vec<_>>();
+    println!(
+        "{} Tracks to fingerprint: {}",
+        "⚡".bright_blue(),
+        missing.len().to_string().bright_yellow().bold()
+    );
+    if missing.is_empty() || options.dry_run {
+        return;
+    }
+
+    configure_thread_pool();
+    let pb = ProgressBar::new(missing.len() as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}")
+            .unwrap()
+            .progress_chars("█▉▊▋▌▍▎▏  "),
+    );
+    pb.set_message("🎼 Fingerprinting audio files...");
+    let results = missing
+        .par_iter()
+        .map(|relative| {
+            let result = compute(&root.join(relative));
+            pb.inc(1);
+            (*relative, result)
+        })
+        .collect::<Vec<_>>();
+    pb.finish_and_clear();
+
+    let mut fingerprints = BTreeMap::new();
+    for (relative, result) in results {
+        match result {
+            Ok(fingerprint) => {
+                fingerprints.insert(relative.clone(), fingerprint);
+            }
+            Err(e) => eprintln!("{} {}: {}", "✗".red().bold(), relative, e),
+        }
+    }
+    let stored = update_stored_tracks(root, &fingerprints, |track, fingerprint| {
+        track.fingerprint = Some(fingerprint.clone())
+    });
+    match stored {
+        Ok(index_path) => println!(
+            "{} Recorded {} fingerprints in {}",
+            "💾".bright_green(),
+            fingerprints.len().to_string().bright_yellow().bold(),
+            index_path.display().to_string().bright_white()
+        ),
+        Err(e) => eprintln!("{} Error recording fingerprints: {}", "✗".red().bold(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, raw_fingerprint, similarity, SAMPLE_RATE};
+
+    /// A few seconds of a tone that steps through pitches.
+    fn melody(seconds: u32) -> Vec<i16> {
+        (0..SAMPLE_RATE * seconds)
+            .map(|n| {
+                let t = f64::from(n) / f64::from(SAMPLE_RATE);
+                let frequency = 220.0 * 2f64.powf(f64::from((n / 2000) % 12) / 12.0);
+                ((t * frequency * std::f64::consts::TAU).sin() * 12000.0) as i16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let raw = vec![0, 1, 0xFFFF_FFFF, 0x8000_0001, 0x1234_5678, 0x1234_5678];
+        assert_eq!(decode(&encode(&raw)), Some(raw));
+        assert_eq!(decode("not base64!"), None);
+    }
+
+    #[test]
+    fn matching_audio_is_more_similar_than_different_audio() {
+        let original = raw_fingerprint(&melody(20)).expect("fingerprint");
+        let mut shifted = vec![0; 1000];
+        shifted.extend(melody(20));
+        let shifted = raw_fingerprint(&shifted).expect("fingerprint");
+        let silence = raw_fingerprint(&[0; 20 * 11025]).expect("fingerprint");
+
+        assert!(!original.is_empty());
+        assert!(similarity(&original, &original) > 0.99);
+        assert!(similarity(&original, &shifted) > 0.9);
+        assert!(similarity(&original, &shifted) > similarity(&original, &silence));
+    }
+}
@@ -1,5 +1,6 @@
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::error::Result;
@@ -23,6 +24,64 @@ pub fn relative_path_string(base: &Path, path: &Path) -> Result<String> {
     Ok(path.strip_prefix(base)?.to_string_lossy().into_owned())
 }
 
+/// Makes `path` absolute, resolving symlinks when it exists.
+pub fn absolute_path(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| {
+        std::env::current_dir()
+            .map(|cwd| cwd.join(path))
+            .unwrap_or_else(|_| path.to_path_buf())
+    })
+}
+
+/// Expresses absolute `path` relative to absolute directory `base`, stepping
+/// up with `..` where the two diverge.
+pub fn relative_to(path: &Path, base: &Path) -> PathBuf {
+    let path_components = path.components().collect::<Vec<_>>();
+    let base_components = base.components().collect::<Vec<_>>();
+    let common = path_components
+        .iter()
+        .zip(&base_components)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    base_components[common..]
+        .iter()
+        .map(|_| Component::ParentDir)
+        .chain(path_components[common..].iter().copied())
+        .collect()
+}
+
+/// Resolves `.` and `..` components without touching the filesystem.
+pub fn normalize_lexically(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push(component);
+                }
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// Writes `contents` to `path` through a temporary file in the same directory
+/// and renames it into place, so readers never see a partially written file.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(contents)?;
+    file.as_file().sync_all()?;
+    file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
 /// Returns the modified time for a file if its metadata can be read.
 pub fn modified_time(path: &Path) -> Option<SystemTime> {
     fs::metadata(path).ok()?.modified().ok()
@@ -42,7 +101,10 @@ mod tests {
 
     use tempfile::tempdir;
 
-    use super::{glob_pattern, modified_time, relative_path_string, unix_timestamp_secs};
+    use super::{
+        glob_pattern, modified_time, normalize_lexically, relative_path_string, relative_to,
+        unix_timestamp_secs, write_atomic,
+    };
 
     #[test]
     fn creates_glob_patterns_for_multiple_extensions() {
@@ -60,6 +122,40 @@ mod tests {
         assert_eq!(relative, "Artist/Album/track.flac");
     }
 
+    #[test]
+    fn expresses_paths_relative_to_other_directories() {
+        let track = Path::new("/music/opus/Artist/Album/01.opus");
+
+        assert_eq!(
+            relative_to(track, Path::new("/music/opus")),
+            Path::new("Artist/Album/01.opus")
+        );
+        assert_eq!(
+            relative_to(track, Path::new("/music/playlists")),
+            Path::new("../opus/Artist/Album/01.opus")
+        );
+    }
+
+    #[test]
+    fn normalizes_dot_components() {
+        assert_eq!(
+            normalize_lexically(Path::new("/music/Lists/../Artist/./01.flac")),
+            Path::new("/music/Artist/01.flac")
+        );
+    }
+
+    #[test]
+    fn replaces_files_atomically() {
+        let dir = tempdir().expect("expected temp dir");
+        let file = dir.path().join("index.json");
+        fs::write(&file, b"old").expect("expected temp file write to succeed");
+
+        write_atomic(&file, b"new").expect("expected atomic write to succeed");
+
+        assert_eq!(fs::read(&file).expect("read"), b"new");
+        assert_eq!(fs::read_dir(dir.path()).expect("read dir").count(), 1);
+    }
+
     #[test]
     fn reads_file_modified_times() {
         let dir = tempdir().expect("expected temp dir");
@@ -1,20 +1,28 @@
 use colored::*;
 use globwalk::GlobWalkerBuilder;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use lofty::config::ParseOptions;
+use lofty::file::FileType;
+use lofty::mp4::{Mp4Codec, Mp4File};
+use lofty::picture::PictureInformation;
 use lofty::prelude::*;
-use lofty::read_from_path;
-use lofty::tag::{Accessor, ItemKey};
+use lofty::probe::Probe;
+use lofty::tag::{Accessor, ItemKey, Tag};
 use rayon::prelude::*;
-use serde_json::{json, Map, Value};
-use std::collections::{HashMap, HashSet};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
 
-use crate::fs_utils::{relative_path_string, unix_timestamp_secs};
+use crate::aggregate::{build_albums, build_artists};
+use crate::checkpoint::{self, checkpoint_path, Checkpoint};
+use crate::fs_utils::{relative_path_string, write_atomic};
+use crate::schema::{FailedFile, FieldSet, FileStamp, Index, IndexError, Track, INDEX_FILE_NAME};
+use crate::sqlite::{self, INDEX_DB_FILE_NAME};
+use crate::tag::{parse_gain, parse_peak};
 
-fn configure_thread_pool() {
+pub(crate) fn configure_thread_pool() {
     // Configure rayon to use more threads for better parallelism
     // This is especially helpful for I/O bound operations
     let num_threads = std::thread::available_parallelism()
@@ -22,16 +30,52 @@ fn configure_thread_pool() {
         .unwrap_or(4)
         .max(4); // Ensure minimum of 4 threads
 
-    rayon::ThreadPoolBuilder::new()
+    // An error means the ThreadPool was already initialized, that's fine
+    let _ = rayon::ThreadPoolBuilder::new()
         .num_threads(num_threads)
-        .build_global()
-        .unwrap_or_else(|_| {
-            // ThreadPool already initialized, that's fine
-        });
+        .build_global();
+}
+
+/// Extensions of the audio files picked up by the index.
+pub const AUDIO_EXTENSIONS: &[&str] = &[
+    "mp3", "flac", "wav", "m4a", "aac", "ogg", "opus", "wv", "ape",
+];
+
+/// Storage format for the index written by `index_directory`.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexFormat {
+    /// A single `index.json` file, rewritten on every run.
+    #[default]
+    Json,
+    /// An `index.sqlite` database updated in place.
+    Sqlite,
 }
 
-/// Scans a music directory and writes or previews an `index.json` metadata file.
-pub fn index_directory(directory: &str, dry_run: bool, force: bool) {
+/// Options controlling an `index_directory` run.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct IndexOptions {
+    /// Report what would change without writing the index.
+    pub dry_run: bool,
+    /// Rebuild the index from scratch, ignoring any existing index.
+    pub force: bool,
+    /// How much metadata is extracted per track.
+    pub fields: FieldSet,
+    pub format: IndexFormat,
+    /// Also treat a changed inode number or change time as a modification.
+    pub check_ctime: bool,
+    /// List the files that could not be read once the run finishes.
+    pub report_errors: bool,
+}
+
+/// Audio files found on disk, keyed by path relative to the indexed directory.
+type CurrentFiles = HashMap<String, (PathBuf, FileStamp)>;
+
+/// Tracks extracted by a run and the files that failed, keyed by relative path.
+type Extracted = (HashMap<String, Track>, HashMap<String, FailedFile>);
+
+/// Scans a music directory and writes or previews its metadata index.
+pub fn index_directory(directory: &str, options: IndexOptions) {
     // Configure thread pool for optimal performance
     configure_thread_pool();
     let dir_path = Path::new(directory);
@@ -62,20 +106,459 @@ pub fn index_directory(directory: &str, dry_run: bool, force: bool) {
             .bold()
     );
 
-    let index_path = dir_path.join("index.json");
+    match options.format {
+        IndexFormat::Json => index_json(dir_path, options, None),
+        IndexFormat::Sqlite => index_sqlite(dir_path, options, None),
+    }
+}
+
+/// Updates the index of `directory` for the given relative paths only.
+///
+/// Each path may name a file or a directory. Index entries at or below the
+/// paths are refreshed or removed to match the disk; the rest of the index is
+/// left alone, unless the index needs rebuilding and every file is indexed.
+pub fn update_index(directory: &Path, paths: &BTreeSet<String>, options: IndexOptions) {
+    configure_thread_pool();
+    println!(
+        "{} {}",
+        "🎵".bright_blue(),
+        format!(
+            "Updating index for {} changed paths in {}",
+            paths.len(),
+            directory.display()
+        )
+        .bright_white()
+        .bold()
+    );
+
+    match options.format {
+        IndexFormat::Json => index_json(directory, options, Some(paths)),
+        IndexFormat::Sqlite => index_sqlite(directory, options, Some(paths)),
+    }
+}
+
+fn index_json(dir_path: &Path, options: IndexOptions, scope: Option<&BTreeSet<String>>) {
+    let index_path = dir_path.join(INDEX_FILE_NAME);
 
     // Load existing index if it exists
-    let mut existing_tracks = load_existing_index(&index_path);
+    let (existing, digests) = load_existing_index(&index_path, options.fields);
+    let scope = unscoped_for_rebuild(scope, existing.is_none() || options.force);
+    let existing = existing.unwrap_or_default();
+    let mut stored_fields = existing.fields;
+    let mut existing_tracks = existing.tracks;
+    let mut errors = existing.errors;
     println!(
         "{} Loaded existing index with {} tracks",
         "📚".bright_green(),
         existing_tracks.len().to_string().bright_yellow().bold()
     );
 
+    let current_files = discover(dir_path, scope, existing_tracks.len());
+
+    if options.force {
+        existing_tracks.clear();
+        errors.clear();
+        stored_fields = options.fields;
+    }
+    let (checkpoint, mut recovered) = open_checkpoint(&index_path, options);
+    carry_over_digests(&digests, recovered.iter_mut(), options.check_ctime);
+    let resumed = !recovered.is_empty();
+    existing_tracks.extend(recovered);
+    let mut existing_stamps = stored_stamps(&existing_tracks);
+    existing_stamps.extend(failed_stamps(&errors));
+    restrict_to_scope(&mut existing_stamps, scope);
+    let (files_to_process, files_to_remove) = plan_changes(
+        &existing_stamps,
+        &current_files,
+        options.force,
+        options.check_ctime,
+    );
+    refresh_legacy_stamps(&mut existing_tracks, &current_files, &files_to_process);
+
+    // Remove files that no longer exist
+    for file_to_remove in &files_to_remove {
+        existing_tracks.remove(file_to_remove);
+        println!("{} Removed: {}", "🗑️".red(), file_to_remove.dimmed());
+    }
+
+    // Update the existing tracks with new data
+    let (mut new_tracks, failed) =
+        process_files(&files_to_process, options.fields, checkpoint.as_ref());
+    carry_over_digests(&digests, new_tracks.iter_mut(), options.check_ctime);
+    update_errors(&mut errors, &files_to_remove, &new_tracks, &failed);
+    for path in failed.keys() {
+        existing_tracks.remove(path);
+    }
+    let fields = written_fields(
+        options.fields,
+        stored_fields,
+        resumed || !new_tracks.is_empty(),
+    );
+    existing_tracks.extend(new_tracks);
+
+    // Create the index structure
+    let albums = build_albums(dir_path, &existing_tracks);
+    let index = Index {
+        fields,
+        artists: build_artists(&albums),
+        albums,
+        tracks: existing_tracks,
+        errors,
+        ..Default::default()
+    };
+    report_failures(&index.errors, options.report_errors);
+
+    if options.dry_run {
+        println!(
+            "{} {} Would write index to: {}",
+            "🔍".bright_blue(),
+            "DRY RUN:".bright_yellow().bold(),
+            index_path.display().to_string().bright_white()
+        );
+        println!(
+            "{} Index would contain {} tracks in {} albums",
+            "📊".bright_blue(),
+            index.tracks.len().to_string().bright_yellow().bold(),
+            index.albums.len().to_string().bright_yellow().bold()
+        );
+    } else {
+        match index.to_json() {
+            Ok(json_string) => match write_atomic(&index_path, json_string.as_bytes()) {
+                Ok(_) => {
+                    println!(
+                        "{} Index written to: {} ({} tracks)",
+                        "💾".bright_green(),
+                        index_path.display().to_string().bright_white(),
+                        index.tracks.len().to_string().bright_yellow().bold()
+                    );
+                    finish_checkpoint(checkpoint);
+                }
+                Err(e) => eprintln!("{} Error writing index file: {}", "✗".red().bold(), e),
+            },
+            Err(e) => eprintln!(
+                "{} Error serializing index to JSON: {}",
+                "✗".red().bold(),
+                e
+            ),
+        }
+    }
+}
+
+fn index_sqlite(dir_path: &Path, options: IndexOptions, scope: Option<&BTreeSet<String>>) {
+    let db_path = dir_path.join(INDEX_DB_FILE_NAME);
+
+    let mut db = match sqlite::open(&db_path, options.dry_run) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("{} Error opening index database: {}", "✗".red().bold(), e);
+            return;
+        }
+    };
+
+    let stored_fields = match sqlite::stored_fields(&db) {
+        Ok(fields) => fields,
+        Err(e) => {
+            eprintln!("{} Error reading index database: {}", "✗".red().bold(), e);
+            return;
+        }
+    };
+    // A full run over a minimal database needs every track re-extracted, and
+    // a database never written to needs every track extracted
+    let rebuild = options.force
+        || stored_fields.is_none()
+        || (options.fields == FieldSet::Full && stored_fields != Some(FieldSet::Full));
+    let scope = unscoped_for_rebuild(scope, rebuild);
+    // A rebuild replaces every row, so keep what extraction cannot recompute
+    let digests = if rebuild {
+        sqlite::load_tracks(&db)
+            .map(|tracks| digest_tracks(&tracks))
+            .unwrap_or_default()
+    } else {
+        BTreeMap::new()
+    };
+    let (mut existing_stamps, mut errors) = if rebuild {
+        (BTreeMap::new(), BTreeMap::new())
+    } else {
+        match sqlite::load_stamps(&db).and_then(|stamps| Ok((stamps, sqlite::load_errors(&db)?))) {
+            Ok(existing) => existing,
+            Err(e) => {
+                eprintln!("{} Error reading index database: {}", "✗".red().bold(), e);
+                return;
+            }
+        }
+    };
+    println!(
+        "{} Loaded existing index with {} tracks",
+        "📚".bright_green(),
+        existing_stamps.len().to_string().bright_yellow().bold()
+    );
+
+    let current_files = discover(dir_path, scope, existing_stamps.len());
+    let (checkpoint, recovered) = open_checkpoint(&db_path, options);
+    existing_stamps.extend(stored_stamps(&recovered));
+    existing_stamps.extend(failed_stamps(&errors));
+    restrict_to_scope(&mut existing_stamps, scope);
+    let (files_to_process, files_to_remove) = plan_changes(
+        &existing_stamps,
+        &current_files,
+        rebuild,
+        options.check_ctime,
+    );
+    for file_to_remove in &files_to_remove {
+        println!("{} Removed: {}", "🗑️".red(), file_to_remove.dimmed());
+    }
+    // Recovered tracks are not in the database yet, so they are written along
+    // with the newly extracted ones
+    let mut new_tracks = recovered
+        .into_iter()
+        .filter(|(path, _)| current_files.contains_key(path))
+        .collect::<HashMap<_, _>>();
+    let (extracted, failed) = process_files(&files_to_process, options.fields, checkpoint.as_ref());
+    new_tracks.extend(extracted);
+    carry_over_digests(&digests, new_tracks.iter_mut(), options.check_ctime);
+    update_errors(&mut errors, &files_to_remove, &new_tracks, &failed);
+    report_failures(&errors, options.report_errors);
+
+    if options.dry_run {
+        println!(
+            "{} {} Would update index database: {}",
+            "🔍".bright_blue(),
+            "DRY RUN:".bright_yellow().bold(),
+            db_path.display().to_string().bright_white()
+        );
+        return;
+    }
+
+    let stored_fields = match stored_fields {
+        Some(fields) if !rebuild => fields,
+        _ => options.fields,
+    };
+    let changes = sqlite::Changes {
+        rebuild,
+        fields: written_fields(options.fields, stored_fields, !new_tracks.is_empty()),
+        removed: &files_to_remove,
+        updated: &new_tracks,
+        failed: &failed,
+    };
+    match sqlite::apply_changes(&mut db, dir_path, &changes) {
+        Ok(track_count) => {
+            println!(
+                "{} Index written to: {} ({} tracks)",
+                "💾".bright_green(),
+                db_path.display().to_string().bright_white(),
+                track_count.to_string().bright_yellow().bold()
+            );
+            finish_checkpoint(checkpoint);
+        }
+        Err(e) => eprintln!("{} Error writing index database: {}", "✗".red().bold(), e),
+    }
+}
+
+/// Returns the field set to record for an index stored with `stored` after a
+/// run extracting `requested`.
+///
+/// A minimal run over a full index keeps it full as long as it extracted
+/// nothing, since every entry still has the full field set.
+fn written_fields(requested: FieldSet, stored: FieldSet, extracted: bool) -> FieldSet {
+    if stored == FieldSet::Full && !extracted {
+        FieldSet::Full
+    } else {
+        requested
+    }
+}
+
+/// Opens the journal that lets an interrupted run resume, returning the tracks
+/// it recovered. Dry runs write nothing and `--force` starts from scratch.
+fn open_checkpoint(
+    index_path: &Path,
+    options: IndexOptions,
+) -> (Option<Checkpoint>, BTreeMap<String, Track>) {
+    if options.dry_run {
+        return (None, BTreeMap::new());
+    }
+    let path = checkpoint_path(index_path);
+    if options.force {
+        let _ = checkpoint::remove(&path);
+    }
+    match Checkpoint::open(&path, options.fields) {
+        Ok((checkpoint, recovered)) => {
+            if !recovered.is_empty() {
+                println!(
+                    "{} Resuming interrupted scan with {} tracks already extracted",
+                    "♻️".bright_blue(),
+                    recovered.len().to_string().bright_yellow().bold()
+                );
+            }
+            (Some(checkpoint), recovered)
+        }
+        Err(e) => {
+            eprintln!(
+                "{} Could not open checkpoint file, progress will not be saved: {}",
+                "⚠️".bright_yellow(),
+                e
+            );
+            (None, BTreeMap::new())
+        }
+    }
+}
+
+fn failed_stamps(errors: &BTreeMap<String, FailedFile>) -> BTreeMap<String, FileStamp> {
+    errors
+        .iter()
+        .map(|(path, failed)| (path.clone(), failed.stamp))
+        .collect()
+}
+
+/// Brings the recorded failures up to date with the files removed, read and
+/// failed in this run.
+fn update_errors(
+    errors: &mut BTreeMap<String, FailedFile>,
+    removed: &HashSet<String>,
+    extracted: &HashMap<String, Track>,
+    failed: &HashMap<String, FailedFile>,
+) {
+    for path in removed.iter().chain(extracted.keys()) {
+        errors.remove(path);
+    }
+    errors.extend(failed.iter().map(|(path, f)| (path.clone(), f.clone())));
+}
+
+/// Summarizes unreadable files, listing each one with `list`.
+fn report_failures(errors: &BTreeMap<String, FailedFile>, list: bool) {
+    if errors.is_empty() {
+        if list {
+            println!("{} No unreadable files", "✓".bright_green().bold());
+        }
+        return;
+    }
+    if !list {
+        println!(
+            "{} {} files could not be read (list them with --report-errors)",
+            "⚠️".bright_yellow(),
+            errors.len().to_string().bright_yellow().bold()
+        );
+        return;
+    }
+    println!(
+        "{} {} files could not be read:",
+        "⚠️".bright_yellow(),
+        errors.len().to_string().bright_yellow().bold()
+    );
+    for (path, failed) in errors {
+        println!("  {} {}: {}", "✗".red().bold(), path, failed.error.red());
+    }
+}
+
+fn finish_checkpoint(checkpoint: Option<Checkpoint>) {
+    if let Some(Err(e)) = checkpoint.map(Checkpoint::finish) {
+        eprintln!("{} Error removing checkpoint file: {}", "✗".red().bold(), e);
+    }
+}
+
+/// Applies `update` to the stored tracks of the index in `directory` (JSON or
+/// SQLite) with the value given for their path, returning the index path.
+///
+/// Used for values computed outside an index run, such as checksums and
+/// fingerprints, which are kept until the file changes.
+pub fn update_stored_tracks<V, F: Fn(&mut Track, &V)>(
+    directory: &Path,
+    updates: &BTreeMap<String, V>,
+    update: F,
+) -> Result<PathBuf, String> {
+    let json_path = directory.join(INDEX_FILE_NAME);
+    if json_path.exists() {
+        let mut index = Index::load(&json_path).map_err(|e| e.to_string())?;
+        for (path, value) in updates {
+            if let Some(track) = index.tracks.get_mut(path) {
+                update(track, value);
+            }
+        }
+        let json = index.to_json().map_err(|e| e.to_string())?;
+        write_atomic(&json_path, json.as_bytes()).map_err(|e| e.to_string())?;
+        Ok(json_path)
+    } else {
+        let db_path = directory.join(INDEX_DB_FILE_NAME);
+        sqlite::open(&db_path, false)
+            .and_then(|mut db| sqlite::update_tracks(&mut db, updates, update))
+            .map_err(|e| e.to_string())?;
+        Ok(db_path)
+    }
+}
+
+/// Finds the audio files of a run: every file below `dir_path`, or only those
+/// at or below the `scope` paths.
+fn discover(
+    dir_path: &Path,
+    scope: Option<&BTreeSet<String>>,
+    existing_count: usize,
+) -> CurrentFiles {
+    let scope = match scope {
+        Some(scope) => scope,
+        None => {
+            let current_files = discover_files(dir_path);
+            if current_files.is_empty() {
+                println!(
+                    "{} No audio files found in directory tree",
+                    "⚠️".bright_yellow()
+                );
+                if existing_count > 0 {
+                    println!(
+                        "{} Clearing existing index since no audio files found",
+                        "🧹".bright_blue()
+                    );
+                }
+            }
+            return current_files;
+        }
+    };
+
+    let mut current_files = CurrentFiles::new();
+    for relative in scope {
+        let path = dir_path.join(relative);
+        if path.is_dir() {
+            current_files.extend(
+                discover_files(&path)
+                    .into_iter()
+                    .map(|(sub_path, file)| (format!("{}/{}", relative, sub_path), file)),
+            );
+        } else if is_audio_file(&path) {
+            if let Ok(metadata) = fs::metadata(&path) {
+                let stamp = FileStamp::from_metadata(&metadata);
+                current_files.insert(relative.clone(), (path, stamp));
+            }
+        }
+    }
+    current_files
+}
+
+/// Returns whether `path` has one of the `AUDIO_EXTENSIONS`.
+pub fn is_audio_file(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| {
+            AUDIO_EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str())
+        })
+        .unwrap_or(false)
+}
+
+/// Limits the stored entries considered by a scoped run to those at or below
+/// the scope paths.
+fn restrict_to_scope(stamps: &mut BTreeMap<String, FileStamp>, scope: Option<&BTreeSet<String>>) {
+    if let Some(scope) = scope {
+        stamps.retain(|path, _| {
+            scope
+                .iter()
+                .any(|prefix| Path::new(path).starts_with(prefix))
+        });
+    }
+}
+
+/// Finds all audio files below `dir_path` along with their modification times.
+fn discover_files(dir_path: &Path) -> CurrentFiles {
     // Find all current audio files in parallel
-    let audio_extensions = [
-        "*.mp3", "*.flac", "*.wav", "*.m4a", "*.aac", "*.ogg", "*.opus", "*.wv", "*.ape",
-    ];
+    let audio_extensions = AUDIO_EXTENSIONS
+        .iter()
+        .map(|extension| format!("*.{}", extension))
+        .collect::<Vec<_>>();
 
     // Create a progress bar for file discovery
     let discovery_pb = ProgressBar::new_spinner();
@@ -88,10 +571,10 @@ pub fn index_directory(directory: &str, dry_run: bool, force: bool) {
     discovery_pb.enable_steady_tick(std::time::Duration::from_millis(80));
 
     // Parallelize file discovery across extensions
-    let current_files: HashMap<String, (std::path::PathBuf, SystemTime)> = audio_extensions
+    let current_files: CurrentFiles = audio_extensions
         .par_iter()
         .map(|ext| {
-            let walker = GlobWalkerBuilder::new(directory, ext)
+            let walker = GlobWalkerBuilder::new(dir_path, ext)
                 .follow_links(false)
                 .build()
                 .unwrap();
@@ -109,12 +592,9 @@ pub fn index_directory(directory: &str, dry_run: bool, force: bool) {
         .par_iter()
         .filter_map(|(relative_path, file_path)| {
             // Parallelize metadata collection
-            if let Ok(metadata) = fs::metadata(file_path) {
-                if let Ok(modified) = metadata.modified() {
-                    return Some((relative_path.clone(), (file_path.clone(), modified)));
-                }
-            }
-            None
+            let metadata = fs::metadata(file_path).ok()?;
+            let stamp = FileStamp::from_metadata(&metadata);
+            Some((relative_path.clone(), (file_path.clone(), stamp)))
         })
         .collect();
 
@@ -124,365 +604,543 @@ pub fn index_directory(directory: &str, dry_run: bool, force: bool) {
         current_files.len().to_string().bright_yellow().bold()
     ));
 
-    if current_files.is_empty() {
-        println!(
-            "{} No audio files found in directory tree",
-            "⚠️".bright_yellow()
-        );
-        if !existing_tracks.is_empty() {
-            println!(
-                "{} Clearing existing index since no audio files found",
-                "🧹".bright_blue()
-            );
-            existing_tracks.clear();
-        }
-    } else {
-        // Identify files to process
-        let (files_to_process, files_to_remove) = if force {
-            // Force mode: process all files and clear existing index
-            println!(
-                "{} Force mode: regenerating entire index",
-                "⚡".bright_yellow().bold()
-            );
-            existing_tracks.clear();
-            (current_files.clone(), HashSet::new())
-        } else {
-            identify_changes(&existing_tracks, &current_files)
-        };
+    current_files
+}
 
+/// Decides which files to (re)process and which index entries to drop.
+///
+/// With `rebuild` the caller is expected to discard the existing entries and
+/// pass only the stamps recovered from an interrupted run.
+fn plan_changes(
+    existing_stamps: &BTreeMap<String, FileStamp>,
+    current_files: &CurrentFiles,
+    rebuild: bool,
+    check_ctime: bool,
+) -> (CurrentFiles, HashSet<String>) {
+    // Identify files to process
+    if rebuild {
+        // Force mode: process all files and clear existing index
         println!(
-            "{} Files to process: {}",
-            "⚡".bright_blue(),
-            files_to_process.len().to_string().bright_yellow().bold()
-        );
-        println!(
-            "{} Files to remove: {}",
-            "🗑️".bright_red(),
-            files_to_remove.len().to_string().bright_yellow().bold()
+            "{} Force mode: regenerating entire index",
+            "⚡".bright_yellow().bold()
         );
+    }
+    let (files_to_process, files_to_remove) =
+        identify_changes(existing_stamps, current_files, check_ctime);
 
-        // Remove files that no longer exist
-        for file_to_remove in &files_to_remove {
-            existing_tracks.remove(file_to_remove);
-            println!("{} Removed: {}", "🗑️".red(), file_to_remove.dimmed());
-        }
+    println!(
+        "{} Files to process: {}",
+        "⚡".bright_blue(),
+        files_to_process.len().to_string().bright_yellow().bold()
+    );
+    println!(
+        "{} Files to remove: {}",
+        "🗑️".bright_red(),
+        files_to_remove.len().to_string().bright_yellow().bold()
+    );
 
-        // Process only changed/new files
-        if !files_to_process.is_empty() {
-            // Create multi-progress for parallel processing
-            let multi_progress = Arc::new(MultiProgress::new());
-            let main_pb = multi_progress.add(ProgressBar::new(files_to_process.len() as u64));
-            main_pb.set_style(
-                ProgressStyle::default_bar()
-                    .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}")
-                    .unwrap()
-                    .progress_chars("█▉▊▋▌▍▎▏  ")
-            );
-            main_pb.set_message("🎵 Processing audio files...");
-
-            // Use Arc<Mutex<>> to reduce progress bar contention
-            let pb_clone = Arc::new(Mutex::new(main_pb.clone()));
-            let processed_count = Arc::new(Mutex::new(0usize));
-
-            let new_tracks: HashMap<String, Value> = files_to_process
-                .par_iter()
-                .filter_map(|(relative_path, (file_path, _))| {
-                    let result = match extract_metadata(file_path) {
-                        Ok(metadata) => {
-                            // Update progress less frequently to reduce contention
-                            let mut count = processed_count.lock().unwrap();
-                            *count += 1;
-                            if *count % 10 == 0 || *count == files_to_process.len() {
-                                if let Ok(pb) = pb_clone.lock() {
-                                    pb.set_message(format!(
-                                        "✓ {}",
-                                        relative_path
-                                            .split('/')
-                                            .last()
-                                            .unwrap_or(relative_path)
-                                            .bright_green()
-                                    ));
-                                    pb.set_position(*count as u64);
-                                }
-                            }
-                            Some((relative_path.clone(), metadata))
-                        }
-                        Err(e) => {
-                            // Update progress for errors
-                            let mut count = processed_count.lock().unwrap();
-                            *count += 1;
-                            if let Ok(pb) = pb_clone.lock() {
-                                pb.set_message(format!(
-                                    "✗ {} - {}",
-                                    relative_path
-                                        .split('/')
-                                        .last()
-                                        .unwrap_or(relative_path)
-                                        .bright_red(),
-                                    e.to_string().red()
-                                ));
-                                pb.set_position(*count as u64);
-                            }
-                            None
+    (files_to_process, files_to_remove)
+}
+
+/// Extracts metadata for `files_to_process` in parallel, skipping files that fail.
+///
+/// Each extracted track is also recorded in `checkpoint` so the work survives
+/// an interruption.
+fn process_files(
+    files_to_process: &CurrentFiles,
+    fields: FieldSet,
+    checkpoint: Option<&Checkpoint>,
+) -> Extracted {
+    // Process only changed/new files
+    if files_to_process.is_empty() {
+        return (HashMap::new(), HashMap::new());
+    }
+
+    // Create multi-progress for parallel processing
+    let multi_progress = Arc::new(MultiProgress::new());
+    let main_pb = multi_progress.add(ProgressBar::new(files_to_process.len() as u64));
+    main_pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}")
+            .unwrap()
+            .progress_chars("█▉▊▋▌▍▎▏  "),
+    );
+    main_pb.set_message("🎵 Processing audio files...");
+
+    // Use Arc<Mutex<>> to reduce progress bar contention
+    let pb_clone = Arc::new(Mutex::new(main_pb.clone()));
+    let processed_count = Arc::new(Mutex::new(0usize));
+
+    let results: Vec<(String, Result<Track, FailedFile>)> = files_to_process
+        .par_iter()
+        .map(
+            |(relative_path, (file_path, stamp))| match extract_metadata(file_path, fields) {
+                Ok(metadata) => {
+                    if let Some(checkpoint) = checkpoint {
+                        // A failed journal write only costs re-extraction on resume
+                        let _ = checkpoint.record(relative_path, &metadata);
+                    }
+                    // Update progress less frequently to reduce contention
+                    let mut count = processed_count.lock().unwrap();
+                    *count += 1;
+                    if count.is_multiple_of(10) || *count == files_to_process.len() {
+                        if let Ok(pb) = pb_clone.lock() {
+                            pb.set_message(format!(
+                                "✓ {}",
+                                relative_path
+                                    .split('/')
+                                    .next_back()
+                                    .unwrap_or(relative_path)
+                                    .bright_green()
+                            ));
+                            pb.set_position(*count as u64);
                         }
+                    }
+                    (relative_path.clone(), Ok(metadata))
+                }
+                Err(e) => {
+                    // Update progress for errors
+                    let mut count = processed_count.lock().unwrap();
+                    *count += 1;
+                    if let Ok(pb) = pb_clone.lock() {
+                        pb.set_message(format!(
+                            "✗ {} - {}",
+                            relative_path
+                                .split('/')
+                                .next_back()
+                                .unwrap_or(relative_path)
+                                .bright_red(),
+                            e.to_string().red()
+                        ));
+                        pb.set_position(*count as u64);
+                    }
+                    let failed = FailedFile {
+                        error: e.to_string(),
+                        stamp: *stamp,
                     };
-                    // Remove artificial delay that was limiting throughput
-                    result
-                })
-                .collect();
-
-            main_pb.finish_with_message(format!(
-                "{} Processed {} files successfully",
-                "🎉".bright_green(),
-                new_tracks.len().to_string().bright_yellow().bold()
-            ));
-
-            // Update the existing tracks with new data
-            for (path, metadata) in new_tracks {
-                existing_tracks.insert(path, metadata);
+                    (relative_path.clone(), Err(failed))
+                }
+            },
+        )
+        .collect();
+
+    let mut new_tracks = HashMap::new();
+    let mut failed = HashMap::new();
+    for (path, result) in results {
+        match result {
+            Ok(track) => {
+                new_tracks.insert(path, track);
+            }
+            Err(failure) => {
+                failed.insert(path, failure);
             }
         }
     }
 
-    // Create the index structure
-    let index = json!({
-        "version": 1,
-        "tracks": existing_tracks
-    });
+    main_pb.finish_with_message(format!(
+        "{} Processed {} files successfully",
+        "🎉".bright_green(),
+        new_tracks.len().to_string().bright_yellow().bold()
+    ));
 
-    if dry_run {
-        println!(
-            "{} {} Would write index to: {}",
-            "🔍".bright_blue(),
-            "DRY RUN:".bright_yellow().bold(),
-            index_path.display().to_string().bright_white()
-        );
+    (new_tracks, failed)
+}
+
+fn stored_stamps(tracks: &BTreeMap<String, Track>) -> BTreeMap<String, FileStamp> {
+    tracks
+        .iter()
+        .map(|(path, track)| (path.clone(), track.stamp()))
+        .collect()
+}
+
+/// Fills in the full stamp of unchanged tracks indexed before nanosecond
+/// mtimes were recorded, so later runs no longer fall back to whole seconds.
+fn refresh_legacy_stamps(
+    tracks: &mut BTreeMap<String, Track>,
+    current_files: &CurrentFiles,
+    files_to_process: &CurrentFiles,
+) {
+    for (path, track) in tracks.iter_mut() {
+        if track.mtime_ns.is_some() || files_to_process.contains_key(path) {
+            continue;
+        }
+        if let Some((_, stamp)) = current_files.get(path) {
+            track.set_stamp(*stamp);
+        }
+    }
+}
+
+/// Drops the scope of an update that rebuilds the index: a scoped pass only
+/// refreshes the scope, so it would write an index of the changed paths alone.
+fn unscoped_for_rebuild(
+    scope: Option<&BTreeSet<String>>,
+    rebuild: bool,
+) -> Option<&BTreeSet<String>> {
+    if rebuild && scope.is_some() {
         println!(
-            "{} Index would contain {} tracks",
-            "📊".bright_blue(),
-            existing_tracks.len().to_string().bright_yellow().bold()
+            "{} The index needs rebuilding, indexing every file instead of the changed paths",
+            "♻️".bright_blue()
         );
-    } else {
-        match serde_json::to_string_pretty(&index) {
-            Ok(json_string) => match fs::write(&index_path, json_string) {
-                Ok(_) => println!(
-                    "{} Index written to: {} ({} tracks)",
-                    "💾".bright_green(),
-                    index_path.display().to_string().bright_white(),
-                    existing_tracks.len().to_string().bright_yellow().bold()
-                ),
-                Err(e) => eprintln!("{} Error writing index file: {}", "✗".red().bold(), e),
-            },
-            Err(e) => eprintln!(
-                "{} Error serializing index to JSON: {}",
-                "✗".red().bold(),
-                e
-            ),
-        }
+        return None;
     }
+    scope
 }
 
-fn load_existing_index(index_path: &Path) -> HashMap<String, Value> {
+/// Loads the tracks and recorded failures of an existing index, along with
+/// every stored track that has a checksum or fingerprint.
+///
+/// The index is `None` when there is none to build on: it is missing,
+/// unreadable, or lacks the full metadata a full run needs. The second map
+/// survives such a rebuild, so `carry_over_digests` can keep those values for
+/// files that did not change.
+fn load_existing_index(
+    index_path: &Path,
+    fields: FieldSet,
+) -> (Option<Index>, BTreeMap<String, Track>) {
     if !index_path.exists() {
-        return HashMap::new();
-    }
-
-    match fs::read_to_string(index_path) {
-        Ok(content) => match serde_json::from_str::<Value>(&content) {
-            Ok(index) => {
-                if let Some(tracks) = index.get("tracks") {
-                    if let Some(tracks_obj) = tracks.as_object() {
-                        return tracks_obj
-                            .iter()
-                            .map(|(k, v)| (k.clone(), v.clone()))
-                            .collect();
-                    }
-                }
+        return (None, BTreeMap::new());
+    }
+
+    match Index::load(index_path) {
+        Ok(mut index) => {
+            let digests = digest_tracks(&index.tracks);
+            if fields == FieldSet::Full && index.fields != FieldSet::Full {
+                println!(
+                    "{} Existing index lacks full metadata, re-extracting all tracks",
+                    "♻️".bright_blue()
+                );
+                return (None, digests);
             }
-            Err(e) => {
-                eprintln!("{} Error parsing existing index: {}", "✗".red().bold(), e);
+            if !index.stale.is_empty() {
+                println!(
+                    "{} Refreshing {} entries written by an older index schema",
+                    "♻️".bright_blue(),
+                    index.stale.len().to_string().bright_yellow().bold()
+                );
             }
-        },
-        Err(e) => {
+            // Dropping stale entries makes them look new so they are re-extracted
+            for path in &index.stale {
+                index.tracks.remove(path);
+            }
+            return (Some(index), digests);
+        }
+        Err(IndexError::Io(e)) => {
             eprintln!("{} Error reading existing index: {}", "✗".red().bold(), e);
         }
+        Err(IndexError::Parse(e)) => {
+            eprintln!("{} Error parsing existing index: {}", "✗".red().bold(), e);
+        }
+        Err(e) => {
+            eprintln!("{} Ignoring existing index: {}", "✗".red().bold(), e);
+        }
     }
 
-    HashMap::new()
+    (None, salvage_digests(index_path))
 }
 
-fn identify_changes(
-    existing_tracks: &HashMap<String, Value>,
-    current_files: &HashMap<String, (std::path::PathBuf, SystemTime)>,
-) -> (
-    HashMap<String, (std::path::PathBuf, SystemTime)>,
-    HashSet<String>,
+/// Returns the tracks that have a checksum or fingerprint.
+fn digest_tracks(tracks: &BTreeMap<String, Track>) -> BTreeMap<String, Track> {
+    tracks
+        .iter()
+        .filter(|(_, track)| track.checksum.is_some() || track.fingerprint.is_some())
+        .map(|(path, track)| (path.clone(), track.clone()))
+        .collect()
+}
+
+/// Reads what it can of the tracks of an index that failed to load.
+fn salvage_digests(index_path: &Path) -> BTreeMap<String, Track> {
+    let value = fs::read_to_string(index_path)
+        .ok()
+        .and_then(|json| serde_json::from_str::<serde_json::Value>(&json).ok());
+    let tracks = match value.as_ref().and_then(|value| value.get("tracks")) {
+        Some(serde_json::Value::Object(tracks)) => tracks,
+        _ => return BTreeMap::new(),
+    };
+    let tracks = tracks
+        .iter()
+        .filter_map(|(path, track)| {
+            serde_json::from_value(track.clone())
+                .ok()
+                .map(|track| (path.clone(), track))
+        })
+        .collect();
+    digest_tracks(&tracks)
+}
+
+/// Copies the checksum and fingerprint of `previous` entries into re-extracted
+/// tracks whose file is unchanged, since extraction never computes them.
+fn carry_over_digests<'a>(
+    previous: &BTreeMap<String, Track>,
+    tracks: impl Iterator<Item = (&'a String, &'a mut Track)>,
+    check_ctime: bool,
 ) {
+    for (path, track) in tracks {
+        let old = match previous.get(path) {
+            Some(old) if old.stamp().is_unchanged(&track.stamp(), check_ctime) => old,
+            _ => continue,
+        };
+        if track.checksum.is_none() {
+            track.checksum = old.checksum.clone();
+        }
+        if track.fingerprint.is_none() {
+            track.fingerprint = old.fingerprint.clone();
+        }
+    }
+}
+
+fn identify_changes(
+    existing_stamps: &BTreeMap<String, FileStamp>,
+    current_files: &CurrentFiles,
+    check_ctime: bool,
+) -> (CurrentFiles, HashSet<String>) {
     let mut files_to_process = HashMap::new();
     let mut files_to_remove = HashSet::new();
 
     // Find files that need to be removed (exist in index but not on disk)
-    for existing_path in existing_tracks.keys() {
+    for existing_path in existing_stamps.keys() {
         if !current_files.contains_key(existing_path) {
             files_to_remove.insert(existing_path.clone());
         }
     }
 
     // Find files that need to be processed (new files or modified files)
-    for (path, (file_path, current_mtime)) in current_files {
-        let needs_processing = if let Some(existing_metadata) = existing_tracks.get(path) {
+    for (path, (file_path, current_stamp)) in current_files {
+        let needs_processing = match existing_stamps.get(path) {
             // File exists in index, check if it's been modified
-            if let Some(stored_mtime) = existing_metadata.get("mtime") {
-                if let Some(stored_timestamp) = stored_mtime.as_u64() {
-                    let current_timestamp = unix_timestamp_secs(*current_mtime);
-                    current_timestamp != stored_timestamp
-                } else {
-                    true // Invalid stored timestamp, reprocess
-                }
-            } else {
-                true // No stored timestamp, reprocess
-            }
-        } else {
-            true // New file
+            Some(stored_stamp) => !stored_stamp.is_unchanged(current_stamp, check_ctime),
+            None => true, // New file
         };
 
         if needs_processing {
-            files_to_process.insert(path.clone(), (file_path.clone(), *current_mtime));
+            files_to_process.insert(path.clone(), (file_path.clone(), *current_stamp));
         }
     }
 
     (files_to_process, files_to_remove)
 }
 
-fn extract_metadata(file_path: &Path) -> Result<Value, Box<dyn std::error::Error>> {
-    let tagged_file = read_from_path(file_path)?;
+pub(crate) fn extract_metadata(
+    file_path: &Path,
+    fields: FieldSet,
+) -> Result<Track, Box<dyn std::error::Error>> {
+    let parse_options = ParseOptions::new().read_cover_art(fields == FieldSet::Full);
+    let tagged_file = Probe::open(file_path)?.options(parse_options).read()?;
     let tag = tagged_file.primary_tag();
     let properties = tagged_file.properties();
 
-    let mut metadata = Map::new();
-    metadata.insert("compilation".to_string(), json!(false));
-
-    // Add required fields that match Track type
+    let mut track = Track::default();
 
-    // Get file metadata for mtime and size
+    // Get file metadata for change detection
     if let Ok(file_metadata) = fs::metadata(file_path) {
-        if let Ok(modified) = file_metadata.modified() {
-            let timestamp = unix_timestamp_secs(modified);
-            metadata.insert("mtime".to_string(), json!(timestamp));
-        }
-        metadata.insert("size".to_string(), json!(file_metadata.len()));
+        track.set_stamp(FileStamp::from_metadata(&file_metadata));
     }
 
-    // Duration in seconds (matching Track.duration field)
+    // Duration in whole seconds
     if properties.duration().as_secs() > 0 {
-        metadata.insert(
-            "duration".to_string(),
-            json!(properties.duration().as_secs()),
-        );
+        track.duration = Some(properties.duration().as_secs());
     }
 
-    // Bitrate
-    if let Some(bitrate) = properties.overall_bitrate() {
-        metadata.insert("bitrate".to_string(), json!(bitrate));
+    track.bitrate = properties.overall_bitrate();
+
+    if fields == FieldSet::Full {
+        track.duration_ms = Some(properties.duration().as_millis() as u64);
+        track.sample_rate = properties.sample_rate();
+        track.bit_depth = properties.bit_depth();
+        track.channels = properties.channels();
+        let (codec, container) = codec_and_container(file_path, tagged_file.file_type());
+        track.codec = codec.map(str::to_owned);
+        track.container = container.map(str::to_owned);
     }
 
-    // Tag metadata - only add fields that have values
+    // Tag metadata - only fill fields that have values
     if let Some(tag) = tag {
-        if let Some(compilation_flag) = tag.get_string(ItemKey::FlagCompilation) {
-            let normalized = compilation_flag.trim();
-            let is_compilation = normalized == "1"
-                || normalized.eq_ignore_ascii_case("true")
-                || normalized.eq_ignore_ascii_case("yes");
-            metadata.insert("compilation".to_string(), json!(is_compilation));
-        }
-
-        // Required fields (album, artist) - add empty strings if missing
-        let album = tag.album().map(|s| s.to_string()).unwrap_or_default();
-        let artist = tag.artist().map(|s| s.to_string()).unwrap_or_default();
-
-        metadata.insert("album".to_string(), json!(album));
-        metadata.insert("artist".to_string(), json!(artist));
-
-        // Optional fields - only add if present
-        if let Some(title) = tag.title() {
-            metadata.insert("title".to_string(), json!(title.to_string()));
-        }
-
-        if let Some(album_artist) = tag.get_string(ItemKey::AlbumArtist) {
-            metadata.insert("albumArtist".to_string(), json!(album_artist));
-        }
-
-        if let Some(composer) = tag.get_string(ItemKey::Composer) {
-            metadata.insert("composer".to_string(), json!(composer));
-        }
-
-        if let Some(genre) = tag.genre() {
-            metadata.insert("genre".to_string(), json!(genre.to_string()));
-        }
-
-        if let Some(date) = tag.date() {
-            metadata.insert("year".to_string(), json!(date.year));
-        }
-
-        if let Some(track) = tag.track() {
-            metadata.insert("trackNumber".to_string(), json!(track));
-        }
-
-        if let Some(track_total) = tag.track_total() {
-            metadata.insert("trackNumberTotal".to_string(), json!(track_total));
-        }
-
-        if let Some(disc) = tag.disk() {
-            metadata.insert("diskNumber".to_string(), json!(disc));
+        extract_tag_fields(tag, &mut track);
+        if fields == FieldSet::Full {
+            extract_full_tag_fields(tag, &mut track);
         }
+    }
 
-        if let Some(disc_total) = tag.disk_total() {
-            metadata.insert("diskNumberTotal".to_string(), json!(disc_total));
-        }
+    Ok(track)
+}
 
-        // Additional fields that might be available - using ItemKey for fields not in standard API
-        if let Some(performer) = tag.get_string(ItemKey::Performer) {
-            metadata.insert("performer".to_string(), json!(performer));
-        }
+/// Fills the tag fields of `track` recorded in every index, which `norm`
+/// uses too.
+pub(crate) fn extract_tag_fields(tag: &Tag, track: &mut Track) {
+    if let Some(compilation_flag) = tag.get_string(ItemKey::FlagCompilation) {
+        let normalized = compilation_flag.trim();
+        track.compilation = normalized == "1"
+            || normalized.eq_ignore_ascii_case("true")
+            || normalized.eq_ignore_ascii_case("yes");
+    }
 
-        if let Some(work) = tag.get_string(ItemKey::Work) {
-            metadata.insert("work".to_string(), json!(work));
-        }
+    // Required fields (album, artist) - stay empty strings if missing
+    track.album = tag.album().map(|s| s.to_string()).unwrap_or_default();
+    track.artist = tag.artist().map(|s| s.to_string()).unwrap_or_default();
+
+    track.title = tag.title().map(|s| s.to_string());
+    track.album_artist = tag.get_string(ItemKey::AlbumArtist).map(str::to_owned);
+    track.composer = tag.get_string(ItemKey::Composer).map(str::to_owned);
+    track.genre = tag.genre().map(|s| s.to_string());
+    track.year = tag.date().map(|date| date.year);
+    track.track_number = tag.track();
+    track.track_number_total = tag.track_total();
+    track.disk_number = tag.disk();
+    track.disk_number_total = tag.disk_total();
+
+    // Additional fields that might be available - using ItemKey for fields not in standard API
+    track.performer = tag.get_string(ItemKey::Performer).map(str::to_owned);
+    track.work = tag.get_string(ItemKey::Work).map(str::to_owned);
+    track.grouping = tag.get_string(ItemKey::ContentGroup).map(str::to_owned);
+    track.label = tag.get_string(ItemKey::Label).map(str::to_owned);
+
+    // Rating would typically need to be stored/managed by the application
+    // as it's not commonly stored in audio file tags
+}
 
-        if let Some(grouping) = tag.get_string(ItemKey::ContentGroup) {
-            metadata.insert("grouping".to_string(), json!(grouping));
-        }
+fn extract_full_tag_fields(tag: &Tag, track: &mut Track) {
+    let text = |key: ItemKey| tag.get_string(key).map(str::to_owned);
+
+    track.musicbrainz_recording_id = text(ItemKey::MusicBrainzRecordingId);
+    track.musicbrainz_release_id = text(ItemKey::MusicBrainzReleaseId);
+    track.musicbrainz_artist_id = text(ItemKey::MusicBrainzArtistId);
+    track.isrc = text(ItemKey::Isrc);
+
+    track.replaygain_track_gain = tag
+        .get_string(ItemKey::ReplayGainTrackGain)
+        .and_then(parse_gain);
+    track.replaygain_track_peak = tag
+        .get_string(ItemKey::ReplayGainTrackPeak)
+        .and_then(parse_peak);
+    track.replaygain_album_gain = tag
+        .get_string(ItemKey::ReplayGainAlbumGain)
+        .and_then(parse_gain);
+    track.replaygain_album_peak = tag
+        .get_string(ItemKey::ReplayGainAlbumPeak)
+        .and_then(parse_peak);
+
+    track.artist_sort = text(ItemKey::TrackArtistSortOrder);
+    track.album_sort = text(ItemKey::AlbumTitleSortOrder);
+    track.album_artist_sort = text(ItemKey::AlbumArtistSortOrder);
+    track.title_sort = text(ItemKey::TrackTitleSortOrder);
+    track.composer_sort = text(ItemKey::ComposerSortOrder);
+
+    track.has_lyrics = Some(
+        tag.get_string(ItemKey::Lyrics).is_some()
+            || tag.get_string(ItemKey::UnsyncLyrics).is_some(),
+    );
 
-        if let Some(label) = tag.get_string(ItemKey::Label) {
-            metadata.insert("label".to_string(), json!(label));
+    let picture = tag.pictures().first();
+    track.has_picture = Some(picture.is_some());
+    if let Some(info) = picture.and_then(|p| PictureInformation::from_picture(p).ok()) {
+        // Unsupported image formats report zeroed dimensions
+        if info.width > 0 && info.height > 0 {
+            track.picture_width = Some(info.width);
+            track.picture_height = Some(info.height);
         }
+    }
+}
 
-        // Rating would typically need to be stored/managed by the application
-        // as it's not commonly stored in audio file tags
+/// Returns the codec and container names for a probed file type.
+fn codec_and_container(
+    file_path: &Path,
+    file_type: FileType,
+) -> (Option<&'static str>, Option<&'static str>) {
+    match file_type {
+        FileType::Aac => (Some("aac"), Some("adts")),
+        FileType::Aiff => (Some("pcm"), Some("aiff")),
+        FileType::Ape => (Some("ape"), Some("ape")),
+        FileType::Flac => (Some("flac"), Some("flac")),
+        FileType::Mpeg => (Some("mp3"), Some("mpeg")),
+        FileType::Mp4 => (mp4_codec(file_path), Some("mp4")),
+        FileType::Mpc => (Some("musepack"), Some("mpc")),
+        FileType::Opus => (Some("opus"), Some("ogg")),
+        FileType::Vorbis => (Some("vorbis"), Some("ogg")),
+        FileType::Speex => (Some("speex"), Some("ogg")),
+        FileType::Wav => (Some("pcm"), Some("wav")),
+        FileType::WavPack => (Some("wavpack"), Some("wavpack")),
+        _ => (None, None),
     }
+}
 
-    Ok(Value::Object(metadata))
+/// MP4 files can hold several codecs, which only the MP4 properties expose.
+fn mp4_codec(file_path: &Path) -> Option<&'static str> {
+    let mut file = fs::File::open(file_path).ok()?;
+    let mp4 = Mp4File::read_from(&mut file, ParseOptions::new().read_tags(false)).ok()?;
+    match mp4.properties().codec() {
+        Mp4Codec::AAC => Some("aac"),
+        Mp4Codec::ALAC => Some("alac"),
+        Mp4Codec::MP3 => Some("mp3"),
+        Mp4Codec::FLAC => Some("flac"),
+        _ => None,
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
     use std::fs;
-    use std::path::PathBuf;
-    use std::time::{Duration, SystemTime};
+    use std::path::{Path, PathBuf};
 
     use serde_json::json;
     use tempfile::tempdir;
 
-    use super::{identify_changes, load_existing_index};
+    use super::{
+        carry_over_digests, identify_changes, index_directory, load_existing_index,
+        refresh_legacy_stamps, stored_stamps, update_errors, update_index, written_fields,
+        IndexFormat, IndexOptions,
+    };
+    use crate::schema::{FailedFile, FieldSet, FileStamp, Track};
+    use crate::sqlite;
+
+    /// Writes a short silent 16-bit mono PCM file.
+    fn write_wav(path: &Path) {
+        let samples = vec![0u8; 800];
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + samples.len() as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&8000u32.to_le_bytes());
+        wav.extend_from_slice(&16000u32.to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&samples);
+        fs::create_dir_all(path.parent().expect("parent")).expect("create dirs");
+        fs::write(path, wav).expect("write wav");
+    }
+
+    /// Indexes two albums with minimal fields, then runs a full update
+    /// scoped to the first one.
+    fn update_minimal_index_with_full_fields(dir: &Path, format: IndexFormat) {
+        write_wav(&dir.join("A/one.wav"));
+        write_wav(&dir.join("B/two.wav"));
+        let options = IndexOptions {
+            format,
+            ..Default::default()
+        };
+        index_directory(dir.to_str().expect("utf-8"), options);
+
+        update_index(
+            dir,
+            &BTreeSet::from(["A".to_owned()]),
+            IndexOptions {
+                fields: FieldSet::Full,
+                ..options
+            },
+        );
+    }
 
     #[test]
-    fn load_existing_index_returns_empty_for_missing_file() {
+    fn load_existing_index_returns_none_for_missing_file() {
         let dir = tempdir().expect("tempdir");
 
-        let tracks = load_existing_index(&dir.path().join("index.json"));
+        let index = load_existing_index(&dir.path().join("index.json"), FieldSet::Minimal).0;
 
-        assert!(tracks.is_empty());
+        assert!(index.is_none());
     }
 
     #[test]
@@ -494,65 +1152,211 @@ mod tests {
             serde_json::to_string(&json!({
                 "version": 1,
                 "tracks": {
-                    "Artist/Album/track.flac": { "album": "Album" }
+                    "Artist/Album/track.flac": {
+                        "compilation": false,
+                        "mtime": 10,
+                        "size": 20,
+                        "album": "Album",
+                        "artist": "Artist"
+                    }
                 }
             }))
             .expect("json"),
         )
         .expect("write");
 
-        let tracks = load_existing_index(&index_path);
+        let tracks = load_existing_index(&index_path, FieldSet::Minimal)
+            .0
+            .expect("index")
+            .tracks;
 
         assert_eq!(tracks.len(), 1);
         assert_eq!(
             tracks.get("Artist/Album/track.flac"),
-            Some(&json!({ "album": "Album" }))
+            Some(&Track {
+                mtime: Some(10),
+                size: Some(20),
+                album: "Album".to_owned(),
+                artist: "Artist".to_owned(),
+                ..Default::default()
+            })
         );
     }
 
+    #[test]
+    fn load_existing_index_drops_entries_missing_required_fields() {
+        let dir = tempdir().expect("tempdir");
+        let index_path = dir.path().join("index.json");
+        fs::write(
+            &index_path,
+            serde_json::to_string(&json!({
+                "version": 1,
+                "tracks": {
+                    "Artist/Album/track.flac": { "album": "Album", "mtime": 10 }
+                }
+            }))
+            .expect("json"),
+        )
+        .expect("write");
+
+        assert!(load_existing_index(&index_path, FieldSet::Minimal)
+            .0
+            .expect("index")
+            .tracks
+            .is_empty());
+    }
+
+    #[test]
+    fn load_existing_index_ignores_invalid_index() {
+        let dir = tempdir().expect("tempdir");
+        let index_path = dir.path().join("index.json");
+        fs::write(
+            &index_path,
+            serde_json::to_string(&json!({
+                "version": 1,
+                "tracks": {
+                    "../outside.flac": { "album": "Album" }
+                }
+            }))
+            .expect("json"),
+        )
+        .expect("write");
+
+        assert!(load_existing_index(&index_path, FieldSet::Minimal)
+            .0
+            .is_none());
+    }
+
+    #[test]
+    fn load_existing_index_discards_minimal_index_for_full_runs() {
+        let dir = tempdir().expect("tempdir");
+        let index_path = dir.path().join("index.json");
+        fs::write(
+            &index_path,
+            serde_json::to_string(&json!({
+                "version": 2,
+                "fields": "minimal",
+                "tracks": {
+                    "Artist/Album/track.flac": {
+                        "compilation": false,
+                        "mtime": 10,
+                        "size": 20,
+                        "album": "Album",
+                        "artist": "Artist"
+                    }
+                }
+            }))
+            .expect("json"),
+        )
+        .expect("write");
+
+        assert_eq!(
+            load_existing_index(&index_path, FieldSet::Minimal)
+                .0
+                .expect("index")
+                .tracks
+                .len(),
+            1
+        );
+        assert!(load_existing_index(&index_path, FieldSet::Full).0.is_none());
+    }
+
+    #[test]
+    fn keeps_digests_of_unchanged_tracks_when_the_index_is_discarded() {
+        let dir = tempdir().expect("tempdir");
+        let index_path = dir.path().join("index.json");
+        fs::write(
+            &index_path,
+            serde_json::to_string(&json!({
+                "version": 2,
+                "fields": "minimal",
+                "tracks": {
+                    "same.flac": {
+                        "compilation": false, "mtimeNs": 10, "size": 5, "album": "A", "artist": "B",
+                        "checksum": "abc", "fingerprint": "AQAA"
+                    },
+                    "changed.flac": {
+                        "compilation": false, "mtimeNs": 10, "size": 5, "album": "A", "artist": "B",
+                        "checksum": "def"
+                    }
+                }
+            }))
+            .expect("json"),
+        )
+        .expect("write");
+        let extracted = |size| Track {
+            mtime_ns: Some(10),
+            size: Some(size),
+            ..Default::default()
+        };
+        let mut tracks = HashMap::from([
+            ("same.flac".to_owned(), extracted(5)),
+            ("changed.flac".to_owned(), extracted(6)),
+        ]);
+
+        let (index, digests) = load_existing_index(&index_path, FieldSet::Full);
+        carry_over_digests(&digests, tracks.iter_mut(), false);
+
+        assert!(index.is_none());
+        assert_eq!(tracks["same.flac"].checksum.as_deref(), Some("abc"));
+        assert_eq!(tracks["same.flac"].fingerprint.as_deref(), Some("AQAA"));
+        assert_eq!(tracks["changed.flac"].checksum, None);
+    }
+
+    #[test]
+    fn minimal_runs_only_keep_a_full_index_full_when_nothing_was_extracted() {
+        assert_eq!(
+            written_fields(FieldSet::Minimal, FieldSet::Full, false),
+            FieldSet::Full
+        );
+        assert_eq!(
+            written_fields(FieldSet::Minimal, FieldSet::Full, true),
+            FieldSet::Minimal
+        );
+        assert_eq!(
+            written_fields(FieldSet::Full, FieldSet::Minimal, true),
+            FieldSet::Full
+        );
+    }
+
+    fn stamp(mtime_ns: u64, size: u64) -> FileStamp {
+        FileStamp {
+            mtime: Some(mtime_ns / 1_000_000_000),
+            mtime_ns: Some(mtime_ns),
+            size: Some(size),
+            ..Default::default()
+        }
+    }
+
+    fn current(path: &str, stamp: FileStamp) -> (String, (PathBuf, FileStamp)) {
+        (path.to_owned(), (PathBuf::from("/tmp").join(path), stamp))
+    }
+
     #[test]
     fn identify_changes_detects_removed_new_and_modified_files() {
-        let existing_tracks = HashMap::from([
+        let existing_stamps = BTreeMap::from([
             (
                 "Artist/Album/stale.flac".to_owned(),
-                json!({ "mtime": 10_u64 }),
+                stamp(10_000_000_000, 5),
             ),
             (
                 "Artist/Album/updated.flac".to_owned(),
-                json!({ "mtime": 10_u64 }),
+                stamp(10_000_000_000, 5),
             ),
             (
                 "Artist/Album/unchanged.flac".to_owned(),
-                json!({ "mtime": 20_u64 }),
+                stamp(20_000_000_000, 5),
             ),
         ]);
 
         let current_files = HashMap::from([
-            (
-                "Artist/Album/updated.flac".to_owned(),
-                (
-                    PathBuf::from("/tmp/updated.flac"),
-                    SystemTime::UNIX_EPOCH + Duration::from_secs(11),
-                ),
-            ),
-            (
-                "Artist/Album/unchanged.flac".to_owned(),
-                (
-                    PathBuf::from("/tmp/unchanged.flac"),
-                    SystemTime::UNIX_EPOCH + Duration::from_secs(20),
-                ),
-            ),
-            (
-                "Artist/Album/new.flac".to_owned(),
-                (
-                    PathBuf::from("/tmp/new.flac"),
-                    SystemTime::UNIX_EPOCH + Duration::from_secs(30),
-                ),
-            ),
+            current("Artist/Album/updated.flac", stamp(11_000_000_000, 5)),
+            current("Artist/Album/unchanged.flac", stamp(20_000_000_000, 5)),
+            current("Artist/Album/new.flac", stamp(30_000_000_000, 5)),
         ]);
 
         let (files_to_process, files_to_remove) =
-            identify_changes(&existing_tracks, &current_files);
+            identify_changes(&existing_stamps, &current_files, false);
 
         assert_eq!(files_to_remove.len(), 1);
         assert!(files_to_remove.contains("Artist/Album/stale.flac"));
@@ -562,24 +1366,136 @@ mod tests {
         assert!(!files_to_process.contains_key("Artist/Album/unchanged.flac"));
     }
 
+    #[test]
+    fn identify_changes_detects_same_second_and_same_mtime_changes() {
+        let existing_stamps = BTreeMap::from([
+            ("retagged.flac".to_owned(), stamp(10_100_000_000, 5)),
+            ("rewritten.flac".to_owned(), stamp(10_100_000_000, 5)),
+        ]);
+        let current_files = HashMap::from([
+            current("retagged.flac", stamp(10_900_000_000, 5)),
+            current("rewritten.flac", stamp(10_100_000_000, 6)),
+        ]);
+
+        let (files_to_process, _) = identify_changes(&existing_stamps, &current_files, false);
+
+        assert_eq!(files_to_process.len(), 2);
+    }
+
+    #[test]
+    fn identify_changes_reprocesses_missing_stored_mtime() {
+        let existing_stamps =
+            BTreeMap::from([("Artist/Album/track.flac".to_owned(), FileStamp::default())]);
+        let current_files =
+            HashMap::from([current("Artist/Album/track.flac", stamp(10_000_000_000, 5))]);
+
+        let (files_to_process, files_to_remove) =
+            identify_changes(&existing_stamps, &current_files, false);
+
+        assert!(files_to_remove.is_empty());
+        assert!(files_to_process.contains_key("Artist/Album/track.flac"));
+    }
+
     #[test]
     fn identify_changes_reprocesses_invalid_stored_mtime() {
-        let existing_tracks = HashMap::from([(
-            "Artist/Album/track.flac".to_owned(),
-            json!({ "mtime": "bad-data" }),
-        )]);
-        let current_files = HashMap::from([(
-            "Artist/Album/track.flac".to_owned(),
-            (
-                PathBuf::from("/tmp/track.flac"),
-                SystemTime::UNIX_EPOCH + Duration::from_secs(10),
-            ),
-        )]);
+        let dir = tempdir().expect("tempdir");
+        let index_path = dir.path().join("index.json");
+        fs::write(
+            &index_path,
+            serde_json::to_string(&json!({
+                "version": 2,
+                "tracks": { "Artist/Album/track.flac": { "mtime": "bad-data" } }
+            }))
+            .expect("json"),
+        )
+        .expect("write index");
+        let current_files =
+            HashMap::from([current("Artist/Album/track.flac", stamp(10_000_000_000, 5))]);
 
+        let index = load_existing_index(&index_path, FieldSet::Minimal)
+            .0
+            .expect("index");
         let (files_to_process, files_to_remove) =
-            identify_changes(&existing_tracks, &current_files);
+            identify_changes(&stored_stamps(&index.tracks), &current_files, false);
 
         assert!(files_to_remove.is_empty());
         assert!(files_to_process.contains_key("Artist/Album/track.flac"));
     }
+
+    #[test]
+    fn refreshes_stamps_of_unchanged_legacy_tracks() {
+        let mut tracks = BTreeMap::from([(
+            "track.flac".to_owned(),
+            Track {
+                mtime: Some(10),
+                size: Some(5),
+                ..Default::default()
+            },
+        )]);
+        let current_files = HashMap::from([current("track.flac", stamp(10_500_000_000, 5))]);
+
+        refresh_legacy_stamps(&mut tracks, &current_files, &HashMap::new());
+
+        assert_eq!(tracks["track.flac"].mtime_ns, Some(10_500_000_000));
+    }
+
+    #[test]
+    fn update_errors_tracks_failures_across_runs() {
+        let failure = |error: &str| FailedFile {
+            error: error.to_owned(),
+            ..Default::default()
+        };
+        let mut errors = BTreeMap::from([
+            ("fixed.flac".to_owned(), failure("old")),
+            ("deleted.flac".to_owned(), failure("old")),
+            ("still.flac".to_owned(), failure("old")),
+        ]);
+
+        update_errors(
+            &mut errors,
+            &HashSet::from(["deleted.flac".to_owned()]),
+            &HashMap::from([("fixed.flac".to_owned(), Track::default())]),
+            &HashMap::from([("new.flac".to_owned(), failure("bad header"))]),
+        );
+
+        assert_eq!(
+            errors.keys().collect::<Vec<_>>(),
+            vec!["new.flac", "still.flac"]
+        );
+        assert_eq!(errors["new.flac"].error, "bad header");
+    }
+
+    #[test]
+    fn scoped_json_rebuild_keeps_tracks_outside_the_scope() {
+        let dir = tempdir().expect("tempdir");
+        update_minimal_index_with_full_fields(dir.path(), IndexFormat::Json);
+
+        let index = load_existing_index(&dir.path().join("index.json"), FieldSet::Full)
+            .0
+            .expect("index");
+
+        assert_eq!(index.fields, FieldSet::Full);
+        assert_eq!(
+            index.tracks.keys().collect::<Vec<_>>(),
+            vec!["A/one.wav", "B/two.wav"]
+        );
+    }
+
+    #[test]
+    fn scoped_sqlite_rebuild_keeps_tracks_outside_the_scope() {
+        let dir = tempdir().expect("tempdir");
+        update_minimal_index_with_full_fields(dir.path(), IndexFormat::Sqlite);
+
+        let db = sqlite::open(&dir.path().join("index.sqlite"), false).expect("open");
+        let stamps = sqlite::load_stamps(&db).expect("stamps");
+
+        assert_eq!(
+            sqlite::stored_fields(&db).expect("fields"),
+            Some(FieldSet::Full)
+        );
+        assert_eq!(
+            stamps.keys().collect::<Vec<_>>(),
+            vec!["A/one.wav", "B/two.wav"]
+        );
+    }
 }
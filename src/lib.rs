@@ -0,0 +1,23 @@
+pub mod aggregate;
+pub mod checkpoint;
+pub mod cli;
+pub mod dest_names;
+pub mod dupes;
+pub mod error;
+pub mod fingerprint;
+pub mod fs_utils;
+pub mod index;
+pub mod lint;
+pub mod normalize;
+pub mod playlist;
+pub mod prune;
+pub mod query;
+pub mod replaygain;
+pub mod schema;
+pub mod sqlite;
+pub mod tag;
+pub mod tag_edit;
+pub mod text;
+pub mod transcode;
+pub mod verify;
+pub mod watch;
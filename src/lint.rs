@@ -0,0 +1,502 @@
+use colored::*;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use crate::aggregate::album_key;
+use crate::fs_utils::{canonicalize_path, glob_pattern, relative_path_string};
+use crate::index::{configure_thread_pool, extract_metadata};
+use crate::schema::{FieldSet, Track};
+
+/// Extensions of the files checked, the same formats `normalize` renames.
+const LINT_EXTENSIONS: &[&str] = &["flac", "opus", "m4a", "mp3"];
+
+/// How `loot lint` prints the problems it finds.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum LintOutput {
+    /// A readable listing grouped by album directory.
+    #[default]
+    Table,
+    /// One JSON object per problem.
+    Json,
+}
+
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum IssueKind {
+    Unreadable,
+    MissingAlbumArtist,
+    MissingAlbum,
+    MissingTitle,
+    MissingTrackNumber,
+    StrayWhitespace,
+    InconsistentAlbumArtist,
+    InconsistentAlbum,
+    InconsistentYear,
+    MixedCompilation,
+    DuplicateTrackNumber,
+    TrackNumberGap,
+    TrackTotalMismatch,
+}
+
+/// A metadata problem with a file or an album directory.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Issue {
+    /// Album directory, relative to the library root.
+    pub album: String,
+    /// File the problem is in, or `None` for problems with the whole album.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    pub kind: IssueKind,
+    pub message: String,
+}
+
+/// Text fields checked for leading, trailing or doubled whitespace.
+fn text_fields(track: &Track) -> [(&'static str, Option<&str>); 6] {
+    [
+        ("artist", Some(track.artist.as_str())),
+        ("album artist", track.album_artist.as_deref()),
+        ("album", Some(track.album.as_str())),
+        ("title", track.title.as_deref()),
+        ("genre", track.genre.as_deref()),
+        ("composer", track.composer.as_deref()),
+    ]
+}
+
+fn has_stray_whitespace(value: &str) -> bool {
+    value != value.trim() || value.contains("  ") || value.contains(['\t', '\n', '\r'])
+}
+
+fn is_blank(value: Option<&str>) -> bool {
+    value.map(|value| value.trim().is_empty()).unwrap_or(true)
+}
+
+/// Problems with a single file: the values `normalize` would silently
+/// default, and stray whitespace.
+fn lint_track(album: &str, path: &str, track: &Track) -> Vec<Issue> {
+    let issue = |kind, message: String| Issue {
+        album: album.to_owned(),
+        path: Some(path.to_owned()),
+        kind,
+        message,
+    };
+    let mut issues = Vec::new();
+    if !track.compilation && is_blank(track.album_artist.as_deref()) {
+        let message = if track.artist.trim().is_empty() {
+            "no album artist or artist; the artist directory will be empty".to_owned()
+        } else {
+            format!("no album artist; the artist \"{}\" is used", track.artist)
+        };
+        issues.push(issue(IssueKind::MissingAlbumArtist, message));
+    }
+    if track.album.trim().is_empty() {
+        issues.push(issue(
+            IssueKind::MissingAlbum,
+            "no album title; the album directory will be empty".to_owned(),
+        ));
+    }
+    if is_blank(track.title.as_deref()) {
+        issues.push(issue(
+            IssueKind::MissingTitle,
+            "no title; the file name will have none".to_owned(),
+        ));
+    }
+    if track.track_number.is_none() {
+        issues.push(issue(
+            IssueKind::MissingTrackNumber,
+            "no track number; it will be numbered 01".to_owned(),
+        ));
+    }
+    for (field, value) in text_fields(track) {
+        if let Some(value) = value.filter(|value| has_stray_whitespace(value)) {
+            issues.push(issue(
+                IssueKind::StrayWhitespace,
+                format!("stray whitespace in {}: {:?}", field, value),
+            ));
+        }
+    }
+    issues
+}
+
+/// Distinct values of a field across an album, in order.
+fn distinct<T: Ord, F: Fn(&Track) -> Option<T>>(
+    tracks: &[(&String, &Track)],
+    value: F,
+) -> BTreeSet<T> {
+    tracks
+        .iter()
+        .filter_map(|(_, track)| value(track))
+        .collect()
+}
+
+fn quoted(values: &BTreeSet<String>) -> String {
+    values
+        .iter()
+        .map(|value| format!("\"{}\"", value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Problems across the tracks of one album directory.
+fn lint_album(album: &str, tracks: &[(&String, &Track)]) -> Vec<Issue> {
+    let issue = |kind, message: String| Issue {
+        album: album.to_owned(),
+        path: None,
+        kind,
+        message,
+    };
+    let mut issues = Vec::new();
+
+    let compilations = tracks.iter().filter(|(_, track)| track.compilation).count();
+    if compilations > 0 && compilations < tracks.len() {
+        issues.push(issue(
+            IssueKind::MixedCompilation,
+            format!(
+                "{} of {} tracks are flagged as a compilation",
+                compilations,
+                tracks.len()
+            ),
+        ));
+    }
+    // The artist directory `normalize` uses
+    if compilations == 0 {
+        let artists = distinct(tracks, |track| {
+            Some(
+                track
+                    .album_artist
+                    .clone()
+                    .filter(|artist| !artist.trim().is_empty())
+                    .unwrap_or_else(|| track.artist.clone()),
+            )
+        });
+        if artists.len() > 1 {
+            issues.push(issue(
+                IssueKind::InconsistentAlbumArtist,
+                format!("album artists differ: {}", quoted(&artists)),
+            ));
+        }
+    }
+    let titles = distinct(tracks, |track| Some(track.album.clone()));
+    if titles.len() > 1 {
+        issues.push(issue(
+            IssueKind::InconsistentAlbum,
+            format!("album titles differ: {}", quoted(&titles)),
+        ));
+    }
+    let years = distinct(tracks, |track| track.year);
+    if years.len() > 1 {
+        issues.push(issue(
+            IssueKind::InconsistentYear,
+            format!(
+                "years differ: {}",
+                years
+                    .iter()
+                    .map(u16::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        ));
+    }
+
+    let mut discs = BTreeMap::<u32, Vec<&Track>>::new();
+    for (_, track) in tracks {
+        discs
+            .entry(track.disk_number.unwrap_or(1))
+            .or_default()
+            .push(track);
+    }
+    let multi_disc = discs.len() > 1;
+    for (disc, disc_tracks) in discs {
+        let on_disc = if multi_disc {
+            format!(" on disc {}", disc)
+        } else {
+            String::new()
+        };
+        let mut counts = BTreeMap::<u32, usize>::new();
+        for number in disc_tracks.iter().filter_map(|track| track.track_number) {
+            *counts.entry(number).or_default() += 1;
+        }
+        let duplicates = counts
+            .iter()
+            .filter(|(_, count)| **count > 1)
+            .map(|(number, _)| number.to_string())
+            .collect::<Vec<_>>();
+        if !duplicates.is_empty() {
+            issues.push(issue(
+                IssueKind::DuplicateTrackNumber,
+                format!(
+                    "duplicate track numbers{}: {}",
+                    on_disc,
+                    duplicates.join(", ")
+                ),
+            ));
+        }
+        let highest = counts.keys().next_back().copied().unwrap_or(0);
+        let gaps = (1..highest)
+            .filter(|number| !counts.contains_key(number))
+            .map(|number| number.to_string())
+            .collect::<Vec<_>>();
+        if !gaps.is_empty() {
+            issues.push(issue(
+                IssueKind::TrackNumberGap,
+                format!("missing track numbers{}: {}", on_disc, gaps.join(", ")),
+            ));
+        }
+        let totals = disc_tracks
+            .iter()
+            .filter_map(|track| track.track_number_total)
+            .collect::<BTreeSet<_>>();
+        let message = match totals.iter().collect::<Vec<_>>().as_slice() {
+            [] => None,
+            [total] if **total as usize == disc_tracks.len() && **total >= highest => None,
+            [total] => Some(format!(
+                "track total is {} but there are {} tracks{}",
+                total,
+                disc_tracks.len(),
+                on_disc
+            )),
+            totals => Some(format!(
+                "track totals differ{}: {}",
+                on_disc,
+                totals
+                    .iter()
+                    .map(|total| total.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        };
+        if let Some(message) = message {
+            issues.push(issue(IssueKind::TrackTotalMismatch, message));
+        }
+    }
+    issues
+}
+
+/// Checks the tags of tracks, keyed by path relative to the library root,
+/// and returns the problems found, ordered by album.
+pub fn lint_tracks(tracks: &BTreeMap<String, Track>) -> Vec<Issue> {
+    let mut albums = BTreeMap::<String, Vec<(&String, &Track)>>::new();
+    for (path, track) in tracks {
+        albums
+            .entry(album_key(path))
+            .or_default()
+            .push((path, track));
+    }
+    let mut issues = Vec::new();
+    for (album, album_tracks) in &albums {
+        issues.extend(lint_album(album, album_tracks));
+        for (path, track) in album_tracks {
+            issues.extend(lint_track(album, path, track));
+        }
+    }
+    issues
+}
+
+fn print_issues(issues: &[Issue]) {
+    let mut current = None;
+    for issue in issues {
+        if current != Some(&issue.album) {
+            current = Some(&issue.album);
+            let album = if issue.album.is_empty() {
+                "."
+            } else {
+                issue.album.as_str()
+            };
+            println!("{}", album.bright_white().bold());
+        }
+        match &issue.path {
+            Some(path) => {
+                let name = Path::new(path)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| path.clone());
+                println!("  {} {}: {}", "⚠️".bright_yellow(), name, issue.message);
+            }
+            None => println!("  {} {}", "⚠️".bright_yellow(), issue.message),
+        }
+    }
+}
+
+/// Reads the tags of the audio files below `directory`, prints the metadata
+/// problems found and returns how many there are.
+pub fn lint(directory: &str, output: LintOutput) -> usize {
+    configure_thread_pool();
+    let root = canonicalize_path(directory);
+    let pattern = glob_pattern(&root, LINT_EXTENSIONS);
+    let files = globwalk::glob(&pattern)
+        .expect("glob error")
+        .filter_map(Result::ok)
+        .map(|entry| entry.into_path())
+        .collect::<Vec<_>>();
+
+    let results = files
+        .par_iter()
+        .filter_map(|path| {
+            let relative = relative_path_string(&root, path).ok()?;
+            let track = extract_metadata(path, FieldSet::Minimal).map_err(|e| e.to_string());
+            Some((relative, track))
+        })
+        .collect::<Vec<_>>();
+    let mut tracks = BTreeMap::new();
+    let mut issues = Vec::new();
+    for (relative, track) in results {
+        match track {
+            Ok(track) => {
+                tracks.insert(relative, track);
+            }
+            Err(e) => issues.push(Issue {
+                album: album_key(&relative),
+                path: Some(relative),
+                kind: IssueKind::Unreadable,
+                message: format!("unreadable: {}", e),
+            }),
+        }
+    }
+    issues.extend(lint_tracks(&tracks));
+    issues.sort_by(|a, b| a.album.cmp(&b.album).then(a.path.cmp(&b.path)));
+
+    match output {
+        LintOutput::Json => {
+            for issue in &issues {
+                match serde_json::to_string(issue) {
+                    Ok(json) => println!("{}", json),
+                    Err(e) => eprintln!("{} Error serializing problem: {}", "✗".red().bold(), e),
+                }
+            }
+        }
+        LintOutput::Table => {
+            print_issues(&issues);
+            let albums = issues
+                .iter()
+                .map(|issue| &issue.album)
+                .collect::<BTreeSet<_>>();
+            if issues.is_empty() {
+                println!(
+                    "{} Checked {} files, no problems found",
+                    "✓".bright_green().bold(),
+                    files.len().to_string().bright_yellow().bold()
+                );
+            } else {
+                println!(
+                    "{} Checked {} files, {} problems in {} albums",
+                    "✗".red().bold(),
+                    files.len().to_string().bright_yellow().bold(),
+                    issues.len().to_string().bright_red().bold(),
+                    albums.len().to_string().bright_yellow().bold()
+                );
+            }
+        }
+    }
+    issues.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{lint_tracks, IssueKind};
+    use crate::schema::Track;
+
+    fn track(number: Option<u32>, total: Option<u32>) -> Track {
+        Track {
+            artist: "Artist".to_owned(),
+            album_artist: Some("Artist".to_owned()),
+            album: "Album".to_owned(),
+            title: Some("Song".to_owned()),
+            year: Some(1999),
+            track_number: number,
+            track_number_total: total,
+            ..Default::default()
+        }
+    }
+
+    fn kinds(tracks: &BTreeMap<String, Track>) -> Vec<(Option<String>, IssueKind)> {
+        lint_tracks(tracks)
+            .into_iter()
+            .map(|issue| (issue.path, issue.kind))
+            .collect()
+    }
+
+    #[test]
+    fn clean_album_has_no_issues() {
+        let tracks = (1..=3)
+            .map(|number| (format!("A/B/{}.flac", number), track(Some(number), Some(3))))
+            .collect::<BTreeMap<_, _>>();
+
+        assert!(lint_tracks(&tracks).is_empty());
+    }
+
+    #[test]
+    fn reports_file_and_album_problems() {
+        let mut tracks = BTreeMap::new();
+        tracks.insert("A/B/1.flac".to_owned(), track(Some(1), Some(4)));
+        tracks.insert("A/B/1b.flac".to_owned(), track(Some(1), Some(4)));
+        tracks.insert(
+            "A/B/4.flac".to_owned(),
+            Track {
+                album_artist: None,
+                title: Some(" Song".to_owned()),
+                year: Some(2001),
+                compilation: true,
+                ..track(Some(4), Some(4))
+            },
+        );
+        tracks.insert(
+            "A/B/x.flac".to_owned(),
+            Track {
+                album_artist: None,
+                title: None,
+                ..track(None, None)
+            },
+        );
+
+        let issues = kinds(&tracks);
+        for expected in [
+            (None, IssueKind::MixedCompilation),
+            (None, IssueKind::InconsistentYear),
+            (None, IssueKind::DuplicateTrackNumber),
+            (None, IssueKind::TrackNumberGap),
+            (Some("A/B/4.flac".to_owned()), IssueKind::StrayWhitespace),
+            (Some("A/B/x.flac".to_owned()), IssueKind::MissingAlbumArtist),
+            (Some("A/B/x.flac".to_owned()), IssueKind::MissingTitle),
+            (Some("A/B/x.flac".to_owned()), IssueKind::MissingTrackNumber),
+        ] {
+            assert!(issues.contains(&expected), "missing {:?}", expected);
+        }
+        // Compilations have no album artist by design
+        assert!(!issues.contains(&(Some("A/B/4.flac".to_owned()), IssueKind::MissingAlbumArtist)));
+        // Four tracks and a total of four, but numbered 1, 1 and 4
+        assert!(!issues.contains(&(None, IssueKind::TrackTotalMismatch)));
+    }
+
+    #[test]
+    fn reports_track_total_mismatch_per_disc() {
+        let mut tracks = BTreeMap::new();
+        for number in 1..=2 {
+            tracks.insert(
+                format!("A/B/1-{}.flac", number),
+                Track {
+                    disk_number: Some(1),
+                    ..track(Some(number), Some(3))
+                },
+            );
+            tracks.insert(
+                format!("A/B/2-{}.flac", number),
+                Track {
+                    disk_number: Some(2),
+                    ..track(Some(number), Some(2))
+                },
+            );
+        }
+
+        let issues = lint_tracks(&tracks);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].kind, IssueKind::TrackTotalMismatch);
+        assert_eq!(
+            issues[0].message,
+            "track total is 3 but there are 2 tracks on disc 1"
+        );
+    }
+}
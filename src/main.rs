@@ -1,15 +1,21 @@
 use clap::{Args, Parser, Subcommand, ValueEnum};
-use transcode::{AacBitrateMode, TranscodeFormat};
-
-mod cli;
-mod error;
-mod fs_utils;
-mod index;
-mod normalize;
-mod prune;
-mod tag;
-mod text;
-mod transcode;
+use loot::dupes::{DupesOptions, DupesOutput};
+use loot::index::{IndexFormat, IndexOptions};
+use loot::lint::LintOutput;
+use loot::normalize::NormalizeOptions;
+use loot::playlist::{PlaylistFormat, PlaylistOptions, SmartPlaylist};
+use loot::query::{QueryOutput, SortKey};
+use loot::replaygain::ReplayGainOptions;
+use loot::schema::FieldSet;
+use loot::tag_edit::{Edit, TagEditOptions};
+use loot::text::{self, ArticleMode, FsProfile, TidyPolicy};
+use loot::transcode::{AacBitrateMode, GainMode, TranscodeFormat};
+use loot::verify::VerifyOptions;
+use loot::{
+    cli, dupes, fingerprint, index, lint, normalize, playlist, prune, query, replaygain, tag_edit,
+    transcode, verify, watch,
+};
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -27,6 +33,12 @@ enum Commands {
     Prune(PruneArgs),
     /// Create a JSON index of audio files with metadata
     Index(IndexArgs),
+    /// Add acoustic fingerprints to the index, or print one file's fingerprint
+    Fingerprint(IndexArgs),
+    /// Search the index of a library
+    Query(QueryArgs),
+    /// Write a playlist of the index tracks matching a query
+    Playlist(PlaylistArgs),
     /// Transcode audio files to AAC format
     TranscodeAac(TranscodeAacArgs),
     /// Transcode audio files to AAC format at 256kbps
@@ -35,15 +47,113 @@ enum Commands {
     TranscodeMp3(TranscodeArgs),
     /// Transcode audio files to Opus format
     TranscodeOpus(TranscodeOpusArgs),
+    /// Find likely duplicate albums and tracks in the index
+    Dupes(DupesArgs),
+    /// Decode every audio file and report corruption
+    Verify(VerifyArgs),
+    /// Report missing and inconsistent tags before normalizing
+    Lint(LintArgs),
+    /// Set, remove, rename, copy or rewrite tag fields across many files
+    Tag(TagArgs),
+    /// Analyze loudness and write ReplayGain tags (R128 tags for Opus)
+    Replaygain(ReplaygainArgs),
+    /// Keep the index and transcoded files up to date as sources change
+    Watch(WatchArgs),
 }
 
 #[derive(Args)]
 struct NormArgs {
     #[arg(short, long)]
     dry_run: bool,
+    /// Keep non-ASCII characters (NFC-normalized) instead of transliterating them
+    #[arg(long)]
+    keep_unicode: bool,
+    /// Keep the case of tag values instead of lowercasing them
+    #[arg(long)]
+    keep_case: bool,
+    /// Characters to remove from names
+    #[arg(long, value_name = "CHARS", default_value = text::DEFAULT_REMOVE)]
+    remove: String,
+    /// Characters to replace with the separator; runs become one separator
+    #[arg(long, value_name = "CHARS", default_value = text::DEFAULT_REPLACE)]
+    replace: String,
+    #[arg(long, default_value_t = '_')]
+    separator: char,
+    /// Filesystem the names must be valid on
+    #[arg(long = "fs", value_enum, default_value = "ext4")]
+    filesystem: FsCliProfile,
+    /// Maximum length of each path component
+    #[arg(long, default_value_t = 255, value_name = "N")]
+    max_length: usize,
+    /// Leading articles of artist names, such as `the,le,la,les,l'`
+    #[arg(
+        long,
+        value_name = "WORDS",
+        value_delimiter = ',',
+        default_value = "the"
+    )]
+    articles: Vec<String>,
+    /// Move articles to the end (`beatles_the`) instead of dropping them
+    #[arg(long)]
+    move_articles: bool,
+    /// Put the discs of multi-disc albums in `Disc N - Subtitle` directories
+    #[arg(long)]
+    disc_dirs: bool,
+    /// Genres of albums laid out as composer/work/performer_year/movement
+    #[arg(
+        long,
+        value_name = "GENRES",
+        value_delimiter = ',',
+        default_value = "classical"
+    )]
+    classical_genres: Vec<String>,
     path: String,
 }
 
+impl From<&NormArgs> for NormalizeOptions {
+    fn from(args: &NormArgs) -> Self {
+        NormalizeOptions {
+            dry_run: args.dry_run,
+            disc_dirs: args.disc_dirs,
+            classical_genres: args.classical_genres.clone(),
+            policy: TidyPolicy {
+                transliterate: !args.keep_unicode,
+                lowercase: !args.keep_case,
+                remove: args.remove.clone(),
+                replace: args.replace.clone(),
+                separator: args.separator,
+                filesystem: args.filesystem.into(),
+                max_length: args.max_length,
+                articles: args.articles.clone(),
+                article_mode: if args.move_articles {
+                    ArticleMode::Move
+                } else {
+                    ArticleMode::Drop
+                },
+            },
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+enum FsCliProfile {
+    Ext4,
+    Fat32,
+    Exfat,
+    Ntfs,
+}
+
+impl From<FsCliProfile> for FsProfile {
+    fn from(profile: FsCliProfile) -> Self {
+        match profile {
+            FsCliProfile::Ext4 => FsProfile::Ext4,
+            FsCliProfile::Fat32 => FsProfile::Fat32,
+            FsCliProfile::Exfat => FsProfile::Exfat,
+            FsCliProfile::Ntfs => FsProfile::Ntfs,
+        }
+    }
+}
+
 #[derive(Args)]
 struct PruneArgs {
     #[arg(short, long)]
@@ -55,9 +165,49 @@ struct PruneArgs {
 struct TranscodeArgs {
     #[arg(short, long)]
     dry_run: bool,
+    /// Bake track or album gain into the output for players that ignore gain tags
+    #[arg(short, long, value_enum, default_value = "off")]
+    normalize: GainCliMode,
+    /// Make names valid on the destination's filesystem, recording them for later runs
+    #[arg(long, value_enum, value_name = "FS")]
+    dest_fs: Option<FsCliProfile>,
+    /// Separator replacing the characters the destination's filesystem forbids
+    #[arg(long, default_value_t = '_', value_name = "CHAR")]
+    dest_separator: char,
+    /// Maximum length of each destination path component
+    #[arg(long, default_value_t = 255, value_name = "N")]
+    dest_max_length: usize,
     paths: Vec<String>,
 }
 
+impl TranscodeArgs {
+    fn dest_policy(&self) -> Option<TidyPolicy> {
+        self.dest_fs.map(|filesystem| TidyPolicy {
+            filesystem: filesystem.into(),
+            separator: self.dest_separator,
+            max_length: self.dest_max_length,
+            ..Default::default()
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+enum GainCliMode {
+    Off,
+    Track,
+    Album,
+}
+
+impl From<GainCliMode> for GainMode {
+    fn from(mode: GainCliMode) -> Self {
+        match mode {
+            GainCliMode::Off => GainMode::Off,
+            GainCliMode::Track => GainMode::Track,
+            GainCliMode::Album => GainMode::Album,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
 enum AacCliBitrateMode {
     Vbr,
@@ -91,15 +241,335 @@ struct TranscodeOpusArgs {
     bitrate: u32,
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+enum IndexCliFieldSet {
+    Minimal,
+    Full,
+}
+
+impl From<IndexCliFieldSet> for FieldSet {
+    fn from(fields: IndexCliFieldSet) -> Self {
+        match fields {
+            IndexCliFieldSet::Minimal => FieldSet::Minimal,
+            IndexCliFieldSet::Full => FieldSet::Full,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+enum IndexCliFormat {
+    Json,
+    Sqlite,
+}
+
+impl From<IndexCliFormat> for IndexFormat {
+    fn from(format: IndexCliFormat) -> Self {
+        match format {
+            IndexCliFormat::Json => IndexFormat::Json,
+            IndexCliFormat::Sqlite => IndexFormat::Sqlite,
+        }
+    }
+}
+
 #[derive(Args)]
 struct IndexArgs {
     #[arg(short, long)]
     dry_run: bool,
     #[arg(short, long)]
     force: bool,
+    #[arg(long, value_enum, default_value = "minimal")]
+    fields: IndexCliFieldSet,
+    #[arg(long, value_enum, default_value = "json")]
+    format: IndexCliFormat,
+    /// Also treat inode or change-time changes as modifications
+    #[arg(long)]
+    check_ctime: bool,
+    /// List the files whose metadata could not be read
+    #[arg(long)]
+    report_errors: bool,
     path: String,
 }
 
+impl From<&IndexArgs> for IndexOptions {
+    fn from(args: &IndexArgs) -> Self {
+        IndexOptions {
+            dry_run: args.dry_run,
+            force: args.force,
+            fields: args.fields.into(),
+            format: args.format.into(),
+            check_ctime: args.check_ctime,
+            report_errors: args.report_errors,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+enum QueryCliOutput {
+    Table,
+    Json,
+    Paths,
+}
+
+impl From<QueryCliOutput> for QueryOutput {
+    fn from(output: QueryCliOutput) -> Self {
+        match output {
+            QueryCliOutput::Table => QueryOutput::Table,
+            QueryCliOutput::Json => QueryOutput::Json,
+            QueryCliOutput::Paths => QueryOutput::Paths,
+        }
+    }
+}
+
+#[derive(Args)]
+struct QueryArgs {
+    /// Comma-separated fields to sort by; prefix a field with `-` for descending order
+    #[arg(short, long, value_name = "FIELDS", allow_hyphen_values = true)]
+    sort: Option<String>,
+    #[arg(short, long)]
+    limit: Option<usize>,
+    #[arg(short, long, value_enum, default_value = "table")]
+    output: QueryCliOutput,
+    library: String,
+    /// Filter expression, e.g. `artist~"radiohead" and year>=2000 and codec=flac`
+    expr: String,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+enum PlaylistCliFormat {
+    M3u8,
+    Xspf,
+    Pls,
+}
+
+impl From<PlaylistCliFormat> for PlaylistFormat {
+    fn from(format: PlaylistCliFormat) -> Self {
+        match format {
+            PlaylistCliFormat::M3u8 => PlaylistFormat::M3u8,
+            PlaylistCliFormat::Xspf => PlaylistFormat::Xspf,
+            PlaylistCliFormat::Pls => PlaylistFormat::Pls,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+enum PlaylistCliTranscode {
+    Aac,
+    Mp3,
+    Opus,
+}
+
+impl From<PlaylistCliTranscode> for TranscodeFormat {
+    fn from(format: PlaylistCliTranscode) -> Self {
+        // Only the extension matters when mapping paths
+        match format {
+            PlaylistCliTranscode::Aac => TranscodeFormat::Aac {
+                mode: AacBitrateMode::Vbr,
+                bitrate_kbps: 128,
+            },
+            PlaylistCliTranscode::Mp3 => TranscodeFormat::Mp3,
+            PlaylistCliTranscode::Opus => TranscodeFormat::Opus { bitrate_kbps: 128 },
+        }
+    }
+}
+
+#[derive(Args)]
+struct PlaylistArgs {
+    /// Smart-playlist definition file (JSON with `name`, `query`, `sort` and `limit`)
+    #[arg(long, value_name = "FILE", conflicts_with = "expr")]
+    from: Option<PathBuf>,
+    #[arg(long)]
+    name: Option<String>,
+    /// Comma-separated fields to sort by; prefix a field with `-` for descending order
+    #[arg(short, long, value_name = "FIELDS", allow_hyphen_values = true)]
+    sort: Option<String>,
+    #[arg(short, long)]
+    limit: Option<usize>,
+    /// Playlist format; guessed from the output file extension when omitted
+    #[arg(short, long, value_enum)]
+    format: Option<PlaylistCliFormat>,
+    /// Write entries relative to this directory instead of as absolute paths
+    #[arg(long, value_name = "DIR")]
+    root: Option<PathBuf>,
+    /// Point entries at the transcoded files in this destination tree
+    #[arg(long, value_name = "DIR", requires = "transcode")]
+    dest: Option<PathBuf>,
+    /// Format the destination tree was transcoded to
+    #[arg(long, value_enum, requires = "dest")]
+    transcode: Option<PlaylistCliTranscode>,
+    /// Playlist file to write; prints to standard output when omitted
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+    library: String,
+    /// Filter expression in the `query` language
+    expr: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+enum DupesCliOutput {
+    Table,
+    Json,
+}
+
+impl From<DupesCliOutput> for DupesOutput {
+    fn from(output: DupesCliOutput) -> Self {
+        match output {
+            DupesCliOutput::Table => DupesOutput::Table,
+            DupesCliOutput::Json => DupesOutput::Json,
+        }
+    }
+}
+
+#[derive(Args)]
+struct DupesArgs {
+    /// Maximum difference in duration per track between copies
+    #[arg(short, long, default_value_t = 2, value_name = "SECS")]
+    tolerance: u64,
+    #[arg(short, long, value_enum, default_value = "table")]
+    output: DupesCliOutput,
+    library: String,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    #[arg(short, long)]
+    dry_run: bool,
+    /// Compare file checksums with the index and record missing ones
+    #[arg(long)]
+    checksums: bool,
+    path: String,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+enum LintCliOutput {
+    Table,
+    Json,
+}
+
+impl From<LintCliOutput> for LintOutput {
+    fn from(output: LintCliOutput) -> Self {
+        match output {
+            LintCliOutput::Table => LintOutput::Table,
+            LintCliOutput::Json => LintOutput::Json,
+        }
+    }
+}
+
+#[derive(Args)]
+struct LintArgs {
+    #[arg(short, long, value_enum, default_value = "table")]
+    output: LintCliOutput,
+    path: String,
+}
+
+#[derive(Args)]
+struct TagArgs {
+    /// Print the changes without writing them
+    #[arg(short, long)]
+    dry_run: bool,
+    /// Only edit files matching this glob, relative to the directory
+    #[arg(short, long, value_name = "PATTERN")]
+    glob: Option<String>,
+    /// Only edit files matching this query against the directory's index
+    #[arg(short, long, value_name = "EXPR")]
+    query: Option<String>,
+    /// Copy the values of one field to another
+    #[arg(long, value_name = "FROM=TO")]
+    copy: Vec<String>,
+    /// Move the values of one field to another
+    #[arg(long, value_name = "OLD=NEW")]
+    rename: Vec<String>,
+    /// Replace regex matches in the values of a field ($1 refers to groups)
+    #[arg(long, num_args = 3, value_names = ["FIELD", "REGEX", "REPLACEMENT"])]
+    replace: Vec<String>,
+    /// Set a field
+    #[arg(long, value_name = "FIELD=VALUE")]
+    set: Vec<String>,
+    /// Remove a field
+    #[arg(long, value_name = "FIELD")]
+    remove: Vec<String>,
+    /// Directory or file to edit
+    path: String,
+}
+
+impl TagArgs {
+    /// Builds the edits, applied as copy, rename, replace, set, then remove.
+    fn edits(&self) -> Result<Vec<Edit>, String> {
+        let mut edits = Vec::new();
+        for assignment in &self.copy {
+            edits.push(Edit::copy(assignment)?);
+        }
+        for assignment in &self.rename {
+            edits.push(Edit::rename(assignment)?);
+        }
+        for replace in self.replace.chunks(3) {
+            edits.push(Edit::replace(&replace[0], &replace[1], &replace[2])?);
+        }
+        for assignment in &self.set {
+            edits.push(Edit::set(assignment)?);
+        }
+        for field in &self.remove {
+            edits.push(Edit::remove(field)?);
+        }
+        Ok(edits)
+    }
+}
+
+#[derive(Args)]
+struct ReplaygainArgs {
+    #[arg(short, long)]
+    dry_run: bool,
+    /// Analyze albums whose files already have gain tags
+    #[arg(short, long)]
+    force: bool,
+    path: String,
+}
+
+#[derive(Args)]
+struct WatchArgs {
+    #[arg(short, long)]
+    dry_run: bool,
+    /// Watch configuration file (JSON with `sources`, `index`, `transcode` and `debounceMs`)
+    config: PathBuf,
+}
+
+fn run_playlist(args: &PlaylistArgs) {
+    let mut definition = match &args.from {
+        Some(path) => match SmartPlaylist::load(path) {
+            Ok(definition) => definition,
+            Err(e) => {
+                eprintln!("Error reading {}: {}", path.display(), e);
+                return;
+            }
+        },
+        None => SmartPlaylist {
+            query: args.expr.clone().unwrap_or_default(),
+            ..Default::default()
+        },
+    };
+    if args.name.is_some() {
+        definition.name = args.name.clone();
+    }
+    if args.sort.is_some() {
+        definition.sort = args.sort.clone();
+    }
+    if args.limit.is_some() {
+        definition.limit = args.limit;
+    }
+
+    let format = args
+        .format
+        .map(PlaylistFormat::from)
+        .or_else(|| args.output.as_deref().and_then(PlaylistFormat::from_path))
+        .unwrap_or_default();
+    let options = PlaylistOptions {
+        format,
+        root: args.root.clone(),
+        transcoded: args.dest.clone().zip(args.transcode.map(Into::into)),
+        output: args.output.clone(),
+    };
+    playlist::playlist(&args.library, &definition, &options);
+}
+
 fn run_prune(paths: &[String], dry_run: bool) {
     if let Some((sources, dest)) = cli::split_sources_and_dest(paths) {
         prune::prune(sources, dest, dry_run);
@@ -111,7 +581,14 @@ fn run_prune(paths: &[String], dry_run: bool) {
 fn transcode(args: &TranscodeArgs, format: TranscodeFormat) {
     if let Some((sources, dest)) = cli::split_sources_and_dest(&args.paths) {
         prune::prune(sources, dest, args.dry_run);
-        transcode::transcode(sources, dest, args.dry_run, format)
+        transcode::transcode(
+            sources,
+            dest,
+            args.dry_run,
+            format,
+            args.normalize.into(),
+            args.dest_policy().as_ref(),
+        )
     } else {
         eprintln!("At least two paths required.");
     }
@@ -122,13 +599,33 @@ fn main() {
 
     match &cli.command {
         Commands::Norm(args) => {
-            normalize::normalize(&args.path, args.dry_run);
+            normalize::normalize(&args.path, &args.into());
         }
         Commands::Prune(args) => {
             run_prune(&args.paths, args.dry_run);
         }
         Commands::Index(args) => {
-            index::index_directory(&args.path, args.dry_run, args.force);
+            index::index_directory(&args.path, args.into());
+        }
+        Commands::Fingerprint(args) => {
+            fingerprint::fingerprint(&args.path, args.into());
+        }
+        Commands::Query(args) => {
+            let sort = args
+                .sort
+                .as_deref()
+                .map(SortKey::parse_list)
+                .unwrap_or_default();
+            query::query(
+                &args.library,
+                &args.expr,
+                &sort,
+                args.limit,
+                args.output.into(),
+            );
+        }
+        Commands::Playlist(args) => {
+            run_playlist(args);
         }
         Commands::TranscodeAac(args) => {
             transcode(
@@ -159,6 +656,59 @@ fn main() {
                 },
             );
         }
+        Commands::Dupes(args) => {
+            dupes::dupes(
+                &args.library,
+                DupesOptions {
+                    tolerance_secs: args.tolerance,
+                    output: args.output.into(),
+                },
+            );
+        }
+        Commands::Verify(args) => {
+            verify::verify(
+                &args.path,
+                VerifyOptions {
+                    checksums: args.checksums,
+                    dry_run: args.dry_run,
+                },
+            );
+        }
+        Commands::Lint(args) => {
+            // A failing exit status lets CI reject a library with problems
+            if lint::lint(&args.path, args.output.into()) > 0 {
+                std::process::exit(1);
+            }
+        }
+        Commands::Tag(args) => match args.edits() {
+            Ok(edits) if edits.is_empty() => {
+                eprintln!(
+                    "At least one of --set, --remove, --rename, --replace or --copy required."
+                );
+            }
+            Ok(edits) => tag_edit::tag(
+                &args.path,
+                &edits,
+                &TagEditOptions {
+                    glob: args.glob.clone(),
+                    query: args.query.clone(),
+                    dry_run: args.dry_run,
+                },
+            ),
+            Err(e) => eprintln!("Invalid edit: {}", e),
+        },
+        Commands::Replaygain(args) => {
+            replaygain::replaygain(
+                &args.path,
+                ReplayGainOptions {
+                    dry_run: args.dry_run,
+                    force: args.force,
+                },
+            );
+        }
+        Commands::Watch(args) => {
+            watch::watch(&args.config, args.dry_run);
+        }
     }
 }
 
@@ -199,8 +749,175 @@ mod tests {
             Commands::TranscodeAac(args) => {
                 assert_eq!(args.bitrate, 128);
                 assert_eq!(args.mode, AacCliBitrateMode::Vbr);
+                assert_eq!(args.shared.normalize, GainCliMode::Off);
             }
             _ => panic!("expected transcode-aac command"),
         }
     }
+
+    #[test]
+    fn tag_collects_edits_in_application_order() {
+        let cli = Cli::try_parse_from([
+            "loot",
+            "tag",
+            "--set",
+            "genre=Jazz",
+            "--replace",
+            "album",
+            r" \(Deluxe\)$",
+            "",
+            "--copy",
+            "artist=albumartist",
+            "--glob",
+            "Miles*/**/*.flac",
+            "lib",
+        ])
+        .expect("expected tag args to parse");
+
+        match cli.command {
+            Commands::Tag(args) => {
+                assert_eq!(args.glob.as_deref(), Some("Miles*/**/*.flac"));
+                let edits = args.edits().expect("edits");
+                assert!(matches!(edits[0], Edit::Copy { .. }));
+                assert!(matches!(edits[1], Edit::Replace { .. }));
+                assert!(matches!(edits[2], Edit::Set { .. }));
+            }
+            _ => panic!("expected tag command"),
+        }
+    }
+
+    #[test]
+    fn norm_defaults_to_the_standard_policy() {
+        let cli =
+            Cli::try_parse_from(["loot", "norm", "lib"]).expect("expected norm args to parse");
+
+        match cli.command {
+            Commands::Norm(args) => {
+                let options = NormalizeOptions::from(&args);
+                assert_eq!(options.policy, TidyPolicy::default());
+                assert_eq!(
+                    options.classical_genres,
+                    NormalizeOptions::default().classical_genres
+                );
+            }
+            _ => panic!("expected norm command"),
+        }
+    }
+
+    #[test]
+    fn norm_accepts_policy_options() {
+        let cli = Cli::try_parse_from([
+            "loot",
+            "norm",
+            "--keep-unicode",
+            "--keep-case",
+            "--fs",
+            "fat32",
+            "--separator",
+            " ",
+            "--max-length",
+            "64",
+            "--articles",
+            "the,les,l'",
+            "--move-articles",
+            "lib",
+        ])
+        .expect("expected norm args to parse");
+
+        match cli.command {
+            Commands::Norm(args) => {
+                let policy = NormalizeOptions::from(&args).policy;
+                assert!(!policy.transliterate && !policy.lowercase);
+                assert_eq!(policy.filesystem, FsProfile::Fat32);
+                assert_eq!(policy.separator, ' ');
+                assert_eq!(policy.max_length, 64);
+                assert_eq!(policy.articles, vec!["the", "les", "l'"]);
+                assert_eq!(policy.article_mode, ArticleMode::Move);
+            }
+            _ => panic!("expected norm command"),
+        }
+    }
+
+    #[test]
+    fn transcode_accepts_normalize_mode() {
+        let cli = Cli::try_parse_from([
+            "loot",
+            "transcode-opus",
+            "-n",
+            "album",
+            "--dest-fs",
+            "fat32",
+            "--dest-separator",
+            " ",
+            "src",
+            "dest",
+        ])
+        .expect("expected transcode-opus args to parse");
+
+        match cli.command {
+            Commands::TranscodeOpus(args) => {
+                assert_eq!(args.shared.normalize, GainCliMode::Album);
+                assert_eq!(args.shared.dest_fs, Some(FsCliProfile::Fat32));
+                let policy = args.shared.dest_policy().expect("policy");
+                assert_eq!(policy.filesystem, FsProfile::Fat32);
+                assert_eq!(policy.separator, ' ');
+                assert_eq!(policy.max_length, 255);
+            }
+            _ => panic!("expected transcode-opus command"),
+        }
+    }
+
+    #[test]
+    fn query_accepts_sort_limit_and_output() {
+        let cli = Cli::try_parse_from([
+            "loot",
+            "query",
+            "--sort",
+            "-year",
+            "--limit",
+            "5",
+            "--output",
+            "paths",
+            "lib",
+            "codec=flac",
+        ])
+        .expect("expected query args to parse");
+
+        match cli.command {
+            Commands::Query(args) => {
+                assert_eq!(args.sort.as_deref(), Some("-year"));
+                assert_eq!(args.limit, Some(5));
+                assert_eq!(args.output, QueryCliOutput::Paths);
+                assert_eq!(args.expr, "codec=flac");
+            }
+            _ => panic!("expected query command"),
+        }
+    }
+
+    #[test]
+    fn playlist_requires_dest_and_transcode_together() {
+        assert!(Cli::try_parse_from(["loot", "playlist", "--dest", "opus", "lib", ""]).is_err());
+
+        let cli = Cli::try_parse_from([
+            "loot",
+            "playlist",
+            "--dest",
+            "opus",
+            "--transcode",
+            "opus",
+            "-o",
+            "mix.xspf",
+            "lib",
+            "genre=jazz",
+        ])
+        .expect("expected playlist args to parse");
+
+        match cli.command {
+            Commands::Playlist(args) => {
+                assert_eq!(args.transcode, Some(PlaylistCliTranscode::Opus));
+                assert_eq!(args.expr.as_deref(), Some("genre=jazz"));
+            }
+            _ => panic!("expected playlist command"),
+        }
+    }
 }
@@ -1,80 +1,889 @@
-use lofty::tag::{Accessor, ItemKey};
+use colored::*;
+use lofty::tag::ItemKey;
 use rayon::prelude::*;
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
 
+use crate::aggregate::most_common;
 use crate::error::{AppError, Result};
 use crate::fs_utils::{canonicalize_path, glob_pattern, relative_path_string};
+use crate::index;
+use crate::schema::Track;
 use crate::tag;
-use crate::text::{strip_leading_the, tidy_string};
+use crate::text::TidyPolicy;
 
-fn process_file(base: &Path, path: &Path, dry_run: bool) -> Result<()> {
+/// File marking a directory, and everything below it, as classical music.
+pub const CLASSICAL_MARKER: &str = ".loot-classical";
+
+/// Genres of albums laid out as classical music by default.
+pub const DEFAULT_CLASSICAL_GENRES: &[&str] = &["classical"];
+
+/// Options controlling a `norm` run.
+#[derive(Clone, Debug)]
+pub struct NormalizeOptions {
+    /// Print the renames without making them.
+    pub dry_run: bool,
+    /// How tag values become directory and file names.
+    pub policy: TidyPolicy,
+    /// Put the discs of multi-disc albums in `Disc N - Subtitle`
+    /// directories instead of prefixing file names with the disc number.
+    pub disc_dirs: bool,
+    /// Genres, matched in any case, of albums that use the classical
+    /// `composer/work/performer_year/NN_movement` layout.
+    pub classical_genres: Vec<String>,
+}
+
+impl Default for NormalizeOptions {
+    fn default() -> Self {
+        NormalizeOptions {
+            dry_run: false,
+            policy: TidyPolicy::default(),
+            disc_dirs: false,
+            classical_genres: DEFAULT_CLASSICAL_GENRES
+                .iter()
+                .map(|genre| (*genre).to_owned())
+                .collect(),
+        }
+    }
+}
+
+/// The tag values of one file that determine its normalized path.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct TrackTags {
+    path: PathBuf,
+    /// Album artist, or the track artist when there is none.
+    artist: String,
+    /// Sort name of the artist, from `ALBUMARTISTSORT` or `ARTISTSORT`.
+    artist_sort: Option<String>,
+    album: String,
+    compilation: bool,
+    disc: Option<u32>,
+    disc_total: Option<u32>,
+    disc_subtitle: Option<String>,
+    track: Option<u32>,
+    title: String,
+    genre: Option<String>,
+    year: Option<u16>,
+    composer: Option<String>,
+    work: Option<String>,
+    /// Movement name, from `MOVEMENTNAME`.
+    movement: Option<String>,
+    movement_number: Option<u32>,
+    /// Conductor, or the performer when there is none.
+    performer: Option<String>,
+}
+
+/// Values shared by every track of an album directory.
+#[derive(Clone, Debug, PartialEq)]
+struct AlbumValues {
+    /// Normalized artist directory name, or `None` when no name is shared by
+    /// most tracks, which then keep their own.
+    artist_dir: Option<String>,
+    /// Normalized album directory name, or `None` like `artist_dir`.
+    album_dir: Option<String>,
+    compilation: bool,
+    /// Digits of the zero-padded disc number, or `None` for single-disc
+    /// albums, whose tracks get no disc number.
+    disc_width: Option<usize>,
+    /// Subtitle of each disc, from `DISCSUBTITLE`.
+    disc_subtitles: BTreeMap<u32, String>,
+}
+
+/// Trims a tag value, dropping it when blank.
+fn present(value: Option<&str>) -> Option<String> {
+    value
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_owned)
+}
+
+/// Reads the values the index records, plus the sort names, disc subtitle,
+/// movement and conductor only `norm` needs.
+fn read_tags(path: &Path) -> Result<TrackTags> {
     let tag = tag::read(path, false)?;
+    let mut fields = Track::default();
+    index::extract_tag_fields(&tag, &mut fields);
+    let text = |key: ItemKey| present(tag.get_string(key));
+    let sort = if fields.album_artist.is_some() {
+        ItemKey::AlbumArtistSortOrder
+    } else {
+        ItemKey::TrackArtistSortOrder
+    };
+    Ok(TrackTags {
+        path: path.to_path_buf(),
+        artist_sort: tag.get_string(sort).map(str::to_owned),
+        disc_subtitle: text(ItemKey::SetSubtitle),
+        movement: text(ItemKey::Movement),
+        movement_number: tag
+            .get_string(ItemKey::MovementNumber)
+            .and_then(|number| number.trim().parse().ok()),
+        performer: text(ItemKey::Conductor).or_else(|| present(fields.performer.as_deref())),
+        composer: present(fields.composer.as_deref()),
+        work: present(fields.work.as_deref()),
+        artist: fields.album_artist.unwrap_or(fields.artist),
+        album: fields.album,
+        compilation: fields.compilation,
+        disc: fields.disk_number,
+        disc_total: fields.disk_number_total,
+        track: fields.track_number,
+        title: fields.title.unwrap_or_default(),
+        genre: fields.genre,
+        year: fields.year,
+    })
+}
+
+fn artist_dir(policy: &TidyPolicy, track: &TrackTags) -> String {
+    policy.component(&policy.artist(&track.artist, track.artist_sort.as_deref()))
+}
 
-    let extension = path
+fn album_dir(policy: &TidyPolicy, album: &str) -> String {
+    policy.component(&policy.tidy(album))
+}
+
+/// Returns the value shared by more than half of `values`.
+fn majority<T: Clone + Eq + Hash>(values: &[T]) -> Option<T> {
+    let value = most_common(values.iter())?;
+    let count = values.iter().filter(|seen| *seen == value).count();
+    (count * 2 > values.len()).then(|| value.clone())
+}
+
+/// Works out the album-level values of a directory's tracks by majority,
+/// so one mistagged track does not split the album. A folder of unrelated
+/// tracks, where no artist or album is shared by most of them, is not merged.
+fn album_values(policy: &TidyPolicy, tracks: &[TrackTags]) -> AlbumValues {
+    let compilation = most_common(tracks.iter().map(|track| track.compilation)).unwrap_or(false);
+    // Untagged tracks have no say, and follow the tracks that are tagged
+    let artists = tracks
+        .iter()
+        .map(|track| artist_dir(policy, track))
+        .filter(|dir| !dir.is_empty())
+        .collect::<Vec<_>>();
+    let albums = tracks
+        .iter()
+        .map(|track| album_dir(policy, &track.album))
+        .filter(|dir| !dir.is_empty())
+        .collect::<Vec<_>>();
+    // Tags like 1/1 are common on single-disc albums, so a disc number alone
+    // does not make an album multi-disc
+    let discs = tracks
+        .iter()
+        .filter_map(|track| track.disc)
+        .collect::<BTreeSet<_>>();
+    let total = most_common(tracks.iter().filter_map(|track| track.disc_total)).unwrap_or(0);
+    let highest = discs.iter().next_back().copied().unwrap_or(0).max(total);
+    let disc_width = (total > 1 || discs.len() > 1).then(|| highest.to_string().len());
+    let disc_subtitles = discs
+        .iter()
+        .filter_map(|disc| {
+            let subtitles = tracks
+                .iter()
+                .filter(|track| disc_of(track) == *disc)
+                .filter_map(|track| track.disc_subtitle.clone());
+            most_common(subtitles).map(|subtitle| (*disc, subtitle))
+        })
+        .collect();
+    AlbumValues {
+        artist_dir: if compilation {
+            Some(policy.component(&policy.tidy("various")))
+        } else {
+            majority(&artists)
+        },
+        album_dir: majority(&albums),
+        compilation,
+        disc_width,
+        disc_subtitles,
+    }
+}
+
+/// Describes how a track's own values differ from its album's.
+fn outliers(policy: &TidyPolicy, album: &AlbumValues, track: &TrackTags) -> Vec<String> {
+    let mut differences = Vec::new();
+    if track.compilation != album.compilation {
+        differences.push(format!(
+            "compilation flag {} differs from the album's",
+            if track.compilation { "set" } else { "unset" }
+        ));
+    }
+    match &album.artist_dir {
+        Some(dir) if !album.compilation && artist_dir(policy, track) != *dir => {
+            differences.push(format!(
+                "album artist \"{}\" differs from the album's ({})",
+                track.artist, dir
+            ))
+        }
+        _ => {}
+    }
+    match &album.album_dir {
+        Some(dir) if album_dir(policy, &track.album) != *dir => differences.push(format!(
+            "album \"{}\" differs from the album's ({})",
+            track.album, dir
+        )),
+        _ => {}
+    }
+    differences
+}
+
+fn extension(track: &TrackTags) -> Result<&str> {
+    track
+        .path
         .extension()
         .and_then(|e| e.to_str())
-        .ok_or(AppError::PathError)?;
-
-    let is_compilation = tag
-        .get_string(ItemKey::FlagCompilation)
-        .unwrap_or("")
-        .eq("1");
-    let album_artist = tag.get_string(ItemKey::AlbumArtist);
-    let tidy_artist = if is_compilation {
-        "various".to_string()
-    } else {
-        strip_leading_the(&tidy_string(
-            album_artist.unwrap_or(tag.artist().as_deref().unwrap_or("")),
-        ))
-    };
+        .ok_or(AppError::PathError)
+}
+
+/// Disc of a track of a multi-disc album, the first when it has no disc tag.
+fn disc_of(track: &TrackTags) -> u32 {
+    track.disc.unwrap_or(1)
+}
+
+/// Disc number of a track of a multi-disc album, zero-padded.
+fn disc_number(album: &AlbumValues, track: &TrackTags) -> Option<String> {
+    album
+        .disc_width
+        .map(|width| format!("{:0>width$}", disc_of(track), width = width))
+}
 
-    let nice_dir = base
-        .join(&tidy_artist)
-        .join(tidy_string(tag.album().as_deref().unwrap_or("")));
+/// Returns the normalized path of a track using its album's values.
+fn nice_path(
+    base: &Path,
+    options: &NormalizeOptions,
+    album: &AlbumValues,
+    track: &TrackTags,
+) -> Result<PathBuf> {
+    let policy = &options.policy;
+    let extension = extension(track)?;
 
-    let disc_prefix = tag
-        .disk()
-        .map(|t| format!("{}-", t))
-        .unwrap_or("".to_owned());
+    let disc = disc_number(album, track);
+    let mut dir = base
+        .join(
+            album
+                .artist_dir
+                .clone()
+                .unwrap_or_else(|| artist_dir(policy, track)),
+        )
+        .join(
+            album
+                .album_dir
+                .clone()
+                .unwrap_or_else(|| album_dir(policy, &track.album)),
+        );
+    let mut disc_prefix = String::new();
+    match disc {
+        Some(disc) if options.disc_dirs => {
+            let name = match album.disc_subtitles.get(&disc_of(track)) {
+                Some(subtitle) => format!("Disc {} - {}", disc, subtitle),
+                None => format!("Disc {}", disc),
+            };
+            dir.push(policy.component(&policy.tidy(&name)));
+        }
+        Some(disc) => disc_prefix = format!("{}-", disc),
+        None => {}
+    }
 
-    let nice_path = nice_dir.join(format!(
-        "{}{:0>2}_{}.{}",
+    let file_name = format!(
+        "{}{:0>2}{}{}.{}",
         disc_prefix,
-        tag.track().unwrap_or(1),
-        tidy_string(&tag.title().as_deref().unwrap_or("")),
+        track.track.unwrap_or(1),
+        policy.separator,
+        policy.tidy(&track.title),
         extension
-    ));
+    );
+    Ok(dir.join(policy.component(&file_name)))
+}
+
+/// Directories shared by the tracks of one work of a classical album.
+#[derive(Clone, Debug, PartialEq)]
+struct WorkValues {
+    composer_dir: String,
+    work_dir: String,
+    /// Performer and year of the recording, such as `karajan_1963`.
+    performance_dir: String,
+}
+
+fn work_dir(policy: &TidyPolicy, track: &TrackTags) -> String {
+    policy.component(&policy.tidy(track.work.as_deref().unwrap_or(&track.album)))
+}
+
+/// Works out the directories of each work of a classical album, keyed by
+/// work directory. Tracks without a work are grouped by album title.
+fn work_values(policy: &TidyPolicy, tracks: &[TrackTags]) -> BTreeMap<String, WorkValues> {
+    let mut works = BTreeMap::<String, Vec<&TrackTags>>::new();
+    for track in tracks {
+        works
+            .entry(work_dir(policy, track))
+            .or_default()
+            .push(track);
+    }
+    works
+        .into_iter()
+        .map(|(work_dir, tracks)| {
+            let composers = tracks
+                .iter()
+                .map(|track| track.composer.as_deref().unwrap_or(&track.artist))
+                .map(|composer| policy.component(&policy.tidy(composer)));
+            let performances = tracks.iter().map(|track| {
+                let performer = track.performer.as_deref().unwrap_or(&track.artist);
+                let performance = match track.year {
+                    Some(year) => format!("{} {}", performer, year),
+                    None => performer.to_owned(),
+                };
+                policy.component(&policy.tidy(&performance))
+            });
+            let values = WorkValues {
+                composer_dir: most_common(composers).unwrap_or_default(),
+                work_dir: work_dir.clone(),
+                performance_dir: most_common(performances).unwrap_or_default(),
+            };
+            (work_dir, values)
+        })
+        .collect()
+}
+
+/// Returns the classical path of a track,
+/// `composer/work/performer_year/NN_movement`, numbered by movement when the
+/// track has a movement number.
+fn classical_path(
+    base: &Path,
+    policy: &TidyPolicy,
+    album: &AlbumValues,
+    work: &WorkValues,
+    track: &TrackTags,
+) -> Result<PathBuf> {
+    let (disc_prefix, number) = match track.movement_number {
+        Some(number) => (String::new(), number),
+        None => (
+            disc_number(album, track)
+                .map(|disc| format!("{}-", disc))
+                .unwrap_or_default(),
+            track.track.unwrap_or(1),
+        ),
+    };
+    let file_name = format!(
+        "{}{:0>2}{}{}.{}",
+        disc_prefix,
+        number,
+        policy.separator,
+        policy.tidy(track.movement.as_deref().unwrap_or(&track.title)),
+        extension(track)?
+    );
+    Ok(base
+        .join(&work.composer_dir)
+        .join(&work.work_dir)
+        .join(&work.performance_dir)
+        .join(policy.component(&file_name)))
+}
+
+/// Returns whether the album in `dir` uses the classical layout: it has a
+/// classical genre, or it or a directory above it up to `base` holds the
+/// marker file.
+fn is_classical(base: &Path, dir: &Path, tracks: &[TrackTags], options: &NormalizeOptions) -> bool {
+    let genres = tracks
+        .iter()
+        .filter_map(|track| track.genre.as_deref())
+        .map(str::trim);
+    let genre = most_common(genres).unwrap_or_default();
+    options
+        .classical_genres
+        .iter()
+        .any(|classical| classical.eq_ignore_ascii_case(genre))
+        || dir
+            .ancestors()
+            .take_while(|ancestor| ancestor.starts_with(base))
+            .any(|ancestor| ancestor.join(CLASSICAL_MARKER).is_file())
+}
+
+/// Describes the renames that would overwrite a file: a target that already
+/// exists, one shared by several tracks, or one `claimed` by another album,
+/// as classical works gather tracks from several directories.
+fn collisions(
+    base: &Path,
+    renames: &[(&Path, PathBuf)],
+    claimed: &BTreeSet<PathBuf>,
+) -> Result<Vec<String>> {
+    let mut targets = BTreeMap::<&Path, Vec<&Path>>::new();
+    for (source, target) in renames {
+        targets.entry(target).or_default().push(source);
+    }
+    let mut collisions = Vec::new();
+    for (target, sources) in targets {
+        let target_name = relative_path_string(base, target)?;
+        if sources.len() > 1 {
+            let names = sources
+                .iter()
+                .map(|source| relative_path_string(base, source))
+                .collect::<Result<Vec<_>>>()?;
+            collisions.push(format!(
+                "{} would all be renamed to {}",
+                names.join(", "),
+                target_name
+            ));
+        } else if target.exists() {
+            collisions.push(format!(
+                "{} would overwrite {}",
+                relative_path_string(base, sources[0])?,
+                target_name
+            ));
+        } else if claimed.contains(target) {
+            collisions.push(format!(
+                "{} would be renamed to {}, like a track of another album",
+                relative_path_string(base, sources[0])?,
+                target_name
+            ));
+        }
+    }
+    Ok(collisions)
+}
 
-    if path != nice_path {
+/// Works out the renames of the tracks of the album in `dir`, reporting the
+/// tracks that disagree with it.
+fn plan_album<'a>(
+    base: &Path,
+    dir: &Path,
+    tracks: &'a [TrackTags],
+    options: &NormalizeOptions,
+) -> Result<Vec<(&'a Path, PathBuf)>> {
+    let policy = &options.policy;
+    let album = album_values(policy, tracks);
+    let works = is_classical(base, dir, tracks, options).then(|| work_values(policy, tracks));
+    if tracks.len() > 1 && (album.artist_dir.is_none() || album.album_dir.is_none()) {
+        eprintln!(
+            "{} {}: most tracks do not share an album, keeping each track's own",
+            "⚠️".bright_yellow(),
+            dir.to_string_lossy()
+        );
+    }
+    for track in tracks {
+        for difference in outliers(policy, &album, track) {
+            eprintln!(
+                "{} {}: {}",
+                "⚠️".bright_yellow(),
+                relative_path_string(base, &track.path)?,
+                difference
+            );
+        }
+    }
+
+    let mut renames = Vec::new();
+    for track in tracks {
+        let nice_path = match &works {
+            Some(works) => {
+                let work = &works[&work_dir(policy, track)];
+                classical_path(base, policy, &album, work, track)?
+            }
+            None => nice_path(base, options, &album, track)?,
+        };
+        if track.path != nice_path {
+            renames.push((track.path.as_path(), nice_path));
+        }
+    }
+    Ok(renames)
+}
+
+/// Renames the tracks of the album in `dir`, claiming their new paths.
+fn rename_album(
+    base: &Path,
+    dir: &Path,
+    renames: Vec<(&Path, PathBuf)>,
+    claimed: &mut BTreeSet<PathBuf>,
+    dry_run: bool,
+) -> Result<()> {
+    // Nothing is moved unless every rename is safe, so an album is never
+    // left half renamed
+    let collisions = collisions(base, &renames, claimed)?;
+    if !collisions.is_empty() {
+        for collision in collisions {
+            eprintln!(
+                "{} {}: {}, leaving the album unchanged",
+                "✗".red().bold(),
+                dir.to_string_lossy(),
+                collision
+            );
+        }
+        return Ok(());
+    }
+
+    for (source, target) in renames {
         println!(
             "{} -> {}",
-            relative_path_string(base, path)?,
-            relative_path_string(base, &nice_path)?
+            relative_path_string(base, source)?,
+            relative_path_string(base, &target)?
         );
         if !dry_run {
-            std::fs::create_dir_all(&nice_dir)?;
-            std::fs::rename(&path, &nice_path)?;
+            std::fs::create_dir_all(target.parent().ok_or(AppError::PathError)?)?;
+            std::fs::rename(source, &target)?;
         }
+        claimed.insert(target);
     }
 
-    return Ok(());
+    Ok(())
 }
 
-/// Renames supported audio files into a normalized artist/album/track layout.
-pub fn normalize(path: &str, dry_run: bool) {
+/// Returns whether a directory name looks like a disc of an album, such as
+/// `CD2` or `disc_1_live`.
+pub(crate) fn is_disc_dir(name: &str) -> bool {
+    let name = name
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .collect::<String>()
+        .to_lowercase();
+    ["disc", "disk", "cd"].iter().any(|prefix| {
+        name.strip_prefix(prefix)
+            .and_then(|rest| rest.chars().next())
+            .is_some_and(|c| c.is_ascii_digit())
+    })
+}
+
+/// Directory holding the album of the file at `path`: its parent, or the
+/// parent's parent when the parent is a disc directory.
+pub(crate) fn album_dir_of(path: &Path) -> Option<&Path> {
+    let parent = path.parent()?;
+    match parent.file_name() {
+        Some(name) if is_disc_dir(&name.to_string_lossy()) => parent.parent(),
+        _ => Some(parent),
+    }
+}
+
+/// Renames supported audio files into a normalized artist/album/track layout,
+/// using the values most tracks of each directory agree on.
+pub fn normalize(path: &str, options: &NormalizeOptions) {
     let canonical = canonicalize_path(path);
     println!("processing {}", canonical.to_string_lossy());
 
     let pattern = glob_pattern(&canonical, &["flac", "opus", "m4a", "mp3"]);
-    globwalk::glob(pattern)
+    let mut paths = globwalk::glob(pattern)
         .expect("Glob error.")
         .filter_map(|e| e.ok())
-        .into_iter()
-        .par_bridge()
-        .for_each(
-            |entry| match process_file(&canonical, entry.path(), dry_run) {
-                Ok(_) => (),
-                Err(_) => eprintln!("Error reading tag: {}", entry.path().to_string_lossy()),
+        .map(|entry| entry.into_path())
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let mut albums = BTreeMap::<PathBuf, Vec<TrackTags>>::new();
+    let read = paths
+        .par_iter()
+        .map(|path| (path, read_tags(path)))
+        .collect::<Vec<_>>();
+    for (path, tags) in read {
+        match tags {
+            Ok(tags) => albums
+                .entry(album_dir_of(path).unwrap_or(&canonical).to_path_buf())
+                .or_default()
+                .push(tags),
+            Err(_) => eprintln!("Error reading tag: {}", path.to_string_lossy()),
+        }
+    }
+
+    let plans = albums
+        .par_iter()
+        .map(|(dir, tracks)| (dir, plan_album(&canonical, dir, tracks, options)))
+        .collect::<Vec<_>>();
+    // Albums are renamed one at a time so none can overwrite another's tracks
+    let mut claimed = BTreeSet::new();
+    for (dir, plan) in plans {
+        let renamed = plan.and_then(|renames| {
+            rename_album(&canonical, dir, renames, &mut claimed, options.dry_run)
+        });
+        if renamed.is_err() {
+            eprintln!("Error normalizing: {}", dir.to_string_lossy());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::path::{Path, PathBuf};
+
+    use tempfile::tempdir;
+
+    use super::{
+        album_dir_of, album_values, classical_path, collisions, is_classical, majority, nice_path,
+        outliers, work_dir, work_values, NormalizeOptions, TrackTags, CLASSICAL_MARKER,
+    };
+    use crate::text::{FsProfile, TidyPolicy};
+
+    fn track(name: &str, artist: &str, album: &str, number: u32) -> TrackTags {
+        TrackTags {
+            path: PathBuf::from(format!("/lib/in/{}.flac", name)),
+            artist: artist.to_owned(),
+            album: album.to_owned(),
+            track: Some(number),
+            title: name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn majority_needs_more_than_half() {
+        assert_eq!(majority(&["a", "b", "b"]), Some("b"));
+        assert_eq!(majority(&["a", "b"]), None);
+    }
+
+    #[test]
+    fn mistagged_track_follows_its_album() {
+        let tracks = vec![
+            track("One", "The Beatles", "Abbey Road", 1),
+            track("Two", "Beatles", "Abbey Road", 2),
+            track("Three", "The Beatles", "Abbey Raod", 3),
+        ];
+
+        let options = NormalizeOptions::default();
+        let policy = &options.policy;
+        let album = album_values(policy, &tracks);
+
+        assert_eq!(album.artist_dir.as_deref(), Some("beatles"));
+        assert_eq!(album.album_dir.as_deref(), Some("abbey_road"));
+        assert!(outliers(policy, &album, &tracks[0]).is_empty());
+        assert!(outliers(policy, &album, &tracks[1]).is_empty());
+        assert_eq!(outliers(policy, &album, &tracks[2]).len(), 1);
+        assert_eq!(
+            nice_path(Path::new("/lib"), &options, &album, &tracks[2]).expect("path"),
+            PathBuf::from("/lib/beatles/abbey_road/03_three.flac")
+        );
+    }
+
+    #[test]
+    fn compilation_flag_follows_majority() {
+        let mut tracks = vec![
+            track("One", "Artist A", "Hits", 1),
+            track("Two", "Artist B", "Hits", 2),
+            track("Three", "Artist C", "Hits", 3),
+        ];
+        tracks[0].compilation = true;
+        tracks[1].compilation = true;
+
+        let policy = TidyPolicy::default();
+        let album = album_values(&policy, &tracks);
+
+        assert!(album.compilation);
+        assert_eq!(album.artist_dir.as_deref(), Some("various"));
+        assert_eq!(outliers(&policy, &album, &tracks[2]).len(), 1);
+    }
+
+    #[test]
+    fn mixed_folders_keep_each_tracks_own_album() {
+        let tracks = vec![
+            track("One", "Artist A", "First", 1),
+            track("Two", "Artist A", "First", 2),
+            track("Three", "Artist B", "Second", 1),
+            track("Four", "Artist C", "Third", 1),
+        ];
+
+        let options = NormalizeOptions::default();
+        let album = album_values(&options.policy, &tracks);
+
+        assert_eq!(album.artist_dir, None);
+        assert_eq!(album.album_dir, None);
+        assert!(outliers(&options.policy, &album, &tracks[2]).is_empty());
+        assert_eq!(
+            nice_path(Path::new("/lib"), &options, &album, &tracks[2]).expect("path"),
+            PathBuf::from("/lib/artist_b/second/01_three.flac")
+        );
+
+        // Untagged tracks do not count against the album of the tagged ones
+        let tracks = vec![
+            track("One", "Artist A", "First", 1),
+            track("Two", "", "", 2),
+        ];
+        let album = album_values(&options.policy, &tracks);
+        assert_eq!(album.artist_dir.as_deref(), Some("artist_a"));
+        assert_eq!(album.album_dir.as_deref(), Some("first"));
+    }
+
+    #[test]
+    fn renames_onto_existing_or_shared_paths_collide() {
+        let root = tempdir().expect("tempdir");
+        let existing = root.path().join("a/b/01_one.flac");
+        std::fs::create_dir_all(existing.parent().expect("parent")).expect("mkdirs");
+        std::fs::write(&existing, b"").expect("write");
+        let one = root.path().join("in/one.flac");
+        let two = root.path().join("in/two.flac");
+        let three = root.path().join("in/three.flac");
+
+        let renames = vec![
+            (one.as_path(), existing.clone()),
+            (two.as_path(), root.path().join("a/b/02_two.flac")),
+            (three.as_path(), root.path().join("a/b/02_two.flac")),
+        ];
+        let found = collisions(root.path(), &renames, &BTreeSet::new()).expect("collisions");
+
+        assert_eq!(
+            found,
+            vec![
+                "in/one.flac would overwrite a/b/01_one.flac".to_owned(),
+                "in/two.flac, in/three.flac would all be renamed to a/b/02_two.flac".to_owned(),
+            ]
+        );
+        assert!(collisions(root.path(), &renames[1..2], &BTreeSet::new())
+            .expect("collisions")
+            .is_empty());
+
+        // A classical work can gather tracks of several albums
+        let claimed = BTreeSet::from([root.path().join("a/b/02_two.flac")]);
+        assert_eq!(
+            collisions(root.path(), &renames[1..2], &claimed).expect("collisions"),
+            vec![
+                "in/two.flac would be renamed to a/b/02_two.flac, like a track of another album"
+                    .to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn policy_controls_names() {
+        let options = NormalizeOptions {
+            policy: TidyPolicy {
+                transliterate: false,
+                lowercase: false,
+                separator: ' ',
+                remove: "?".to_owned(),
+                replace: " ".to_owned(),
+                filesystem: FsProfile::Fat32,
+                ..Default::default()
             },
-        )
+            ..Default::default()
+        };
+        let tracks = vec![track("Hoppípolla: Live?", "The Sigur Rós", "Takk...", 2)];
+
+        let album = album_values(&options.policy, &tracks);
+
+        assert_eq!(
+            nice_path(Path::new("/lib"), &options, &album, &tracks[0]).expect("path"),
+            PathBuf::from("/lib/Sigur Rós/Takk/02 Hoppípolla Live.flac")
+        );
+    }
+
+    #[test]
+    fn disc_numbers_only_for_multi_disc_albums() {
+        let mut single = vec![track("One", "Artist", "Album", 1)];
+        single[0].disc = Some(1);
+        single[0].disc_total = Some(1);
+        let mut options = NormalizeOptions::default();
+        let album = album_values(&options.policy, &single);
+        assert_eq!(
+            nice_path(Path::new("/lib"), &options, &album, &single[0]).expect("path"),
+            PathBuf::from("/lib/artist/album/01_one.flac")
+        );
+
+        let mut boxed = vec![
+            track("One", "Artist", "Album", 1),
+            track("Two", "Artist", "Album", 1),
+            track("Three", "Artist", "Album", 3),
+        ];
+        boxed[0].disc = Some(2);
+        boxed[1].disc = Some(12);
+        boxed[1].disc_subtitle = Some("Live".to_owned());
+        let album = album_values(&options.policy, &boxed);
+        assert_eq!(
+            nice_path(Path::new("/lib"), &options, &album, &boxed[0]).expect("path"),
+            PathBuf::from("/lib/artist/album/02-01_one.flac")
+        );
+        // A track without a disc tag is on the first disc
+        assert_eq!(
+            nice_path(Path::new("/lib"), &options, &album, &boxed[2]).expect("path"),
+            PathBuf::from("/lib/artist/album/01-03_three.flac")
+        );
+
+        options.disc_dirs = true;
+        assert_eq!(
+            nice_path(Path::new("/lib"), &options, &album, &boxed[0]).expect("path"),
+            PathBuf::from("/lib/artist/album/disc_02/01_one.flac")
+        );
+        assert_eq!(
+            nice_path(Path::new("/lib"), &options, &album, &boxed[1]).expect("path"),
+            PathBuf::from("/lib/artist/album/disc_12_live/01_two.flac")
+        );
+        assert_eq!(
+            nice_path(Path::new("/lib"), &options, &album, &boxed[2]).expect("path"),
+            PathBuf::from("/lib/artist/album/disc_01/03_three.flac")
+        );
+    }
+
+    #[test]
+    fn disc_directories_belong_to_their_album() {
+        assert_eq!(
+            album_dir_of(Path::new("/lib/a/b/disc_2_live/01.flac")),
+            Some(Path::new("/lib/a/b"))
+        );
+        assert_eq!(
+            album_dir_of(Path::new("/lib/a/CD 1/01.flac")),
+            Some(Path::new("/lib/a"))
+        );
+        assert_eq!(
+            album_dir_of(Path::new("/lib/a/discography/01.flac")),
+            Some(Path::new("/lib/a/discography"))
+        );
+    }
+
+    #[test]
+    fn classical_albums_use_composer_work_and_movement() {
+        let mut tracks = vec![
+            track(
+                "Symphony No. 5: I. Allegro",
+                "Berliner Philharmoniker",
+                "Beethoven",
+                1,
+            ),
+            track(
+                "Symphony No. 5: II. Andante",
+                "Berliner Philharmoniker",
+                "Beethoven",
+                2,
+            ),
+            track(
+                "Coriolan Overture",
+                "Berliner Philharmoniker",
+                "Beethoven",
+                3,
+            ),
+        ];
+        for (number, track) in tracks.iter_mut().enumerate() {
+            track.composer = Some("Ludwig van Beethoven".to_owned());
+            track.performer = Some("Herbert von Karajan".to_owned());
+            track.year = Some(1963);
+            if number < 2 {
+                track.work = Some("Symphony No. 5".to_owned());
+                track.movement_number = Some(number as u32 + 1);
+            }
+        }
+        tracks[0].movement = Some("Allegro con brio".to_owned());
+
+        let policy = NormalizeOptions::default().policy;
+        let album = album_values(&policy, &tracks);
+        let works = work_values(&policy, &tracks);
+        let path = |track: &TrackTags| {
+            let work = &works[&work_dir(&policy, track)];
+            classical_path(Path::new("/lib"), &policy, &album, work, track).expect("path")
+        };
+
+        assert_eq!(
+            path(&tracks[0]),
+            PathBuf::from(
+                "/lib/ludwig_van_beethoven/symphony_no_5/herbert_von_karajan_1963/01_allegro_con_brio.flac"
+            )
+        );
+        assert_eq!(
+            path(&tracks[2]),
+            PathBuf::from(
+                "/lib/ludwig_van_beethoven/beethoven/herbert_von_karajan_1963/03_coriolan_overture.flac"
+            )
+        );
+    }
+
+    #[test]
+    fn classical_layout_follows_genre_or_marker() {
+        let root = tempdir().expect("tempdir");
+        let album = root.path().join("Classical/Album");
+        std::fs::create_dir_all(&album).expect("mkdirs");
+        let mut tracks = vec![track("One", "Artist", "Album", 1)];
+        let options = NormalizeOptions::default();
+
+        assert!(!is_classical(root.path(), &album, &tracks, &options));
+        tracks[0].genre = Some("Classical".to_owned());
+        assert!(is_classical(root.path(), &album, &tracks, &options));
+
+        tracks[0].genre = None;
+        std::fs::write(root.path().join("Classical").join(CLASSICAL_MARKER), b"").expect("marker");
+        assert!(is_classical(root.path(), &album, &tracks, &options));
+    }
 }
@@ -0,0 +1,516 @@
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::dest_names::DestNames;
+use crate::fs_utils::{absolute_path, normalize_lexically, relative_to};
+use crate::query::{self, Filter, SortKey};
+use crate::schema::Track;
+use crate::transcode::{is_source_file, target_path, TranscodeFormat};
+
+/// Playlist file formats written by `loot playlist`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum PlaylistFormat {
+    /// Extended M3U in UTF-8 with `#EXTINF` durations.
+    #[default]
+    M3u8,
+    /// XML Shareable Playlist Format.
+    Xspf,
+    Pls,
+}
+
+impl PlaylistFormat {
+    /// Guesses the format from a playlist file extension.
+    pub fn from_path(path: &Path) -> Option<PlaylistFormat> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "m3u" | "m3u8" => Some(PlaylistFormat::M3u8),
+            "xspf" => Some(PlaylistFormat::Xspf),
+            "pls" => Some(PlaylistFormat::Pls),
+            _ => None,
+        }
+    }
+}
+
+/// A saved query, read from a JSON smart-playlist definition file.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct SmartPlaylist {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Filter expression in the `loot query` language.
+    #[serde(default)]
+    pub query: String,
+    /// Comma-separated sort fields, as accepted by `loot query --sort`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sort: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+impl SmartPlaylist {
+    /// Reads a smart-playlist definition file.
+    pub fn load(path: &Path) -> Result<SmartPlaylist, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        serde_json::from_str(&contents).map_err(|e| e.to_string())
+    }
+}
+
+/// Options controlling how playlist entries are written.
+#[derive(Clone, Debug, Default)]
+pub struct PlaylistOptions {
+    pub format: PlaylistFormat,
+    /// Directory entries are written relative to; absolute paths when `None`.
+    pub root: Option<PathBuf>,
+    /// Point entries at the files `transcode` writes into this destination
+    /// tree instead of the library sources.
+    pub transcoded: Option<(PathBuf, TranscodeFormat)>,
+    /// Playlist file to write; standard output when `None`.
+    pub output: Option<PathBuf>,
+}
+
+/// One playlist item.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlaylistEntry {
+    pub location: PathBuf,
+    pub artist: String,
+    pub title: String,
+    pub album: String,
+    pub duration_ms: Option<u64>,
+}
+
+impl PlaylistEntry {
+    /// Builds an entry for `track` stored at `location`.
+    pub fn new(location: PathBuf, track: &Track) -> PlaylistEntry {
+        let title = track.title.clone().unwrap_or_else(|| {
+            location
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        });
+        PlaylistEntry {
+            artist: track.artist.clone(),
+            title,
+            album: track.album.clone(),
+            duration_ms: track.duration_ms.or(track.duration.map(|secs| secs * 1000)),
+            location,
+        }
+    }
+
+    /// Duration in whole seconds, or -1 when unknown as M3U and PLS expect.
+    fn seconds(&self) -> i64 {
+        self.duration_ms
+            .map(|ms| ((ms + 500) / 1000) as i64)
+            .unwrap_or(-1)
+    }
+
+    fn display_title(&self) -> String {
+        if self.artist.is_empty() {
+            self.title.clone()
+        } else {
+            format!("{} - {}", self.artist, self.title)
+        }
+    }
+}
+
+/// Resolves where a library track lives for the playlist: in the library
+/// itself, or at its transcoded counterpart named by `names`. Returns `None`
+/// for tracks that `transcode` does not convert.
+pub fn entry_path(
+    library: &Path,
+    relative: &str,
+    transcoded: Option<&(PathBuf, TranscodeFormat)>,
+    names: &DestNames,
+) -> Option<PathBuf> {
+    match transcoded {
+        Some((dest, format)) => is_source_file(Path::new(relative))
+            .then(|| target_path(dest, &names.name(Path::new(relative)), *format)),
+        None => Some(library.join(relative)),
+    }
+}
+
+/// An M3U playlist rewritten to point into a transcoded tree.
+#[derive(Debug, Default, PartialEq)]
+pub struct RemappedPlaylist {
+    pub contents: String,
+    /// Entries left out because they fall outside the source root or their
+    /// transcoded file does not exist.
+    pub dropped: Vec<String>,
+}
+
+/// Rewrites the entries of an M3U playlist in `playlist_dir` (below
+/// `source_root`) to the files `transcode` writes below `dest_root`, named
+/// by `names`.
+///
+/// Relative entries stay relative to the playlist's new location and absolute
+/// entries stay absolute. Entries whose target fails `target_exists` are
+/// dropped together with their `#EXTINF` line.
+pub fn remap_m3u(
+    contents: &str,
+    playlist_dir: &Path,
+    source_root: &Path,
+    dest_root: &Path,
+    format: TranscodeFormat,
+    names: &DestNames,
+    target_exists: impl Fn(&Path) -> bool,
+) -> RemappedPlaylist {
+    let dest_dir = dest_root.join(
+        names.name(
+            playlist_dir
+                .strip_prefix(source_root)
+                .unwrap_or(Path::new("")),
+        ),
+    );
+    let mut remapped = RemappedPlaylist::default();
+    let mut pending_info: Vec<&str> = Vec::new();
+
+    for line in contents.trim_start_matches('\u{feff}').lines() {
+        let line = line.trim_end_matches('\r');
+        if line.starts_with("#EXTINF") {
+            pending_info.push(line);
+            continue;
+        }
+        if line.trim().is_empty() || line.starts_with('#') {
+            remapped.contents.push_str(line);
+            remapped.contents.push('\n');
+            continue;
+        }
+
+        let entry = Path::new(line);
+        let source = normalize_lexically(&playlist_dir.join(entry));
+        let target = source.strip_prefix(source_root).ok().map(|relative| {
+            if is_source_file(relative) {
+                target_path(dest_root, &names.name(relative), format)
+            } else {
+                dest_root.join(names.name(relative))
+            }
+        });
+
+        match target {
+            Some(target) if target_exists(&target) => {
+                let written = if entry.is_absolute() {
+                    target
+                } else {
+                    relative_to(&target, &dest_dir)
+                };
+                for info in pending_info.drain(..) {
+                    remapped.contents.push_str(info);
+                    remapped.contents.push('\n');
+                }
+                remapped.contents.push_str(&written.to_string_lossy());
+                remapped.contents.push('\n');
+            }
+            _ => {
+                pending_info.clear();
+                remapped.dropped.push(line.to_owned());
+            }
+        }
+    }
+    remapped
+}
+
+/// Renders a playlist in `format`.
+pub fn render(format: PlaylistFormat, name: Option<&str>, entries: &[PlaylistEntry]) -> String {
+    match format {
+        PlaylistFormat::M3u8 => render_m3u8(name, entries),
+        PlaylistFormat::Xspf => render_xspf(name, entries),
+        PlaylistFormat::Pls => render_pls(entries),
+    }
+}
+
+fn render_m3u8(name: Option<&str>, entries: &[PlaylistEntry]) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    if let Some(name) = name {
+        out.push_str(&format!("#PLAYLIST:{}\n", name));
+    }
+    for entry in entries {
+        out.push_str(&format!(
+            "#EXTINF:{},{}\n{}\n",
+            entry.seconds(),
+            entry.display_title(),
+            entry.location.display()
+        ));
+    }
+    out
+}
+
+fn render_xspf(name: Option<&str>, entries: &[PlaylistEntry]) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n",
+    );
+    if let Some(name) = name {
+        out.push_str(&format!("  <title>{}</title>\n", xml_escape(name)));
+    }
+    out.push_str("  <trackList>\n");
+    for entry in entries {
+        out.push_str("    <track>\n");
+        out.push_str(&format!(
+            "      <location>{}</location>\n",
+            xml_escape(&location_uri(&entry.location))
+        ));
+        out.push_str(&format!(
+            "      <title>{}</title>\n",
+            xml_escape(&entry.title)
+        ));
+        if !entry.artist.is_empty() {
+            out.push_str(&format!(
+                "      <creator>{}</creator>\n",
+                xml_escape(&entry.artist)
+            ));
+        }
+        if !entry.album.is_empty() {
+            out.push_str(&format!(
+                "      <album>{}</album>\n",
+                xml_escape(&entry.album)
+            ));
+        }
+        if let Some(ms) = entry.duration_ms {
+            out.push_str(&format!("      <duration>{}</duration>\n", ms));
+        }
+        out.push_str("    </track>\n");
+    }
+    out.push_str("  </trackList>\n</playlist>\n");
+    out
+}
+
+fn render_pls(entries: &[PlaylistEntry]) -> String {
+    let mut out = String::from("[playlist]\n");
+    for (number, entry) in entries.iter().enumerate().map(|(i, e)| (i + 1, e)) {
+        out.push_str(&format!("File{}={}\n", number, entry.location.display()));
+        out.push_str(&format!("Title{}={}\n", number, entry.display_title()));
+        out.push_str(&format!("Length{}={}\n", number, entry.seconds()));
+    }
+    out.push_str(&format!("NumberOfEntries={}\nVersion=2\n", entries.len()));
+    out
+}
+
+/// Percent-encodes a path for an XSPF `<location>`; absolute paths become
+/// `file://` URIs.
+fn location_uri(path: &Path) -> String {
+    let encoded = path
+        .to_string_lossy()
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect::<String>();
+    if path.is_absolute() {
+        format!("file://{}", encoded)
+    } else {
+        encoded
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Writes a playlist of the library tracks matching `definition`.
+pub fn playlist(library: &str, definition: &SmartPlaylist, options: &PlaylistOptions) {
+    let filter = match Filter::parse(&definition.query) {
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("{} Invalid query: {}", "✗".red().bold(), e);
+            return;
+        }
+    };
+    let library = absolute_path(Path::new(library));
+    let tracks = match query::load_library_tracks(&library) {
+        Ok(tracks) => tracks,
+        Err(e) => {
+            eprintln!("{} Error loading index: {}", "✗".red().bold(), e);
+            return;
+        }
+    };
+
+    let sort = definition
+        .sort
+        .as_deref()
+        .map(SortKey::parse_list)
+        .unwrap_or_default();
+    let root = options.root.as_deref().map(absolute_path);
+    let transcoded = options
+        .transcoded
+        .as_ref()
+        .map(|(dest, format)| (absolute_path(dest), *format));
+    let names = transcoded
+        .as_ref()
+        .map(|(dest, _)| DestNames::open(dest, None))
+        .unwrap_or_default();
+
+    let mut skipped = 0;
+    let entries = query::select(&tracks, &filter, &sort, definition.limit)
+        .into_iter()
+        .filter_map(|(path, track)| {
+            // Sources that were never transcoded have no file to point at
+            let location = match entry_path(&library, path, transcoded.as_ref(), &names) {
+                Some(location) if transcoded.is_none() || location.exists() => location,
+                _ => {
+                    skipped += 1;
+                    return None;
+                }
+            };
+            let location = match &root {
+                Some(root) => relative_to(&location, root),
+                None => location,
+            };
+            Some(PlaylistEntry::new(location, track))
+        })
+        .collect::<Vec<_>>();
+
+    if skipped > 0 {
+        eprintln!(
+            "{} Skipped {} tracks that are not transcoded",
+            "⚠️".bright_yellow(),
+            skipped.to_string().bright_yellow().bold()
+        );
+    }
+
+    let contents = render(options.format, definition.name.as_deref(), &entries);
+    match &options.output {
+        Some(output) => match fs::write(output, contents) {
+            Ok(_) => println!(
+                "{} Playlist written to: {} ({} tracks)",
+                "💾".bright_green(),
+                output.display().to_string().bright_white(),
+                entries.len().to_string().bright_yellow().bold()
+            ),
+            Err(e) => eprintln!("{} Error writing playlist: {}", "✗".red().bold(), e),
+        },
+        None => print!("{}", contents),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{entry_path, remap_m3u, render, PlaylistEntry, PlaylistFormat};
+    use crate::dest_names::DestNames;
+    use crate::transcode::TranscodeFormat;
+
+    fn entries() -> Vec<PlaylistEntry> {
+        vec![
+            PlaylistEntry {
+                location: PathBuf::from("Artist/Album/01 Song.opus"),
+                artist: "Artist".to_owned(),
+                title: "Song & Dance".to_owned(),
+                album: "Album".to_owned(),
+                duration_ms: Some(183_400),
+            },
+            PlaylistEntry {
+                location: PathBuf::from("/music/02.opus"),
+                artist: String::new(),
+                title: "02".to_owned(),
+                album: String::new(),
+                duration_ms: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn renders_m3u8_with_extinf_durations() {
+        assert_eq!(
+            render(PlaylistFormat::M3u8, Some("Mix"), &entries()),
+            "#EXTM3U\n#PLAYLIST:Mix\n#EXTINF:183,Artist - Song & Dance\nArtist/Album/01 Song.opus\n#EXTINF:-1,02\n/music/02.opus\n"
+        );
+    }
+
+    #[test]
+    fn renders_xspf_and_pls() {
+        let xspf = render(PlaylistFormat::Xspf, None, &entries());
+        assert!(xspf.contains("<location>Artist/Album/01%20Song.opus</location>"));
+        assert!(xspf.contains("<title>Song &amp; Dance</title>"));
+        assert!(xspf.contains("<duration>183400</duration>"));
+        assert!(xspf.contains("<location>file:///music/02.opus</location>"));
+
+        let pls = render(PlaylistFormat::Pls, None, &entries());
+        assert!(pls.starts_with("[playlist]\nFile1=Artist/Album/01 Song.opus\n"));
+        assert!(pls.ends_with("Length2=-1\nNumberOfEntries=2\nVersion=2\n"));
+    }
+
+    #[test]
+    fn maps_entries_to_transcoded_paths() {
+        let library = Path::new("/music/flac");
+        let transcoded = (
+            PathBuf::from("/music/opus"),
+            TranscodeFormat::Opus { bitrate_kbps: 128 },
+        );
+
+        assert_eq!(
+            entry_path(library, "A/B/01.flac", None, &DestNames::default()),
+            Some(PathBuf::from("/music/flac/A/B/01.flac"))
+        );
+        assert_eq!(
+            entry_path(
+                library,
+                "A/B/01.flac",
+                Some(&transcoded),
+                &DestNames::default()
+            ),
+            Some(PathBuf::from("/music/opus/A/B/01.opus"))
+        );
+        assert_eq!(
+            entry_path(
+                library,
+                "A/B/02.FLAC",
+                Some(&transcoded),
+                &DestNames::default()
+            ),
+            Some(PathBuf::from("/music/opus/A/B/02.opus"))
+        );
+        assert_eq!(
+            entry_path(
+                library,
+                "A/B/01.mp3",
+                Some(&transcoded),
+                &DestNames::default()
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn remaps_m3u_entries_into_transcoded_tree() {
+        let contents = "#EXTM3U\r\n#EXTINF:100,A - One\r\n../A/01.flac\r\n#EXTINF:50,Gone\r\n../A/02.flac\r\n/music/flac/A/03.FLAC\r\n/elsewhere/04.flac\r\n";
+
+        let remapped = remap_m3u(
+            contents,
+            Path::new("/music/flac/Lists"),
+            Path::new("/music/flac"),
+            Path::new("/music/opus"),
+            TranscodeFormat::Opus { bitrate_kbps: 128 },
+            &DestNames::default(),
+            |target| target != Path::new("/music/opus/A/02.opus"),
+        );
+
+        assert_eq!(
+            remapped.contents,
+            "#EXTM3U\n#EXTINF:100,A - One\n../A/01.opus\n/music/opus/A/03.opus\n"
+        );
+        assert_eq!(
+            remapped.dropped,
+            vec!["../A/02.flac".to_owned(), "/elsewhere/04.flac".to_owned()]
+        );
+    }
+
+    #[test]
+    fn guesses_format_from_extension() {
+        assert_eq!(
+            PlaylistFormat::from_path(Path::new("mix.M3U8")),
+            Some(PlaylistFormat::M3u8)
+        );
+        assert_eq!(
+            PlaylistFormat::from_path(Path::new("mix.xspf")),
+            Some(PlaylistFormat::Xspf)
+        );
+        assert_eq!(PlaylistFormat::from_path(Path::new("mix.txt")), None);
+    }
+}
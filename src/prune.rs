@@ -1,37 +1,132 @@
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
 
+use crate::dest_names::DestNames;
 use crate::fs_utils::{canonicalize_path, glob_pattern};
+use crate::transcode::is_source_file;
+
+/// Extensions of the files written by `transcode`.
+const TRANSCODED_EXTENSIONS: &[&str] = &["mp3", "opus", "m4a"];
+
+/// Extensions of the playlists `transcode` copies, under their source names.
+const PLAYLIST_EXTENSIONS: &[&str] = &["m3u", "m3u8"];
+
+fn is_playlist(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| {
+            PLAYLIST_EXTENSIONS
+                .iter()
+                .any(|playlist| extension.to_string_lossy().eq_ignore_ascii_case(playlist))
+        })
+        .unwrap_or(false)
+}
 
 fn has_source_counterpart(source_dirs: &[String], relative: &Path) -> bool {
     source_dirs.iter().any(|source_dir| {
-        Path::new(source_dir)
-            .join(relative)
-            .with_extension("flac")
-            .exists()
+        let source = Path::new(source_dir).join(relative);
+        if is_playlist(relative) {
+            return source.exists();
+        }
+        // `transcode` picks up sources whatever the case of their extension,
+        // so the source directory is listed rather than probed per extension
+        let stem = relative.file_stem();
+        let entries = match source.parent().map(fs::read_dir) {
+            Some(Ok(entries)) => entries,
+            _ => return false,
+        };
+        entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .any(|path| path.file_stem() == stem && is_source_file(&path))
     })
 }
 
-/// Removes transcoded files from the destination when the source FLAC no longer exists.
+/// Returns whether the transcoded file at `relative` still has a source: the
+/// one recorded in `names` for sanitized names, otherwise a source file of the
+/// same name.
+fn has_source(source_dirs: &[String], names: &DestNames, relative: &Path) -> bool {
+    match names.source_of(relative) {
+        Some(source) => source_dirs
+            .iter()
+            .any(|source_dir| Path::new(source_dir).join(source).exists()),
+        None => has_source_counterpart(source_dirs, relative),
+    }
+}
+
+/// Removes the orphan at `path` and its recorded name.
+fn remove_orphan(names: &mut DestNames, path: &Path, relative: &Path, dry_run: bool) {
+    println!("{:?}", path);
+    if !dry_run {
+        std::fs::remove_file(path).ok();
+        if let Some(source) = names.source_of(relative).map(PathBuf::from) {
+            names.forget(&source);
+        }
+    }
+}
+
+/// Removes transcoded files from the destination when their source no longer exists,
+/// and copied playlists whose source playlist is gone.
 pub fn prune(source_dirs: &[String], dest_dir: &str, dry_run: bool) {
     let canonical = canonicalize_path(dest_dir);
     let canonical_string = canonical.to_str().expect("Invalid path.");
     println!("processing {}", canonical_string);
 
-    let pattern = glob_pattern(&canonical, &["mp3", "opus", "m4a"]);
+    let mut names = DestNames::open(&canonical, None);
+    let extensions = [TRANSCODED_EXTENSIONS, PLAYLIST_EXTENSIONS].concat();
+    let pattern = glob_pattern(&canonical, &extensions);
     globwalk::glob(&pattern)
         .expect("glob error")
         .filter_map(Result::ok)
-        .into_iter()
         .for_each(|entry| {
             let relative = entry.path().strip_prefix(&canonical).expect("Not a prefix");
-            if has_source_counterpart(source_dirs, relative) {
-                return;
-            }
-            println!("{:?}", entry.path());
-            if !dry_run {
-                std::fs::remove_file(entry.path()).ok();
+            if !has_source(source_dirs, &names, relative) {
+                remove_orphan(&mut names, entry.path(), relative, dry_run);
             }
         });
+    names.finish(&canonical, dry_run);
+}
+
+/// Removes transcoded files at or below the given paths, relative to
+/// `dest_dir`, whose source file or playlist no longer exists.
+pub fn prune_paths<S: AsRef<Path>>(
+    source_dirs: &[String],
+    dest_dir: &Path,
+    relative_paths: &[S],
+    dry_run: bool,
+) {
+    let mut names = DestNames::open(dest_dir, None);
+    for relative in relative_paths {
+        let relative = relative.as_ref();
+        let path = dest_dir.join(names.name(relative));
+        let candidates = if path.is_dir() {
+            let extensions = [TRANSCODED_EXTENSIONS, PLAYLIST_EXTENSIONS].concat();
+            let pattern = glob_pattern(&path, &extensions);
+            globwalk::glob(&pattern)
+                .expect("glob error")
+                .filter_map(Result::ok)
+                .map(|entry| entry.into_path())
+                .collect::<Vec<_>>()
+        } else if is_playlist(&path) {
+            if path.is_file() {
+                vec![path]
+            } else {
+                Vec::new()
+            }
+        } else {
+            TRANSCODED_EXTENSIONS
+                .iter()
+                .map(|extension| path.with_extension(extension))
+                .filter(|candidate| candidate.is_file())
+                .collect()
+        };
+        for candidate in candidates {
+            let relative = candidate.strip_prefix(dest_dir).expect("Not a prefix");
+            if !has_source(source_dirs, &names, relative) {
+                remove_orphan(&mut names, &candidate, relative, dry_run);
+            }
+        }
+    }
+    names.finish(dest_dir, dry_run);
 }
 
 #[cfg(test)]
@@ -41,7 +136,9 @@ mod tests {
 
     use tempfile::tempdir;
 
-    use super::has_source_counterpart;
+    use super::{has_source_counterpart, is_playlist, prune_paths};
+    use crate::dest_names::DestNames;
+    use crate::text::{FsProfile, TidyPolicy};
 
     #[test]
     fn detects_existing_flac_in_any_source_dir() {
@@ -60,6 +157,37 @@ mod tests {
         assert!(has_source_counterpart(&sources, relative));
     }
 
+    #[test]
+    fn detects_sources_with_any_case_or_source_extension() {
+        let source = tempdir().expect("tempdir");
+        let album = source.path().join("Artist/Album");
+        fs::create_dir_all(&album).expect("mkdirs");
+        fs::write(album.join("one.fLaC"), b"audio").expect("write");
+        fs::write(album.join("two.opus"), b"audio").expect("write");
+        fs::write(album.join("three.wav"), b"audio").expect("write");
+        let sources = vec![source.path().to_string_lossy().into_owned()];
+
+        assert!(has_source_counterpart(
+            &sources,
+            Path::new("Artist/Album/one.opus")
+        ));
+        assert!(has_source_counterpart(
+            &sources,
+            Path::new("Artist/Album/two.opus")
+        ));
+        assert!(!has_source_counterpart(
+            &sources,
+            Path::new("Artist/Album/three.mp3")
+        ));
+    }
+
+    #[test]
+    fn matches_playlist_extensions_in_any_case() {
+        assert!(is_playlist(Path::new("Mix.M3U")));
+        assert!(is_playlist(Path::new("Mix.m3u8")));
+        assert!(!is_playlist(Path::new("Mix.opus")));
+    }
+
     #[test]
     fn returns_false_when_no_source_flac_exists() {
         let source = tempdir().expect("tempdir");
@@ -70,4 +198,80 @@ mod tests {
             Path::new("Artist/Album/missing.m4a")
         ));
     }
+
+    #[test]
+    fn prune_paths_only_removes_orphans_below_given_paths() {
+        let source = tempdir().expect("tempdir");
+        let dest = tempdir().expect("tempdir");
+        let kept = source.path().join("Artist/Album/kept.flac");
+        fs::create_dir_all(kept.parent().expect("parent")).expect("mkdirs");
+        fs::write(&kept, b"audio").expect("write");
+        for relative in [
+            "Artist/Album/kept.opus",
+            "Artist/Album/gone.opus",
+            "Other/gone.mp3",
+        ] {
+            let path = dest.path().join(relative);
+            fs::create_dir_all(path.parent().expect("parent")).expect("mkdirs");
+            fs::write(&path, b"audio").expect("write");
+        }
+        let sources = vec![source.path().to_string_lossy().into_owned()];
+
+        prune_paths(&sources, dest.path(), &["Artist"], false);
+
+        assert!(dest.path().join("Artist/Album/kept.opus").exists());
+        assert!(!dest.path().join("Artist/Album/gone.opus").exists());
+        assert!(dest.path().join("Other/gone.mp3").exists());
+    }
+
+    #[test]
+    fn prune_paths_follows_recorded_names() {
+        let source = tempdir().expect("tempdir");
+        let dest = tempdir().expect("tempdir");
+        let kept = source.path().join("A/Why?.flac");
+        fs::create_dir_all(kept.parent().expect("parent")).expect("mkdirs");
+        fs::write(&kept, b"audio").expect("write");
+        let policy = TidyPolicy {
+            filesystem: FsProfile::Fat32,
+            ..Default::default()
+        };
+        let mut names = DestNames::open(dest.path(), Some(&policy));
+        for relative in ["A/Why?.flac", "A/How?.flac"] {
+            let path = dest
+                .path()
+                .join(names.assign(Path::new(relative)))
+                .with_extension("opus");
+            fs::create_dir_all(path.parent().expect("parent")).expect("mkdirs");
+            fs::write(&path, b"audio").expect("write");
+        }
+        names.save(dest.path()).expect("save");
+        let sources = vec![source.path().to_string_lossy().into_owned()];
+
+        prune_paths(&sources, dest.path(), &["A"], false);
+
+        assert!(dest.path().join("A/Why_.opus").exists());
+        assert!(!dest.path().join("A/How_.opus").exists());
+        let names = DestNames::open(dest.path(), None);
+        assert_eq!(names.source_of(Path::new("A/How_.opus")), None);
+    }
+
+    #[test]
+    fn prune_paths_removes_playlists_without_a_source_playlist() {
+        let source = tempdir().expect("tempdir");
+        let dest = tempdir().expect("tempdir");
+        let kept = source.path().join("Lists/kept.m3u");
+        fs::create_dir_all(kept.parent().expect("parent")).expect("mkdirs");
+        fs::write(&kept, b"01.flac\n").expect("write");
+        for relative in ["Lists/kept.m3u", "Lists/gone.m3u8"] {
+            let path = dest.path().join(relative);
+            fs::create_dir_all(path.parent().expect("parent")).expect("mkdirs");
+            fs::write(&path, b"01.opus\n").expect("write");
+        }
+        let sources = vec![source.path().to_string_lossy().into_owned()];
+
+        prune_paths(&sources, dest.path(), &["Lists"], false);
+
+        assert!(dest.path().join("Lists/kept.m3u").exists());
+        assert!(!dest.path().join("Lists/gone.m3u8").exists());
+    }
 }
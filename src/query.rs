@@ -0,0 +1,643 @@
+use colored::*;
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+
+use crate::schema::{track_field_names, Index, Track, INDEX_FILE_NAME};
+use crate::sqlite::{self, INDEX_DB_FILE_NAME};
+
+/// A parsed filter expression such as `artist~"radiohead" and year>=2000`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    /// Matches every track; produced by an empty expression.
+    All,
+    Compare {
+        field: String,
+        op: Op,
+        value: String,
+    },
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+}
+
+/// Comparison operators supported by the filter language.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Op {
+    /// `=`: case-insensitive equality, numeric when both sides are numbers.
+    Eq,
+    /// `!=`
+    Ne,
+    /// `~`: case-insensitive substring match.
+    Contains,
+    /// `!~`
+    NotContains,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A malformed filter expression.
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Output styles for `loot query`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum QueryOutput {
+    /// Aligned columns for reading in a terminal.
+    #[default]
+    Table,
+    /// One JSON object per line, including the track path.
+    Json,
+    /// Absolute file paths, one per line.
+    Paths,
+}
+
+/// Sort key; `-field` sorts descending.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SortKey {
+    pub field: String,
+    pub descending: bool,
+}
+
+impl SortKey {
+    /// Parses a comma-separated list of sort keys like `artist,-year`.
+    pub fn parse_list(value: &str) -> Vec<SortKey> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .map(|field| match field.strip_prefix('-') {
+                Some(field) => SortKey {
+                    field: field.to_owned(),
+                    descending: true,
+                },
+                None => SortKey {
+                    field: field.to_owned(),
+                    descending: false,
+                },
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Op(Op),
+    Open,
+    Close,
+}
+
+impl Filter {
+    /// Parses a filter expression.
+    ///
+    /// Comparisons are `field op value`, combined with `and`, `or`, `not` and
+    /// parentheses. Adjacent comparisons without a keyword are joined by `and`.
+    pub fn parse(expression: &str) -> Result<Filter, ParseError> {
+        let tokens = tokenize(expression)?;
+        if tokens.is_empty() {
+            return Ok(Filter::All);
+        }
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+        };
+        let filter = parser.parse_or()?;
+        match parser.tokens.get(parser.position) {
+            None => Ok(filter),
+            Some(token) => Err(error(format!("unexpected {:?}", token))),
+        }
+    }
+
+    /// Returns whether `track`, indexed at `path`, satisfies the filter.
+    pub fn matches(&self, path: &str, track: &Track) -> bool {
+        self.matches_fields(&lookup_fields(path, track))
+    }
+
+    fn matches_fields(&self, fields: &LookupFields) -> bool {
+        match self {
+            Filter::All => true,
+            Filter::Compare { field, op, value } => compare(field_value(fields, field), *op, value),
+            Filter::And(a, b) => a.matches_fields(fields) && b.matches_fields(fields),
+            Filter::Or(a, b) => a.matches_fields(fields) || b.matches_fields(fields),
+            Filter::Not(inner) => !inner.matches_fields(fields),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.tokens.get(self.position), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn parse_or(&mut self) -> Result<Filter, ParseError> {
+        let mut filter = self.parse_and()?;
+        while self.peek_keyword("or") {
+            self.position += 1;
+            filter = Filter::Or(Box::new(filter), Box::new(self.parse_and()?));
+        }
+        Ok(filter)
+    }
+
+    fn parse_and(&mut self) -> Result<Filter, ParseError> {
+        let mut filter = self.parse_unary()?;
+        loop {
+            if self.peek_keyword("and") {
+                self.position += 1;
+            } else if self.position >= self.tokens.len()
+                || self.peek_keyword("or")
+                || self.tokens[self.position] == Token::Close
+            {
+                return Ok(filter);
+            }
+            filter = Filter::And(Box::new(filter), Box::new(self.parse_unary()?));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Filter, ParseError> {
+        if self.peek_keyword("not") {
+            self.position += 1;
+            return Ok(Filter::Not(Box::new(self.parse_unary()?)));
+        }
+        match self.next() {
+            Some(Token::Open) => {
+                let filter = self.parse_or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(filter),
+                    _ => Err(error("missing closing parenthesis".to_owned())),
+                }
+            }
+            Some(Token::Word(field)) => {
+                if !is_known_field(&field) {
+                    return Err(error(format!("unknown field '{}'", field)));
+                }
+                let op = match self.next() {
+                    Some(Token::Op(op)) => op,
+                    _ => return Err(error(format!("expected an operator after '{}'", field))),
+                };
+                match self.next() {
+                    Some(Token::Word(value)) | Some(Token::Quoted(value)) => {
+                        Ok(Filter::Compare { field, op, value })
+                    }
+                    _ => Err(error(format!("expected a value after '{}'", field))),
+                }
+            }
+            Some(token) => Err(error(format!("unexpected {:?}", token))),
+            None => Err(error("unexpected end of expression".to_owned())),
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+}
+
+fn error(message: String) -> ParseError {
+    ParseError { message }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '"' | '\'' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => value.extend(chars.next()),
+                        Some(q) if q == c => break,
+                        Some(other) => value.push(other),
+                        None => return Err(error("unterminated string".to_owned())),
+                    }
+                }
+                tokens.push(Token::Quoted(value));
+            }
+            '=' | '~' | '!' | '<' | '>' => {
+                chars.next();
+                let followed_by_eq = chars.peek() == Some(&'=');
+                let op = match (c, followed_by_eq) {
+                    ('=', _) => Op::Eq,
+                    ('~', _) => Op::Contains,
+                    ('!', true) => Op::Ne,
+                    ('!', false) if chars.peek() == Some(&'~') => Op::NotContains,
+                    ('<', true) => Op::Le,
+                    ('<', false) => Op::Lt,
+                    ('>', true) => Op::Ge,
+                    ('>', false) => Op::Gt,
+                    _ => return Err(error("unexpected '!'".to_owned())),
+                };
+                if matches!(op, Op::Ne | Op::Le | Op::Ge | Op::NotContains) {
+                    chars.next();
+                }
+                tokens.push(Token::Op(op));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "()=~!<>\"'".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Flattens a track into its JSON fields plus a `path` field.
+fn track_fields(path: &str, track: &Track) -> Map<String, Value> {
+    let mut fields = match serde_json::to_value(track) {
+        Ok(Value::Object(fields)) => fields,
+        _ => Map::new(),
+    };
+    fields.insert("path".to_owned(), Value::from(path));
+    fields
+}
+
+/// Fields of a track keyed by their normalized names.
+type LookupFields = HashMap<String, Value>;
+
+/// Flattens a track once for filtering and sorting.
+fn lookup_fields(path: &str, track: &Track) -> LookupFields {
+    track_fields(path, track)
+        .into_iter()
+        .map(|(key, value)| (normalize_field_name(&key), value))
+        .collect()
+}
+
+/// Looks up a field ignoring case and underscores, so `album_artist`,
+/// `albumartist` and `albumArtist` all name the same field.
+fn field_value<'a>(fields: &'a LookupFields, name: &str) -> Option<&'a Value> {
+    fields.get(&normalize_field_name(name))
+}
+
+/// Returns whether `name` names a track field or `path`, so a misspelt field
+/// is reported instead of matching nothing, or everything when negated.
+fn is_known_field(name: &str) -> bool {
+    let name = normalize_field_name(name);
+    track_field_names()
+        .iter()
+        .chain(&["path"])
+        .any(|known| normalize_field_name(known) == name)
+}
+
+fn normalize_field_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| *c != '_' && *c != '-')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn compare(actual: Option<&Value>, op: Op, expected: &str) -> bool {
+    let actual = match actual {
+        Some(Value::Null) | None => return matches!(op, Op::Ne | Op::NotContains),
+        Some(actual) => actual,
+    };
+
+    let ordering = match (actual.as_f64(), expected.parse::<f64>().ok()) {
+        (Some(a), Some(b)) => a.partial_cmp(&b),
+        _ => Some(
+            value_text(actual)
+                .to_lowercase()
+                .cmp(&expected.to_lowercase()),
+        ),
+    };
+    let contains = || {
+        value_text(actual)
+            .to_lowercase()
+            .contains(&expected.to_lowercase())
+    };
+
+    match op {
+        Op::Eq => ordering == Some(Ordering::Equal),
+        Op::Ne => ordering != Some(Ordering::Equal),
+        Op::Contains => contains(),
+        Op::NotContains => !contains(),
+        Op::Lt => ordering == Some(Ordering::Less),
+        Op::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+        Op::Gt => ordering == Some(Ordering::Greater),
+        Op::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+    }
+}
+
+fn value_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+fn compare_values(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    match (a, b) {
+        (None, None) => Ordering::Equal,
+        // Missing values sort last
+        (None, Some(_)) => Ordering::Greater,
+        (Some(_), None) => Ordering::Less,
+        (Some(a), Some(b)) => match (a.as_f64(), b.as_f64()) {
+            (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+            _ => value_text(a)
+                .to_lowercase()
+                .cmp(&value_text(b).to_lowercase()),
+        },
+    }
+}
+
+/// Loads the tracks of a library from its `index.json`, or its `index.sqlite`
+/// when there is no JSON index.
+pub fn load_library_tracks(library: &Path) -> Result<BTreeMap<String, Track>, String> {
+    let json_path = library.join(INDEX_FILE_NAME);
+    let db_path = library.join(INDEX_DB_FILE_NAME);
+    if json_path.exists() {
+        Index::load(&json_path)
+            .map(|index| index.tracks)
+            .map_err(|e| format!("{}: {}", json_path.display(), e))
+    } else if db_path.exists() {
+        sqlite::open(&db_path, true)
+            .and_then(|db| sqlite::load_tracks(&db))
+            .map_err(|e| format!("{}: {}", db_path.display(), e))
+    } else {
+        Err(format!("no index found in {}", library.display()))
+    }
+}
+
+/// Returns the tracks matching `filter`, sorted by `sort` (then path) and
+/// truncated to `limit`.
+pub fn select<'a>(
+    tracks: &'a BTreeMap<String, Track>,
+    filter: &Filter,
+    sort: &[SortKey],
+    limit: Option<usize>,
+) -> Vec<(&'a String, &'a Track)> {
+    // Each track is flattened once and its sort values are looked up before
+    // sorting rather than on every comparison
+    let mut selected = tracks
+        .iter()
+        .filter_map(|(path, track)| {
+            let fields = lookup_fields(path, track);
+            if !filter.matches_fields(&fields) {
+                return None;
+            }
+            let sort_values = sort
+                .iter()
+                .map(|key| field_value(&fields, &key.field).cloned())
+                .collect::<Vec<_>>();
+            Some((path, track, sort_values))
+        })
+        .collect::<Vec<_>>();
+
+    selected.sort_by(|(a_path, _, a), (b_path, _, b)| {
+        sort.iter()
+            .zip(a.iter().zip(b))
+            .map(|(key, (a, b))| {
+                let ordering = compare_values(a.as_ref(), b.as_ref());
+                if key.descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            })
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or_else(|| a_path.cmp(b_path))
+    });
+
+    selected
+        .into_iter()
+        .take(limit.unwrap_or(usize::MAX))
+        .map(|(path, track, _)| (path, track))
+        .collect()
+}
+
+/// Runs a query against the index of `library` and prints the results.
+pub fn query(
+    library: &str,
+    expression: &str,
+    sort: &[SortKey],
+    limit: Option<usize>,
+    output: QueryOutput,
+) {
+    let filter = match Filter::parse(expression) {
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("{} Invalid query: {}", "✗".red().bold(), e);
+            return;
+        }
+    };
+    let library = Path::new(library);
+    let tracks = match load_library_tracks(library) {
+        Ok(tracks) => tracks,
+        Err(e) => {
+            eprintln!("{} Error loading index: {}", "✗".red().bold(), e);
+            return;
+        }
+    };
+
+    let results = select(&tracks, &filter, sort, limit);
+    match output {
+        QueryOutput::Table => print_table(&results),
+        QueryOutput::Json => {
+            for (path, track) in results {
+                println!("{}", Value::Object(track_fields(path, track)));
+            }
+        }
+        QueryOutput::Paths => {
+            let root = library
+                .canonicalize()
+                .unwrap_or_else(|_| library.to_path_buf());
+            for (path, _) in results {
+                println!("{}", root.join(path).display());
+            }
+        }
+    }
+}
+
+fn print_table(results: &[(&String, &Track)]) {
+    let header = ["ARTIST", "ALBUM", "#", "TITLE", "YEAR", "TIME"];
+    let rows = results
+        .iter()
+        .map(|(path, track)| {
+            [
+                track
+                    .album_artist
+                    .clone()
+                    .unwrap_or_else(|| track.artist.clone()),
+                track.album.clone(),
+                track
+                    .track_number
+                    .map(|n| n.to_string())
+                    .unwrap_or_default(),
+                track.title.clone().unwrap_or_else(|| (*path).clone()),
+                track.year.map(|y| y.to_string()).unwrap_or_default(),
+                track
+                    .duration
+                    .map(|d| format!("{}:{:02}", d / 60, d % 60))
+                    .unwrap_or_default(),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    let mut widths = header.map(|h| h.chars().count());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let format_row = |cells: Vec<&str>| {
+        cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_owned()
+    };
+    println!("{}", format_row(header.to_vec()));
+    for row in &rows {
+        println!("{}", format_row(row.iter().map(String::as_str).collect()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{select, Filter, Op, SortKey};
+    use crate::schema::Track;
+
+    fn track(artist: &str, year: u16, codec: &str) -> Track {
+        Track {
+            artist: artist.to_owned(),
+            year: Some(year),
+            codec: Some(codec.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    fn library() -> BTreeMap<String, Track> {
+        BTreeMap::from([
+            ("a.flac".to_owned(), track("Radiohead", 2000, "flac")),
+            ("b.mp3".to_owned(), track("Radiohead", 1997, "mp3")),
+            ("c.flac".to_owned(), track("Björk", 2001, "flac")),
+            (
+                "d.flac".to_owned(),
+                track("Thom Yorke & Radiohead", 2006, "flac"),
+            ),
+        ])
+    }
+
+    #[test]
+    fn parses_comparisons_and_keywords() {
+        assert_eq!(
+            Filter::parse(r#"artist~"radio head" and year>=2000"#).expect("parse"),
+            Filter::And(
+                Box::new(Filter::Compare {
+                    field: "artist".to_owned(),
+                    op: Op::Contains,
+                    value: "radio head".to_owned(),
+                }),
+                Box::new(Filter::Compare {
+                    field: "year".to_owned(),
+                    op: Op::Ge,
+                    value: "2000".to_owned(),
+                }),
+            )
+        );
+        assert_eq!(Filter::parse("  ").expect("parse"), Filter::All);
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        assert!(Filter::parse("artist").is_err());
+        assert!(Filter::parse("artist=").is_err());
+        assert!(Filter::parse("(year>1").is_err());
+        assert!(Filter::parse(r#"artist="open"#).is_err());
+        assert_eq!(
+            Filter::parse("artsit!=Foo").map_err(|e| e.message),
+            Err("unknown field 'artsit'".to_owned())
+        );
+        assert!(Filter::parse("album_artist=Foo and path~live").is_ok());
+    }
+
+    #[test]
+    fn filters_tracks() {
+        let tracks = library();
+        let paths = |expression: &str| {
+            let filter = Filter::parse(expression).expect("parse");
+            select(&tracks, &filter, &[], None)
+                .into_iter()
+                .map(|(path, _)| path.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            paths(r#"artist~"radiohead" and year>=2000 and codec=flac"#),
+            vec!["a.flac", "d.flac"]
+        );
+        assert_eq!(paths("codec=mp3 or artist=björk"), vec!["b.mp3", "c.flac"]);
+        assert_eq!(paths("not (codec = flac)"), vec!["b.mp3"]);
+        assert_eq!(paths("year<2000"), vec!["b.mp3"]);
+        assert_eq!(
+            paths("genre!=rock"),
+            vec!["a.flac", "b.mp3", "c.flac", "d.flac"]
+        );
+        assert_eq!(paths("path~.mp3"), vec!["b.mp3"]);
+    }
+
+    #[test]
+    fn sorts_and_limits_results() {
+        let tracks = library();
+
+        let results = select(
+            &tracks,
+            &Filter::All,
+            &SortKey::parse_list("-year"),
+            Some(2),
+        );
+
+        let paths = results
+            .into_iter()
+            .map(|(path, _)| path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec!["d.flac", "c.flac"]);
+    }
+}
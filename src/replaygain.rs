@@ -0,0 +1,385 @@
+use colored::*;
+use indicatif::{ProgressBar, ProgressStyle};
+use lofty::file::AudioFile;
+use lofty::probe::Probe;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::fs_utils::{canonicalize_path, glob_pattern, relative_path_string};
+use crate::index::{configure_thread_pool, is_audio_file, AUDIO_EXTENSIONS};
+use crate::normalize::{album_dir_of, is_disc_dir};
+use crate::tag::{self, ReplayGain};
+
+/// Options controlling a `replaygain` run.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ReplayGainOptions {
+    /// Report the computed gains without writing tags.
+    pub dry_run: bool,
+    /// Analyze albums even when every file already has gain tags.
+    pub force: bool,
+}
+
+/// ReplayGain 2.0 reference loudness.
+const REFERENCE_LUFS: f64 = -18.0;
+
+/// Loudness measurements of one track.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Loudness {
+    /// Integrated loudness in LUFS.
+    integrated: f64,
+    /// True peak as a linear amplitude.
+    true_peak: f64,
+    /// Duration in seconds, used to weight the album loudness.
+    duration: f64,
+}
+
+/// Reads the integrated loudness and true peak from the summary the
+/// `ebur128` filter prints when it finishes.
+fn parse_ebur128(stderr: &str) -> Option<(f64, f64)> {
+    let summary = &stderr[stderr.rfind("Summary:")?..];
+    let value = |label: &str, unit: &str| -> Option<f64> {
+        summary.lines().find_map(|line| {
+            let rest = line.trim().strip_prefix(label)?;
+            rest.trim().strip_suffix(unit)?.trim().parse().ok()
+        })
+    };
+    let integrated = value("I:", "LUFS")?;
+    let peak_dbfs = value("Peak:", "dBFS")?;
+    Some((integrated, 10f64.powf(peak_dbfs / 20.0)))
+}
+
+/// Measures a file with ffmpeg's `ebur128` filter.
+fn analyze(path: &Path) -> Result<Loudness, String> {
+    let output = Command::new("ffmpeg")
+        .arg("-nostdin")
+        .arg("-hide_banner")
+        .arg("-nostats")
+        .arg("-i")
+        .arg(path)
+        .arg("-map")
+        .arg("0:a:0")
+        .arg("-af")
+        .arg("ebur128=peak=true")
+        .arg("-f")
+        .arg("null")
+        .arg("-")
+        .output()
+        .map_err(|e| format!("failed to run ffmpeg: {}", e))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        let message = stderr.trim().lines().last().unwrap_or_default();
+        return Err(if message.is_empty() {
+            format!("ffmpeg exited with {}", output.status)
+        } else {
+            message.to_owned()
+        });
+    }
+    let (integrated, true_peak) =
+        parse_ebur128(&stderr).ok_or_else(|| "no loudness summary from ffmpeg".to_owned())?;
+    if !integrated.is_finite() {
+        return Err("too little audio to measure".to_owned());
+    }
+    let duration = Probe::open(path)
+        .and_then(|probe| probe.read())
+        .map(|file| file.properties().duration().as_secs_f64())
+        .unwrap_or(0.0);
+    Ok(Loudness {
+        integrated,
+        true_peak,
+        duration,
+    })
+}
+
+/// Combines track measurements into the loudness of the whole album: the
+/// duration-weighted mean of the tracks' power and the highest peak.
+fn album_loudness(tracks: &[Loudness]) -> Option<(f64, f64)> {
+    if tracks.is_empty() {
+        return None;
+    }
+    let total = tracks.iter().map(|track| track.duration).sum::<f64>();
+    // Without durations every track counts the same
+    let weight = |track: &Loudness| if total > 0.0 { track.duration } else { 1.0 };
+    let weights = tracks.iter().map(weight).sum::<f64>();
+    let power = tracks
+        .iter()
+        .map(|track| weight(track) * 10f64.powf(track.integrated / 10.0))
+        .sum::<f64>();
+    let peak = tracks
+        .iter()
+        .map(|track| track.true_peak)
+        .fold(0.0, f64::max);
+    Some((10.0 * (power / weights).log10(), peak))
+}
+
+/// Track and album gains of each track of an album.
+fn album_gains(tracks: &[Loudness]) -> Option<Vec<ReplayGain>> {
+    let (album_integrated, album_peak) = album_loudness(tracks)?;
+    Some(
+        tracks
+            .iter()
+            .map(|track| ReplayGain {
+                track_gain: REFERENCE_LUFS - track.integrated,
+                track_peak: track.true_peak,
+                album_gain: REFERENCE_LUFS - album_integrated,
+                album_peak,
+            })
+            .collect(),
+    )
+}
+
+/// Measures the tracks of one album and returns their gains, in order.
+pub(crate) fn measure_album(files: &[PathBuf]) -> Result<Vec<ReplayGain>, String> {
+    let tracks = files
+        .par_iter()
+        .map(|file| analyze(file).map_err(|e| format!("{}: {}", file.display(), e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    album_gains(&tracks).ok_or_else(|| "no tracks to measure".to_owned())
+}
+
+/// Album unit of a file: the directory containing it, or the one above a
+/// disc directory so every disc of an album shares its gain.
+pub(crate) fn album_key(path: &Path) -> PathBuf {
+    album_dir_of(path)
+        .map(Path::to_path_buf)
+        .unwrap_or_default()
+}
+
+/// Audio files of the album in `dir`, including those of its disc
+/// directories.
+pub(crate) fn album_files(dir: &Path) -> Vec<PathBuf> {
+    let entries = |dir: &Path| {
+        fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .map(|entry| entry.path())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default()
+    };
+    let mut files = Vec::new();
+    for path in entries(dir) {
+        if path.is_file() && is_audio_file(&path) {
+            files.push(path);
+        } else if path.is_dir()
+            && is_disc_dir(&path.file_name().unwrap_or_default().to_string_lossy())
+        {
+            files.extend(
+                entries(&path)
+                    .into_iter()
+                    .filter(|path| path.is_file() && is_audio_file(path)),
+            );
+        }
+    }
+    files.sort();
+    files
+}
+
+/// Analyzes the tracks and albums below `directory` and writes ReplayGain
+/// tags, or `R128_*` tags to Opus files.
+pub fn replaygain(directory: &str, options: ReplayGainOptions) {
+    configure_thread_pool();
+    let root = canonicalize_path(directory);
+    println!(
+        "{} {}",
+        "🔊".bright_blue(),
+        format!("Analyzing loudness in: {}", root.display())
+            .bright_white()
+            .bold()
+    );
+
+    if Command::new("ffmpeg").arg("-version").output().is_err() {
+        eprintln!(
+            "{} ffmpeg is required to measure loudness but could not be run",
+            "✗".red().bold()
+        );
+        return;
+    }
+
+    let pattern = glob_pattern(&root, AUDIO_EXTENSIONS);
+    let mut albums = BTreeMap::<PathBuf, Vec<PathBuf>>::new();
+    for entry in globwalk::glob(&pattern)
+        .expect("glob error")
+        .filter_map(Result::ok)
+    {
+        let path = entry.into_path();
+        albums.entry(album_key(&path)).or_default().push(path);
+    }
+    let album_count = albums.len();
+    if !options.force {
+        albums.retain(|_, files| !files.iter().all(|file| tag::has_gain_tags(file)));
+    }
+    for files in albums.values_mut() {
+        files.sort();
+    }
+    let files = albums.values().flatten().collect::<Vec<_>>();
+    println!(
+        "{} Albums to analyze: {} ({} already tagged)",
+        "⚡".bright_blue(),
+        albums.len().to_string().bright_yellow().bold(),
+        (album_count - albums.len()).to_string().bright_white()
+    );
+    if files.is_empty() {
+        return;
+    }
+
+    let pb = ProgressBar::new(files.len() as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}")
+            .unwrap()
+            .progress_chars("█▉▊▋▌▍▎▏  "),
+    );
+    pb.set_message("🔊 Measuring loudness...");
+    let measured = files
+        .par_iter()
+        .map(|path| {
+            let result = analyze(path);
+            pb.inc(1);
+            ((*path).clone(), result)
+        })
+        .collect::<BTreeMap<_, _>>();
+    pb.finish_and_clear();
+
+    let display = |path: &Path| {
+        relative_path_string(&root, path).unwrap_or_else(|_| path.display().to_string())
+    };
+    let mut written = 0;
+    let mut failed_albums = 0;
+    for (album, files) in &albums {
+        let mut tracks = Vec::with_capacity(files.len());
+        for file in files {
+            match &measured[file] {
+                Ok(loudness) => tracks.push(*loudness),
+                Err(e) => eprintln!("{} {}: {}", "✗".red().bold(), display(file), e),
+            }
+        }
+        // Album gain from a partial album would be wrong for every track
+        if tracks.len() < files.len() {
+            failed_albums += 1;
+            eprintln!(
+                "{} Skipping {}: not every track could be measured",
+                "⚠️".bright_yellow(),
+                display(album)
+            );
+            continue;
+        }
+        let gains = match album_gains(&tracks) {
+            Some(gains) => gains,
+            None => continue,
+        };
+        for (file, gains) in files.iter().zip(&gains) {
+            if options.dry_run {
+                println!(
+                    "{} {} {}: track {:+.2} dB (peak {:.6}), album {:+.2} dB (peak {:.6})",
+                    "🔍".bright_blue(),
+                    "DRY RUN:".bright_yellow().bold(),
+                    display(file),
+                    gains.track_gain,
+                    gains.track_peak,
+                    gains.album_gain,
+                    gains.album_peak
+                );
+                continue;
+            }
+            match tag::write_gains(file, gains) {
+                Ok(()) => written += 1,
+                Err(e) => eprintln!("{} {}: {:?}", "✗".red().bold(), display(file), e),
+            }
+        }
+    }
+
+    if !options.dry_run {
+        println!(
+            "{} Tagged {} files",
+            "💾".bright_green(),
+            written.to_string().bright_yellow().bold()
+        );
+    }
+    if failed_albums > 0 {
+        println!(
+            "{} {} albums were skipped because of errors",
+            "⚠️".bright_yellow(),
+            failed_albums.to_string().bright_red().bold()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use tempfile::tempdir;
+
+    use super::{album_files, album_key, album_loudness, parse_ebur128, Loudness};
+
+    #[test]
+    fn disc_directories_share_their_album() {
+        assert_eq!(
+            album_key(Path::new("/m/A/B/CD2/01.flac")),
+            PathBuf::from("/m/A/B")
+        );
+        assert_eq!(
+            album_key(Path::new("/m/A/B/01.flac")),
+            PathBuf::from("/m/A/B")
+        );
+
+        let dir = tempdir().expect("tempdir");
+        for name in [
+            "Disc 1/01.flac",
+            "Disc 2/01.flac",
+            "Extras/01.flac",
+            "cover.jpg",
+        ] {
+            let path = dir.path().join(name);
+            fs::create_dir_all(path.parent().expect("parent")).expect("mkdirs");
+            fs::write(&path, b"audio").expect("write");
+        }
+        assert_eq!(
+            album_files(dir.path()),
+            vec![
+                dir.path().join("Disc 1/01.flac"),
+                dir.path().join("Disc 2/01.flac")
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_ebur128_summary() {
+        let stderr = "[Parsed_ebur128_0 @ 0x1] t: 2.9 TARGET:-23 LUFS M: -20.1 S: -120.7 I: -19.0 LUFS\n\
+            [Parsed_ebur128_0 @ 0x1] Summary:\n\n  Integrated loudness:\n    I:         -14.3 LUFS\n    \
+            Threshold: -24.5 LUFS\n\n  Loudness range:\n    LRA:         6.1 LU\n\n  True peak:\n    \
+            Peak:        -0.5 dBFS\n";
+        let (integrated, peak) = parse_ebur128(stderr).expect("summary");
+
+        assert_eq!(integrated, -14.3);
+        assert!((peak - 0.944061).abs() < 1e-6);
+        assert_eq!(parse_ebur128("no summary here"), None);
+    }
+
+    #[test]
+    fn album_loudness_weights_tracks_by_duration() {
+        let track = |integrated, true_peak, duration| Loudness {
+            integrated,
+            true_peak,
+            duration,
+        };
+        let (same, peak) =
+            album_loudness(&[track(-12.0, 0.5, 100.0), track(-12.0, 0.9, 300.0)]).expect("album");
+        assert!((same + 12.0).abs() < 1e-9);
+        assert_eq!(peak, 0.9);
+
+        // Equal durations give the mean power of the two tracks, a long quiet track pulls it down
+        let (even, _) =
+            album_loudness(&[track(-10.0, 0.5, 100.0), track(-20.0, 0.5, 100.0)]).expect("album");
+        let (weighted, _) =
+            album_loudness(&[track(-10.0, 0.5, 100.0), track(-20.0, 0.5, 900.0)]).expect("album");
+        assert!((even + 12.596).abs() < 1e-3);
+        assert!(weighted < even);
+        assert_eq!(album_loudness(&[]), None);
+    }
+}
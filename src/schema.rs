@@ -0,0 +1,765 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path};
+use std::time::UNIX_EPOCH;
+
+/// File name of the index written at the root of an indexed directory.
+pub const INDEX_FILE_NAME: &str = "index.json";
+
+/// Schema version written by this release of loot.
+pub const INDEX_VERSION: u32 = 2;
+
+/// Oldest schema version that can still be migrated to `INDEX_VERSION`.
+const OLDEST_SUPPORTED_VERSION: u32 = 1;
+
+/// Track fields that every entry written by the current schema contains.
+///
+/// Cached entries missing any of these were written before the field was
+/// introduced and are re-extracted instead of being trusted.
+pub const REQUIRED_TRACK_FIELDS: &[&str] = &["compilation", "mtime", "size", "album", "artist"];
+
+/// Returns the JSON names of every `Track` field, as serde reads them.
+pub fn track_field_names() -> &'static [&'static str] {
+    let mut names: &'static [&'static str] = &[];
+    // Serde hands the field list to the deserializer before reading anything
+    let _ = Track::deserialize(FieldNames(&mut names));
+    names
+}
+
+/// Deserializer that records the field names of the struct asked for.
+struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de> serde::Deserializer<'de> for FieldNames<'_> {
+    type Error = serde::de::value::Error;
+
+    fn deserialize_any<V: serde::de::Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(serde::de::Error::custom("only field names are read"))
+    }
+
+    fn deserialize_struct<V: serde::de::Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        self.deserialize_any(visitor)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+        ignored_any
+    }
+}
+
+/// Upgrades the raw JSON object of an index by one schema version.
+type Migration = fn(&mut Map<String, Value>);
+
+/// Migrations indexed by the version they upgrade from, starting at
+/// `OLDEST_SUPPORTED_VERSION`. Bumping `INDEX_VERSION` requires appending one.
+const MIGRATIONS: &[Migration] = &[add_field_set];
+
+/// Version 2 records which field set the tracks were extracted with. Earlier
+/// indexes only ever contained the minimal set.
+fn add_field_set(index: &mut Map<String, Value>) {
+    index.insert("fields".to_owned(), Value::from("minimal"));
+}
+
+/// Which track fields an index run extracts.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldSet {
+    /// Tags, duration in seconds, bitrate, size and mtime.
+    #[default]
+    Minimal,
+    /// Everything in `Minimal` plus audio properties, identifiers,
+    /// ReplayGain values, sort names, lyrics and artwork details.
+    Full,
+}
+
+/// File system attributes compared between runs to detect changed files.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileStamp {
+    /// Modification time in whole seconds, all that older indexes recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime_ns: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inode: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ctime_ns: Option<u64>,
+}
+
+/// A file whose metadata could not be extracted.
+///
+/// The file is skipped on later runs until its stamp changes.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FailedFile {
+    /// Why extraction failed.
+    pub error: String,
+    /// State of the file when it failed.
+    #[serde(flatten)]
+    pub stamp: FileStamp,
+}
+
+impl FileStamp {
+    /// Reads the stamp of a file from its metadata.
+    pub fn from_metadata(metadata: &fs::Metadata) -> FileStamp {
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+        let mut stamp = FileStamp {
+            mtime: mtime.map(|mtime| mtime.as_secs()),
+            mtime_ns: mtime.and_then(|mtime| u64::try_from(mtime.as_nanos()).ok()),
+            size: Some(metadata.len()),
+            ..Default::default()
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            stamp.inode = Some(metadata.ino());
+            stamp.ctime_ns = u64::try_from(metadata.ctime())
+                .ok()
+                .map(|secs| secs * 1_000_000_000 + metadata.ctime_nsec() as u64);
+        }
+        stamp
+    }
+
+    /// Returns whether a file stamped `current` is unchanged since this
+    /// stamp was stored.
+    ///
+    /// Entries from older indexes without a nanosecond mtime fall back to
+    /// whole seconds. Inode and change time are only compared with
+    /// `check_ctime`, since copying or restoring a library changes them.
+    pub fn is_unchanged(&self, current: &FileStamp, check_ctime: bool) -> bool {
+        let same_mtime = match (self.mtime_ns, current.mtime_ns) {
+            (Some(stored), Some(current)) => stored == current,
+            _ => self.mtime.is_some() && self.mtime == current.mtime,
+        };
+        same_mtime
+            && self.size == current.size
+            && (!check_ctime || (self.inode == current.inode && self.ctime_ns == current.ctime_ns))
+    }
+}
+
+/// Top-level structure of an `index.json` file.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Index {
+    /// Schema version the index was written with.
+    pub version: u32,
+    /// Field set the most recent run extracted.
+    #[serde(default)]
+    pub fields: FieldSet,
+    /// Tracks keyed by their path relative to the indexed directory.
+    #[serde(default)]
+    pub tracks: BTreeMap<String, Track>,
+    /// Albums keyed by the relative directory holding their tracks.
+    ///
+    /// Derived from `tracks` on every run.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub albums: BTreeMap<String, Album>,
+    /// Album artists keyed by name. Derived from `albums` on every run.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub artists: BTreeMap<String, Artist>,
+    /// Files that could not be read, keyed by relative path.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub errors: BTreeMap<String, FailedFile>,
+    /// Tracks whose cached entries lack fields in `REQUIRED_TRACK_FIELDS` or
+    /// could not be read at all. Unreadable entries are left out of `tracks`.
+    ///
+    /// Computed on load and never written back.
+    #[serde(skip)]
+    pub stale: BTreeSet<String>,
+}
+
+/// Metadata recorded for a single audio file.
+///
+/// Everything except `compilation`, `album` and `artist` is optional and is
+/// omitted from the JSON when the file does not provide it.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Track {
+    /// Whether the file is flagged as part of a compilation.
+    #[serde(default)]
+    pub compilation: bool,
+    /// Modification time in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+    /// Modification time in nanoseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime_ns: Option<u64>,
+    /// File size in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Inode number, on Unix.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inode: Option<u64>,
+    /// Inode change time in nanoseconds since the Unix epoch, on Unix.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ctime_ns: Option<u64>,
+    /// SHA-256 of the file contents, recorded by `loot verify --checksums`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// Chromaprint fingerprint of the first two minutes of audio, in the
+    /// compressed base64 form `fpcalc` prints, recorded by `loot fingerprint`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// Duration in whole seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    /// Overall bitrate in kbps.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bitrate: Option<u32>,
+    /// Album title, empty when untagged.
+    #[serde(default)]
+    pub album: String,
+    /// Track artist, empty when untagged.
+    #[serde(default)]
+    pub artist: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album_artist: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub composer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub genre: Option<String>,
+    /// Release year taken from the tag date.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub year: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track_number: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track_number_total: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk_number: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk_number_total: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub performer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub work: Option<String>,
+    /// Content group (grouping) tag.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grouping: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+
+    // The remaining fields are only extracted with `FieldSet::Full`.
+    /// Precise duration in milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    /// Sample rate in Hz.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<u32>,
+    /// Bits per sample, only reported by lossless formats.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bit_depth: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channels: Option<u8>,
+    /// Audio codec, e.g. `flac`, `mp3`, `aac`, `alac` or `opus`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec: Option<String>,
+    /// Container format, e.g. `flac`, `mp4` or `ogg`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub musicbrainz_recording_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub musicbrainz_release_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub musicbrainz_artist_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub isrc: Option<String>,
+    /// ReplayGain track gain in dB.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replaygain_track_gain: Option<f64>,
+    /// ReplayGain track peak as a linear sample value.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replaygain_track_peak: Option<f64>,
+    /// ReplayGain album gain in dB.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replaygain_album_gain: Option<f64>,
+    /// ReplayGain album peak as a linear sample value.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replaygain_album_peak: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artist_sort: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album_sort: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album_artist_sort: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title_sort: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub composer_sort: Option<String>,
+    /// Whether the tag carries lyrics.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub has_lyrics: Option<bool>,
+    /// Whether the tag carries embedded artwork.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub has_picture: Option<bool>,
+    /// Width in pixels of the first embedded picture (PNG and JPEG only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub picture_width: Option<u32>,
+    /// Height in pixels of the first embedded picture (PNG and JPEG only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub picture_height: Option<u32>,
+}
+
+/// Aggregate view of the tracks in one album directory.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Album {
+    /// Most common album title among the tracks.
+    pub title: String,
+    /// Most common album artist, falling back to the track artist.
+    pub album_artist: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub year: Option<u16>,
+    /// Whether any track is flagged as part of a compilation.
+    #[serde(default)]
+    pub compilation: bool,
+    /// Total duration in whole seconds.
+    pub duration: u64,
+    pub track_count: usize,
+    /// Number of discs, from the disc total tags or the disc numbers seen.
+    pub disc_count: u32,
+    /// Track numbers absent from each disc, keyed by disc number.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub missing_tracks: BTreeMap<u32, Vec<u32>>,
+    /// Relative path of the cover image in the album directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cover: Option<String>,
+    /// Relative paths of the album's tracks.
+    pub tracks: Vec<String>,
+}
+
+/// Aggregate view of the albums credited to one album artist.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Artist {
+    /// Keys into `Index::albums`.
+    pub albums: Vec<String>,
+    pub track_count: usize,
+    /// Total duration in whole seconds.
+    pub duration: u64,
+}
+
+/// Errors produced while loading or validating an index.
+#[derive(Debug)]
+pub enum IndexError {
+    /// The index file could not be read.
+    Io(std::io::Error),
+    /// The index file is not valid JSON or does not match the schema.
+    Parse(serde_json::Error),
+    /// The index has no version or one this release cannot read or migrate.
+    UnsupportedVersion(u32),
+    /// A track key is not a plain path relative to the index root.
+    InvalidPath(String),
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexError::Io(e) => write!(f, "{}", e),
+            IndexError::Parse(e) => write!(f, "{}", e),
+            IndexError::UnsupportedVersion(version) => {
+                write!(f, "unsupported index version {}", version)
+            }
+            IndexError::InvalidPath(path) => write!(f, "invalid track path: {}", path),
+        }
+    }
+}
+
+impl std::error::Error for IndexError {}
+
+impl From<std::io::Error> for IndexError {
+    fn from(err: std::io::Error) -> IndexError {
+        IndexError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for IndexError {
+    fn from(err: serde_json::Error) -> IndexError {
+        IndexError::Parse(err)
+    }
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Index {
+            version: INDEX_VERSION,
+            fields: FieldSet::default(),
+            tracks: BTreeMap::new(),
+            albums: BTreeMap::new(),
+            artists: BTreeMap::new(),
+            errors: BTreeMap::new(),
+            stale: BTreeSet::new(),
+        }
+    }
+}
+
+impl Track {
+    /// Returns the stamp recorded when the track was extracted.
+    pub fn stamp(&self) -> FileStamp {
+        FileStamp {
+            mtime: self.mtime,
+            mtime_ns: self.mtime_ns,
+            size: self.size,
+            inode: self.inode,
+            ctime_ns: self.ctime_ns,
+        }
+    }
+
+    /// Records `stamp` as the state of the file the track was read from.
+    pub fn set_stamp(&mut self, stamp: FileStamp) {
+        self.mtime = stamp.mtime;
+        self.mtime_ns = stamp.mtime_ns;
+        self.size = stamp.size;
+        self.inode = stamp.inode;
+        self.ctime_ns = stamp.ctime_ns;
+    }
+}
+
+impl Index {
+    /// Reads and validates an index file.
+    pub fn load(path: &Path) -> Result<Index, IndexError> {
+        Index::from_json(&fs::read_to_string(path)?)
+    }
+
+    /// Parses, migrates and validates an index from its JSON representation.
+    pub fn from_json(json: &str) -> Result<Index, IndexError> {
+        let mut value: Value = serde_json::from_str(json)?;
+        if let Some(object) = value.as_object_mut() {
+            migrate(object, MIGRATIONS)?;
+        }
+        let mut stale = stale_tracks(&value);
+        // Tracks are read one by one so a malformed entry only costs its own
+        // re-extraction instead of the whole index
+        let tracks = value
+            .as_object_mut()
+            .and_then(|object| object.remove("tracks"))
+            .unwrap_or_default();
+        let mut index: Index = serde_json::from_value(value)?;
+        if let Value::Object(tracks) = tracks {
+            for (path, track) in tracks {
+                match serde_json::from_value(track) {
+                    Ok(track) => {
+                        index.tracks.insert(path, track);
+                    }
+                    Err(_) => {
+                        stale.insert(path);
+                    }
+                }
+            }
+        }
+        index.stale = stale;
+        index.validate()?;
+        Ok(index)
+    }
+
+    /// Serializes the index as pretty-printed JSON.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    /// Checks that the index can be used by this version of loot.
+    pub fn validate(&self) -> Result<(), IndexError> {
+        if self.version != INDEX_VERSION {
+            return Err(IndexError::UnsupportedVersion(self.version));
+        }
+        match self
+            .tracks
+            .keys()
+            .chain(self.errors.keys())
+            .find(|path| !is_relative_track_path(path))
+        {
+            Some(path) => Err(IndexError::InvalidPath(path.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Runs `migrations` until the object's version reaches
+/// `OLDEST_SUPPORTED_VERSION + migrations.len()`.
+fn migrate(index: &mut Map<String, Value>, migrations: &[Migration]) -> Result<(), IndexError> {
+    let current = OLDEST_SUPPORTED_VERSION + migrations.len() as u32;
+    let version = index
+        .get("version")
+        .and_then(Value::as_u64)
+        .and_then(|version| u32::try_from(version).ok())
+        .unwrap_or(0);
+    if version < OLDEST_SUPPORTED_VERSION || version > current {
+        return Err(IndexError::UnsupportedVersion(version));
+    }
+
+    for migration in &migrations[(version - OLDEST_SUPPORTED_VERSION) as usize..] {
+        migration(index);
+    }
+    index.insert("version".to_owned(), Value::from(current));
+    Ok(())
+}
+
+fn stale_tracks(index: &Value) -> BTreeSet<String> {
+    index
+        .get("tracks")
+        .and_then(Value::as_object)
+        .map(|tracks| {
+            tracks
+                .iter()
+                .filter(|(_, track)| {
+                    REQUIRED_TRACK_FIELDS
+                        .iter()
+                        .any(|field| track.get(field).is_none())
+                })
+                .map(|(path, _)| path.clone())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn is_relative_track_path(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Map, Value};
+
+    use super::{
+        migrate, track_field_names, FailedFile, FieldSet, FileStamp, Index, IndexError, Migration,
+        Track, INDEX_VERSION, MIGRATIONS, OLDEST_SUPPORTED_VERSION,
+    };
+
+    fn object(value: Value) -> Map<String, Value> {
+        value.as_object().expect("object").clone()
+    }
+
+    #[test]
+    fn lists_track_field_names() {
+        let names = track_field_names();
+
+        assert!(names.contains(&"albumArtist"));
+        assert!(names.contains(&"pictureHeight"));
+        assert!(!names.contains(&"album_artist"));
+    }
+
+    #[test]
+    fn file_stamps_detect_sub_second_size_and_ctime_changes() {
+        let stored = FileStamp {
+            mtime: Some(10),
+            mtime_ns: Some(10_250_000_000),
+            size: Some(100),
+            inode: Some(7),
+            ctime_ns: Some(10_250_000_000),
+        };
+
+        assert!(stored.is_unchanged(&stored, true));
+        let retagged = FileStamp {
+            mtime_ns: Some(10_750_000_000),
+            ..stored
+        };
+        assert!(!stored.is_unchanged(&retagged, false));
+        let resized = FileStamp {
+            size: Some(101),
+            ..stored
+        };
+        assert!(!stored.is_unchanged(&resized, false));
+        let replaced = FileStamp {
+            inode: Some(8),
+            ..stored
+        };
+        assert!(stored.is_unchanged(&replaced, false));
+        assert!(!stored.is_unchanged(&replaced, true));
+
+        let legacy = FileStamp {
+            mtime: Some(10),
+            size: Some(100),
+            ..Default::default()
+        };
+        assert!(legacy.is_unchanged(&retagged, false));
+        assert!(!legacy.is_unchanged(&FileStamp::default(), false));
+    }
+
+    #[test]
+    fn serializes_tracks_with_camel_case_keys_and_skips_missing_values() {
+        let track = Track {
+            album: "Album".to_owned(),
+            album_artist: Some("Artist".to_owned()),
+            track_number: Some(3),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            serde_json::to_value(&track).expect("json"),
+            json!({
+                "compilation": false,
+                "album": "Album",
+                "artist": "",
+                "albumArtist": "Artist",
+                "trackNumber": 3
+            })
+        );
+    }
+
+    #[test]
+    fn from_json_fills_defaults_for_missing_fields() {
+        let index = Index::from_json(
+            r#"{ "version": 2, "tracks": { "Artist/Album/track.flac": { "mtime": 10 } } }"#,
+        )
+        .expect("valid index");
+
+        let track = &index.tracks["Artist/Album/track.flac"];
+        assert_eq!(track.mtime, Some(10));
+        assert_eq!(track.album, "");
+        assert!(!track.compilation);
+    }
+
+    #[test]
+    fn validate_rejects_newer_versions() {
+        let json = format!(r#"{{ "version": {}, "tracks": {{}} }}"#, INDEX_VERSION + 1);
+
+        assert!(matches!(
+            Index::from_json(&json),
+            Err(IndexError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn from_json_migrates_version_one_indexes_to_the_minimal_field_set() {
+        let index = Index::from_json(r#"{ "version": 1, "tracks": {} }"#).expect("valid index");
+
+        assert_eq!(index.version, INDEX_VERSION);
+        assert_eq!(index.fields, FieldSet::Minimal);
+    }
+
+    #[test]
+    fn from_json_rejects_missing_versions() {
+        assert!(matches!(
+            Index::from_json(r#"{ "tracks": {} }"#),
+            Err(IndexError::UnsupportedVersion(0))
+        ));
+    }
+
+    #[test]
+    fn from_json_marks_tracks_missing_required_fields_as_stale() {
+        let index = Index::from_json(
+            r#"{ "version": 1, "tracks": {
+                "old.flac": { "mtime": 10, "album": "A", "artist": "B" },
+                "new.flac": { "compilation": false, "mtime": 10, "size": 5, "album": "A", "artist": "B" }
+            } }"#,
+        )
+        .expect("valid index");
+
+        assert_eq!(index.tracks.len(), 2);
+        assert!(index.stale.contains("old.flac"));
+        assert!(!index.stale.contains("new.flac"));
+    }
+
+    #[test]
+    fn from_json_marks_malformed_tracks_as_stale() {
+        let index = Index::from_json(
+            r#"{ "version": 2, "tracks": {
+                "bad.flac": { "mtime": "bad-data" },
+                "good.flac": { "compilation": false, "mtime": 10, "size": 5, "album": "A", "artist": "B" }
+            } }"#,
+        )
+        .expect("valid index");
+
+        assert!(index.stale.contains("bad.flac"));
+        assert!(!index.tracks.contains_key("bad.flac"));
+        assert_eq!(index.tracks["good.flac"].mtime, Some(10));
+    }
+
+    #[test]
+    fn migrations_reach_the_current_version() {
+        assert_eq!(
+            OLDEST_SUPPORTED_VERSION + MIGRATIONS.len() as u32,
+            INDEX_VERSION
+        );
+    }
+
+    #[test]
+    fn migrate_applies_each_migration_in_order() {
+        fn add_albums(index: &mut Map<String, Value>) {
+            index.insert("albums".to_owned(), json!({}));
+        }
+        fn rename_tracks(index: &mut Map<String, Value>) {
+            let tracks = index.remove("tracks").unwrap_or_default();
+            index.insert("songs".to_owned(), tracks);
+        }
+        let migrations: &[Migration] = &[add_albums, rename_tracks];
+
+        let mut index = object(json!({ "version": 2, "tracks": {} }));
+        migrate(&mut index, migrations).expect("migrated");
+        assert_eq!(index, object(json!({ "version": 3, "songs": {} })));
+
+        let mut index = object(json!({ "version": 1, "tracks": {} }));
+        migrate(&mut index, migrations).expect("migrated");
+        assert_eq!(
+            index,
+            object(json!({ "version": 3, "albums": {}, "songs": {} }))
+        );
+    }
+
+    #[test]
+    fn migrate_rejects_versions_newer_than_the_last_migration() {
+        let mut index = object(json!({ "version": 2, "tracks": {} }));
+
+        assert!(matches!(
+            migrate(&mut index, &[]),
+            Err(IndexError::UnsupportedVersion(2))
+        ));
+    }
+
+    #[test]
+    fn serializes_failed_files_with_their_stamp() {
+        let mut index = Index::default();
+        index.errors.insert(
+            "broken.flac".to_owned(),
+            FailedFile {
+                error: "bad header".to_owned(),
+                stamp: FileStamp {
+                    mtime: Some(10),
+                    size: Some(4),
+                    ..Default::default()
+                },
+            },
+        );
+
+        let value: Value = serde_json::from_str(&index.to_json().expect("json")).expect("value");
+
+        assert_eq!(
+            value["errors"],
+            json!({ "broken.flac": { "error": "bad header", "mtime": 10, "size": 4 } })
+        );
+        assert_eq!(
+            Index::from_json(&value.to_string()).expect("parse").errors,
+            index.errors
+        );
+    }
+
+    #[test]
+    fn validate_rejects_paths_outside_the_index_root() {
+        for path in ["/abs/track.flac", "../track.flac", ""] {
+            let mut index = Index::default();
+            index.tracks.insert(path.to_owned(), Track::default());
+
+            assert!(matches!(index.validate(), Err(IndexError::InvalidPath(_))));
+        }
+    }
+}
@@ -0,0 +1,619 @@
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Transaction};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::Path;
+
+use crate::aggregate::{album_key, build_albums, build_artists};
+use crate::schema::{Album, FailedFile, FieldSet, FileStamp, Track};
+
+/// File name of the SQLite index written at the root of an indexed directory.
+pub const INDEX_DB_FILE_NAME: &str = "index.sqlite";
+
+/// Version of the table layout, stored in `PRAGMA user_version`.
+///
+/// Databases with a different non-zero version are dropped and rebuilt,
+/// except version 3 ones, whose albums are regrouped.
+const SCHEMA_VERSION: i64 = 4;
+
+/// Last version keying albums by their parent directory, before disc
+/// directories became part of the album above them.
+const PARENT_KEYS_VERSION: i64 = 3;
+
+const CREATE_TABLES: &str = "
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS tracks (
+        path TEXT PRIMARY KEY,
+        album_key TEXT NOT NULL,
+        mtime INTEGER,
+        mtime_ns INTEGER,
+        size INTEGER,
+        inode INTEGER,
+        ctime_ns INTEGER,
+        duration INTEGER,
+        bitrate INTEGER,
+        compilation INTEGER NOT NULL,
+        album TEXT NOT NULL,
+        artist TEXT NOT NULL,
+        album_artist TEXT,
+        title TEXT,
+        genre TEXT,
+        year INTEGER,
+        track_number INTEGER,
+        disk_number INTEGER,
+        codec TEXT,
+        sample_rate INTEGER,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS tracks_album_key ON tracks (album_key);
+    CREATE INDEX IF NOT EXISTS tracks_artist ON tracks (artist);
+    CREATE INDEX IF NOT EXISTS tracks_album_artist ON tracks (album_artist);
+    CREATE INDEX IF NOT EXISTS tracks_album ON tracks (album);
+    CREATE INDEX IF NOT EXISTS tracks_genre ON tracks (genre);
+    CREATE INDEX IF NOT EXISTS tracks_year ON tracks (year);
+    CREATE INDEX IF NOT EXISTS tracks_codec ON tracks (codec);
+    CREATE TABLE IF NOT EXISTS albums (
+        key TEXT PRIMARY KEY,
+        title TEXT NOT NULL,
+        album_artist TEXT NOT NULL,
+        year INTEGER,
+        compilation INTEGER NOT NULL,
+        duration INTEGER NOT NULL,
+        track_count INTEGER NOT NULL,
+        disc_count INTEGER NOT NULL,
+        cover TEXT,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS albums_album_artist ON albums (album_artist);
+    CREATE INDEX IF NOT EXISTS albums_year ON albums (year);
+    CREATE TABLE IF NOT EXISTS artists (
+        name TEXT PRIMARY KEY,
+        track_count INTEGER NOT NULL,
+        duration INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS errors (
+        path TEXT PRIMARY KEY,
+        error TEXT NOT NULL,
+        data TEXT NOT NULL
+    );
+";
+
+const DROP_TABLES: &str = "
+    DROP TABLE IF EXISTS meta;
+    DROP TABLE IF EXISTS tracks;
+    DROP TABLE IF EXISTS albums;
+    DROP TABLE IF EXISTS artists;
+    DROP TABLE IF EXISTS errors;
+";
+
+/// Index updates produced by one `loot index` run.
+pub struct Changes<'a> {
+    /// Discard every existing row before applying `updated`.
+    pub rebuild: bool,
+    /// Field set the updated tracks were extracted with.
+    pub fields: FieldSet,
+    /// Relative paths of tracks that no longer exist.
+    pub removed: &'a HashSet<String>,
+    /// New or re-extracted tracks keyed by relative path.
+    pub updated: &'a HashMap<String, Track>,
+    /// Files that could not be read, keyed by relative path.
+    pub failed: &'a HashMap<String, FailedFile>,
+}
+
+/// Opens the index database, creating or upgrading its tables as needed.
+///
+/// Dry runs never modify the file: an existing database is opened read-only
+/// and a missing one is replaced by an empty in-memory database.
+pub fn open(path: &Path, dry_run: bool) -> rusqlite::Result<Connection> {
+    if dry_run && path.exists() {
+        return Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY);
+    }
+    let mut db = if dry_run {
+        Connection::open_in_memory()?
+    } else {
+        Connection::open(path)?
+    };
+
+    let version: i64 = db.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version == PARENT_KEYS_VERSION {
+        regroup_albums(&mut db, path.parent().unwrap_or(Path::new("")))?;
+        db.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    } else if version != SCHEMA_VERSION {
+        if version != 0 {
+            db.execute_batch(DROP_TABLES)?;
+        }
+        db.execute_batch(CREATE_TABLES)?;
+        db.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    }
+    Ok(db)
+}
+
+/// Recomputes the album key of every track and the albums and artists built
+/// from them, keeping the tracks and their checksums and fingerprints.
+fn regroup_albums(db: &mut Connection, root: &Path) -> rusqlite::Result<()> {
+    let tx = db.transaction()?;
+    let paths = {
+        let mut statement = tx.prepare("SELECT path FROM tracks")?;
+        let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+        rows.collect::<rusqlite::Result<Vec<_>>>()?
+    };
+    let mut album_keys = BTreeSet::new();
+    for path in &paths {
+        let key = album_key(path);
+        tx.execute(
+            "UPDATE tracks SET album_key = ?2 WHERE path = ?1",
+            params![path, key],
+        )?;
+        album_keys.insert(key);
+    }
+    tx.execute_batch("DELETE FROM albums; DELETE FROM artists;")?;
+    let artists = update_albums(&tx, root, &album_keys)?;
+    update_artists(&tx, &artists)?;
+    tx.commit()
+}
+
+/// Returns the field set recorded by the last run, or `None` when no run has
+/// written the database.
+pub fn stored_fields(db: &Connection) -> rusqlite::Result<Option<FieldSet>> {
+    let value: Option<String> = db
+        .query_row("SELECT value FROM meta WHERE key = 'fields'", [], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    Ok(value.map(|value| match value.as_str() {
+        "full" => FieldSet::Full,
+        _ => FieldSet::Minimal,
+    }))
+}
+
+/// Loads the stored file stamp of every indexed track.
+pub fn load_stamps(db: &Connection) -> rusqlite::Result<BTreeMap<String, FileStamp>> {
+    let mut statement =
+        db.prepare("SELECT path, mtime, mtime_ns, size, inode, ctime_ns FROM tracks")?;
+    let rows = statement.query_map([], |row| {
+        let column = |index| -> rusqlite::Result<Option<u64>> {
+            Ok(row.get::<_, Option<i64>>(index)?.map(|value| value as u64))
+        };
+        let stamp = FileStamp {
+            mtime: column(1)?,
+            mtime_ns: column(2)?,
+            size: column(3)?,
+            inode: column(4)?,
+            ctime_ns: column(5)?,
+        };
+        Ok((row.get(0)?, stamp))
+    })?;
+    rows.collect()
+}
+
+/// Loads the files recorded as unreadable.
+pub fn load_errors(db: &Connection) -> rusqlite::Result<BTreeMap<String, FailedFile>> {
+    let mut statement = db.prepare("SELECT path, data FROM errors")?;
+    let rows = statement.query_map([], |row| {
+        Ok((row.get(0)?, from_json(&row.get::<_, String>(1)?)?))
+    })?;
+    rows.collect()
+}
+
+/// Loads every indexed track.
+pub fn load_tracks(db: &Connection) -> rusqlite::Result<BTreeMap<String, Track>> {
+    load_tracks_where(db, "1 = 1", [])
+}
+
+/// Writes `changes` in one transaction and returns the number of indexed tracks.
+///
+/// Only albums containing changed tracks, and the artists of those albums,
+/// are recomputed.
+pub fn apply_changes(
+    db: &mut Connection,
+    root: &Path,
+    changes: &Changes,
+) -> rusqlite::Result<usize> {
+    let tx = db.transaction()?;
+
+    if changes.rebuild {
+        tx.execute_batch(
+            "DELETE FROM tracks; DELETE FROM albums; DELETE FROM artists; DELETE FROM errors;",
+        )?;
+    }
+
+    let mut album_keys = BTreeSet::new();
+    // A file that fails to read is no longer a usable track
+    for path in changes.removed.iter().chain(changes.failed.keys()) {
+        tx.execute("DELETE FROM tracks WHERE path = ?1", params![path])?;
+        tx.execute("DELETE FROM errors WHERE path = ?1", params![path])?;
+        album_keys.insert(album_key(path));
+    }
+    for (path, track) in changes.updated {
+        upsert_track(&tx, path, track)?;
+        tx.execute("DELETE FROM errors WHERE path = ?1", params![path])?;
+        album_keys.insert(album_key(path));
+    }
+    for (path, failed) in changes.failed {
+        tx.execute(
+            "INSERT INTO errors (path, error, data) VALUES (?1, ?2, ?3)",
+            params![path, failed.error, to_json(failed)?],
+        )?;
+    }
+
+    let artists = update_albums(&tx, root, &album_keys)?;
+    update_artists(&tx, &artists)?;
+
+    tx.execute(
+        "INSERT OR REPLACE INTO meta (key, value) VALUES ('fields', ?1)",
+        params![match changes.fields {
+            FieldSet::Minimal => "minimal",
+            FieldSet::Full => "full",
+        }],
+    )?;
+
+    let count: i64 = tx.query_row("SELECT COUNT(*) FROM tracks", [], |row| row.get(0))?;
+    tx.commit()?;
+    Ok(count as usize)
+}
+
+/// Applies `update` to existing tracks with the value stored for their path,
+/// for fields that are kept only in the `data` column.
+pub fn update_tracks<V, F: Fn(&mut Track, &V)>(
+    db: &mut Connection,
+    updates: &BTreeMap<String, V>,
+    update: F,
+) -> rusqlite::Result<()> {
+    let tx = db.transaction()?;
+    for (path, value) in updates {
+        let data = tx
+            .query_row(
+                "SELECT data FROM tracks WHERE path = ?1",
+                params![path],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+        if let Some(data) = data {
+            let mut track: Track = from_json(&data)?;
+            update(&mut track, value);
+            tx.execute(
+                "UPDATE tracks SET data = ?2 WHERE path = ?1",
+                params![path, to_json(&track)?],
+            )?;
+        }
+    }
+    tx.commit()
+}
+
+fn upsert_track(tx: &Transaction, path: &str, track: &Track) -> rusqlite::Result<()> {
+    tx.execute(
+        "INSERT OR REPLACE INTO tracks (
+            path, album_key, mtime, mtime_ns, size, inode, ctime_ns, duration, bitrate,
+            compilation, album, artist, album_artist, title, genre, year, track_number,
+            disk_number, codec, sample_rate, data
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+            ?18, ?19, ?20, ?21)",
+        params![
+            path,
+            album_key(path),
+            track.mtime.map(|value| value as i64),
+            track.mtime_ns.map(|value| value as i64),
+            track.size.map(|value| value as i64),
+            track.inode.map(|value| value as i64),
+            track.ctime_ns.map(|value| value as i64),
+            track.duration.map(|value| value as i64),
+            track.bitrate,
+            track.compilation,
+            track.album,
+            track.artist,
+            track.album_artist,
+            track.title,
+            track.genre,
+            track.year,
+            track.track_number,
+            track.disk_number,
+            track.codec,
+            track.sample_rate,
+            to_json(track)?,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Recomputes the given albums and returns the album artists they touched,
+/// both before and after the update.
+fn update_albums(
+    tx: &Transaction,
+    root: &Path,
+    album_keys: &BTreeSet<String>,
+) -> rusqlite::Result<BTreeSet<String>> {
+    let mut artists = BTreeSet::new();
+    for key in album_keys {
+        let previous_artist: Option<String> = tx
+            .query_row(
+                "SELECT album_artist FROM albums WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        artists.extend(previous_artist);
+
+        let tracks = load_tracks_where(tx, "album_key = ?1", [key])?;
+        match build_albums(root, &tracks).remove(key) {
+            Some(album) => {
+                artists.insert(album.album_artist.clone());
+                upsert_album(tx, key, &album)?;
+            }
+            None => {
+                tx.execute("DELETE FROM albums WHERE key = ?1", params![key])?;
+            }
+        }
+    }
+    Ok(artists)
+}
+
+fn upsert_album(tx: &Transaction, key: &str, album: &Album) -> rusqlite::Result<()> {
+    tx.execute(
+        "INSERT OR REPLACE INTO albums (
+            key, title, album_artist, year, compilation, duration, track_count, disc_count, cover, data
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+            key,
+            album.title,
+            album.album_artist,
+            album.year,
+            album.compilation,
+            album.duration as i64,
+            album.track_count as i64,
+            album.disc_count,
+            album.cover,
+            to_json(album)?,
+        ],
+    )?;
+    Ok(())
+}
+
+fn update_artists(tx: &Transaction, names: &BTreeSet<String>) -> rusqlite::Result<()> {
+    for name in names {
+        let mut statement = tx.prepare("SELECT key, data FROM albums WHERE album_artist = ?1")?;
+        let albums = statement
+            .query_map(params![name], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    from_json(&row.get::<_, String>(1)?)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<BTreeMap<String, Album>>>()?;
+
+        match build_artists(&albums).remove(name) {
+            Some(artist) => {
+                tx.execute(
+                    "INSERT OR REPLACE INTO artists (name, track_count, duration, data)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        name,
+                        artist.track_count as i64,
+                        artist.duration as i64,
+                        to_json(&artist)?,
+                    ],
+                )?;
+            }
+            None => {
+                tx.execute("DELETE FROM artists WHERE name = ?1", params![name])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn load_tracks_where<P: rusqlite::Params>(
+    db: &Connection,
+    condition: &str,
+    params: P,
+) -> rusqlite::Result<BTreeMap<String, Track>> {
+    let mut statement = db.prepare(&format!(
+        "SELECT path, data FROM tracks WHERE {}",
+        condition
+    ))?;
+    let rows = statement.query_map(params, |row| {
+        Ok((row.get(0)?, from_json(&row.get::<_, String>(1)?)?))
+    })?;
+    rows.collect()
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> rusqlite::Result<String> {
+    serde_json::to_string(value).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(json: &str) -> rusqlite::Result<T> {
+    serde_json::from_str(json).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use tempfile::tempdir;
+
+    use super::{
+        apply_changes, load_errors, load_stamps, load_tracks, open, stored_fields, Changes,
+        PARENT_KEYS_VERSION,
+    };
+    use crate::schema::{FailedFile, FieldSet, Track};
+
+    fn track(album: &str, mtime: u64) -> Track {
+        Track {
+            album: album.to_owned(),
+            album_artist: Some("Artist".to_owned()),
+            mtime: Some(mtime),
+            duration: Some(60),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn applies_incremental_changes() {
+        let dir = tempdir().expect("tempdir");
+        let mut db = open(&dir.path().join("index.sqlite"), false).expect("open");
+        assert_eq!(stored_fields(&db).expect("fields"), None);
+
+        let updated = HashMap::from([
+            ("artist/one/01.flac".to_owned(), track("One", 10)),
+            ("artist/one/02.flac".to_owned(), track("One", 11)),
+            ("artist/two/01.flac".to_owned(), track("Two", 12)),
+        ]);
+        let changes = Changes {
+            rebuild: false,
+            fields: FieldSet::Full,
+            removed: &HashSet::new(),
+            updated: &updated,
+            failed: &HashMap::new(),
+        };
+        assert_eq!(
+            apply_changes(&mut db, dir.path(), &changes).expect("apply"),
+            3
+        );
+        assert_eq!(stored_fields(&db).expect("fields"), Some(FieldSet::Full));
+
+        let removed = HashSet::from(["artist/two/01.flac".to_owned()]);
+        let updated = HashMap::from([("artist/one/02.flac".to_owned(), track("One", 20))]);
+        let changes = Changes {
+            rebuild: false,
+            fields: FieldSet::Full,
+            removed: &removed,
+            updated: &updated,
+            failed: &HashMap::new(),
+        };
+        assert_eq!(
+            apply_changes(&mut db, dir.path(), &changes).expect("apply"),
+            2
+        );
+
+        let stamps = load_stamps(&db).expect("stamps");
+        assert_eq!(stamps["artist/one/02.flac"].mtime, Some(20));
+        assert!(!stamps.contains_key("artist/two/01.flac"));
+        assert_eq!(load_tracks(&db).expect("tracks").len(), 2);
+
+        let albums: i64 = db
+            .query_row("SELECT COUNT(*) FROM albums", [], |row| row.get(0))
+            .expect("count");
+        assert_eq!(albums, 1);
+        let (track_count, duration): (i64, i64) = db
+            .query_row(
+                "SELECT track_count, duration FROM artists WHERE name = 'Artist'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .expect("artist");
+        assert_eq!((track_count, duration), (2, 120));
+    }
+
+    #[test]
+    fn records_failed_files_until_they_are_read() {
+        let dir = tempdir().expect("tempdir");
+        let mut db = open(&dir.path().join("index.sqlite"), false).expect("open");
+        let path = "artist/one/01.flac".to_owned();
+
+        let updated = HashMap::from([(path.clone(), track("One", 10))]);
+        let changes = Changes {
+            rebuild: false,
+            fields: FieldSet::Minimal,
+            removed: &HashSet::new(),
+            updated: &updated,
+            failed: &HashMap::new(),
+        };
+        apply_changes(&mut db, dir.path(), &changes).expect("apply");
+
+        let failed = HashMap::from([(
+            path.clone(),
+            FailedFile {
+                error: "truncated".to_owned(),
+                ..Default::default()
+            },
+        )]);
+        let changes = Changes {
+            updated: &HashMap::new(),
+            failed: &failed,
+            ..changes
+        };
+        assert_eq!(
+            apply_changes(&mut db, dir.path(), &changes).expect("apply"),
+            0
+        );
+        assert_eq!(load_errors(&db).expect("errors")[&path].error, "truncated");
+        let albums: i64 = db
+            .query_row("SELECT COUNT(*) FROM albums", [], |row| row.get(0))
+            .expect("count");
+        assert_eq!(albums, 0);
+
+        let changes = Changes {
+            updated: &updated,
+            failed: &HashMap::new(),
+            ..changes
+        };
+        apply_changes(&mut db, dir.path(), &changes).expect("apply");
+        assert!(load_errors(&db).expect("errors").is_empty());
+    }
+
+    #[test]
+    fn regroups_disc_directories_of_older_databases() {
+        let dir = tempdir().expect("tempdir");
+        let path = dir.path().join("index.sqlite");
+        let mut db = open(&path, false).expect("open");
+        let updated = HashMap::from([
+            ("artist/one/CD1/01.flac".to_owned(), track("One", 10)),
+            ("artist/one/CD2/01.flac".to_owned(), track("One", 11)),
+        ]);
+        let changes = Changes {
+            rebuild: false,
+            fields: FieldSet::Minimal,
+            removed: &HashSet::new(),
+            updated: &updated,
+            failed: &HashMap::new(),
+        };
+        apply_changes(&mut db, dir.path(), &changes).expect("apply");
+        // Key albums by parent directory, as older versions did
+        db.execute_batch(
+            "UPDATE tracks SET album_key = substr(path, 1, length(path) - 8);
+             DELETE FROM albums;
+             INSERT INTO albums (key, title, album_artist, compilation, duration, track_count,
+                 disc_count, data)
+             VALUES ('artist/one/CD1', 'One', 'Artist', 0, 60, 1, 1, '{}'),
+                 ('artist/one/CD2', 'One', 'Artist', 0, 60, 1, 1, '{}');",
+        )
+        .expect("downgrade");
+        db.pragma_update(None, "user_version", PARENT_KEYS_VERSION)
+            .expect("version");
+        drop(db);
+
+        let db = open(&path, false).expect("reopen");
+
+        assert_eq!(load_tracks(&db).expect("tracks").len(), 2);
+        let keys = db
+            .prepare("SELECT key FROM albums")
+            .expect("prepare")
+            .query_map([], |row| row.get::<_, String>(0))
+            .expect("query")
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .expect("keys");
+        assert_eq!(keys, vec!["artist/one"]);
+        let track_keys: i64 = db
+            .query_row(
+                "SELECT COUNT(*) FROM tracks WHERE album_key = 'artist/one'",
+                [],
+                |row| row.get(0),
+            )
+            .expect("count");
+        assert_eq!(track_keys, 2);
+    }
+
+    #[test]
+    fn dry_run_without_database_does_not_create_file() {
+        let dir = tempdir().expect("tempdir");
+        let path = dir.path().join("index.sqlite");
+
+        let db = open(&path, true).expect("open");
+
+        assert!(load_stamps(&db).expect("stamps").is_empty());
+        assert!(!path.exists());
+    }
+}
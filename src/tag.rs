@@ -1,14 +1,37 @@
+use std::fs::File;
 use std::path::Path;
 
 use lofty::{
     config::{ParseOptions, ParsingMode, WriteOptions},
-    file::TaggedFileExt,
+    file::{AudioFile, TaggedFileExt},
+    ogg::OpusFile,
     probe::Probe,
     tag::{ItemKey, Tag, TagExt},
 };
 
 use crate::error::{AppError, Result};
 
+/// ReplayGain 2.0 values: gains in dB relative to -18 LUFS and linear peaks.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: f64,
+    pub track_peak: f64,
+    pub album_gain: f64,
+    pub album_peak: f64,
+}
+
+/// Vorbis comment keys of the ReplayGain tags, which Opus files should not carry.
+const REPLAYGAIN_KEYS: &[&str] = &[
+    "REPLAYGAIN_TRACK_GAIN",
+    "REPLAYGAIN_TRACK_PEAK",
+    "REPLAYGAIN_ALBUM_GAIN",
+    "REPLAYGAIN_ALBUM_PEAK",
+];
+
+/// Difference between the Opus R128 (-23 LUFS) and ReplayGain 2.0 (-18 LUFS)
+/// reference levels.
+const R128_OFFSET_DB: f64 = -5.0;
+
 /// Reads the primary tag from an audio file.
 pub fn read(path: &Path, read_cover: bool) -> Result<Tag> {
     let parsing_options = ParseOptions::new()
@@ -16,14 +39,14 @@ pub fn read(path: &Path, read_cover: bool) -> Result<Tag> {
         .read_cover_art(read_cover);
     let tagged_file = Probe::open(path)?.options(parsing_options).read()?;
     let tag = tagged_file.primary_tag().ok_or(AppError::ReadTagError)?;
-    return Ok(tag.to_owned());
+    Ok(tag.to_owned())
 }
 
 /// Copies supported tag fields from `src` into an already-created destination file.
 pub fn copy(src: &Path, dest: &Path, copy_cover: bool) -> Result<()> {
     let src_tag = read(src, copy_cover)?;
 
-    let mut dest_file = Probe::open(&dest)?.read()?;
+    let mut dest_file = Probe::open(dest)?.read()?;
     let dest_tag = dest_file.primary_tag_mut().ok_or(AppError::WriteTagError)?;
     for item in src_tag.items() {
         dest_tag.push(item.clone());
@@ -50,3 +73,231 @@ pub fn copy(src: &Path, dest: &Path, copy_cover: bool) -> Result<()> {
         Ok(o) => Ok(o),
     }
 }
+
+/// Formats a ReplayGain gain as an Opus `R128_*_GAIN` value: a Q7.8
+/// fixed-point number of dB relative to -23 LUFS.
+pub fn r128_gain(replaygain_db: f64) -> String {
+    let value = ((replaygain_db + R128_OFFSET_DB) * 256.0).round();
+    (value.clamp(f64::from(i16::MIN), f64::from(i16::MAX)) as i16).to_string()
+}
+
+fn is_opus(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| extension.eq_ignore_ascii_case("opus"))
+        .unwrap_or(false)
+}
+
+fn read_opus(path: &Path) -> Result<OpusFile> {
+    let mut file = File::open(path)?;
+    Ok(OpusFile::read_from(
+        &mut file,
+        ParseOptions::new().read_properties(false),
+    )?)
+}
+
+/// Returns whether a file carries track and album gain tags in the form its
+/// format uses: `R128_*` for Opus and ReplayGain for everything else.
+pub fn has_gain_tags(path: &Path) -> bool {
+    if is_opus(path) {
+        read_opus(path)
+            .map(|opus| {
+                let comments = opus.vorbis_comments();
+                comments.get("R128_TRACK_GAIN").is_some()
+                    && comments.get("R128_ALBUM_GAIN").is_some()
+            })
+            .unwrap_or(false)
+    } else {
+        read(path, false)
+            .map(|tag| {
+                tag.get_string(ItemKey::ReplayGainTrackGain).is_some()
+                    && tag.get_string(ItemKey::ReplayGainAlbumGain).is_some()
+            })
+            .unwrap_or(false)
+    }
+}
+
+/// Writes gain tags in the form the file's format uses.
+pub fn write_gains(path: &Path, gains: &ReplayGain) -> Result<()> {
+    if is_opus(path) {
+        let mut opus = read_opus(path)?;
+        let comments = opus.vorbis_comments_mut();
+        for key in REPLAYGAIN_KEYS {
+            comments.remove(key).for_each(drop);
+        }
+        comments.insert("R128_TRACK_GAIN".to_owned(), r128_gain(gains.track_gain));
+        comments.insert("R128_ALBUM_GAIN".to_owned(), r128_gain(gains.album_gain));
+        return comments
+            .save_to_path(path, WriteOptions::new())
+            .map_err(|_| AppError::WriteTagError);
+    }
+
+    let mut tagged_file = Probe::open(path)?.read()?;
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+    let tag = tagged_file
+        .primary_tag_mut()
+        .ok_or(AppError::WriteTagError)?;
+    tag.insert_text(
+        ItemKey::ReplayGainTrackGain,
+        format!("{:.2} dB", gains.track_gain),
+    );
+    tag.insert_text(
+        ItemKey::ReplayGainTrackPeak,
+        format!("{:.6}", gains.track_peak),
+    );
+    tag.insert_text(
+        ItemKey::ReplayGainAlbumGain,
+        format!("{:.2} dB", gains.album_gain),
+    );
+    tag.insert_text(
+        ItemKey::ReplayGainAlbumPeak,
+        format!("{:.6}", gains.album_peak),
+    );
+    tag.save_to_path(path, WriteOptions::new())
+        .map_err(|_| AppError::WriteTagError)
+}
+
+/// Parses a ReplayGain gain value such as `-6.54 dB`.
+pub(crate) fn parse_gain(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let number = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+    number.trim().parse().ok()
+}
+
+/// Parses a ReplayGain peak value such as `0.988312`.
+pub(crate) fn parse_peak(value: &str) -> Option<f64> {
+    value.trim().parse().ok()
+}
+
+/// Parses an Opus `R128_*_GAIN` value into a ReplayGain gain in dB.
+fn parse_r128(value: &str) -> Option<f64> {
+    let value = value.trim().parse::<i16>().ok()?;
+    Some(f64::from(value) / 256.0 - R128_OFFSET_DB)
+}
+
+/// Reads the track or album gain of a file, as a ReplayGain gain in dB, from
+/// whichever gain tags its format uses.
+pub fn read_gain(path: &Path, album: bool) -> Option<f64> {
+    if is_opus(path) {
+        let opus = read_opus(path).ok()?;
+        let key = if album {
+            "R128_ALBUM_GAIN"
+        } else {
+            "R128_TRACK_GAIN"
+        };
+        return opus.vorbis_comments().get(key).and_then(parse_r128);
+    }
+    let key = if album {
+        ItemKey::ReplayGainAlbumGain
+    } else {
+        ItemKey::ReplayGainTrackGain
+    };
+    read(path, false).ok()?.get_string(key).and_then(parse_gain)
+}
+
+/// Replaces the gain tags ffmpeg copies into a transcoded Opus file with
+/// `R128_*` tags relative to the output gain set in its header. ReplayGain
+/// tags from FLAC sources and `R128_*` tags from Opus sources are both read.
+pub fn convert_opus_gains(path: &Path, output_gain_db: f64) -> Result<()> {
+    let mut opus = read_opus(path)?;
+    let comments = opus.vorbis_comments_mut();
+    let gain = |replaygain_key: &str, r128_key: &str| {
+        comments
+            .get(replaygain_key)
+            .and_then(parse_gain)
+            .or_else(|| comments.get(r128_key).and_then(parse_r128))
+    };
+    let track_gain = gain("REPLAYGAIN_TRACK_GAIN", "R128_TRACK_GAIN");
+    let album_gain = gain("REPLAYGAIN_ALBUM_GAIN", "R128_ALBUM_GAIN");
+    let has_replaygain = REPLAYGAIN_KEYS
+        .iter()
+        .any(|key| comments.get(key).is_some());
+    if !has_replaygain && output_gain_db == 0.0 {
+        return Ok(());
+    }
+    for key in REPLAYGAIN_KEYS
+        .iter()
+        .chain(&["R128_TRACK_GAIN", "R128_ALBUM_GAIN"])
+    {
+        comments.remove(key).for_each(drop);
+    }
+    if let Some(gain) = track_gain {
+        comments.insert(
+            "R128_TRACK_GAIN".to_owned(),
+            r128_gain(gain - output_gain_db),
+        );
+    }
+    if let Some(gain) = album_gain {
+        comments.insert(
+            "R128_ALBUM_GAIN".to_owned(),
+            r128_gain(gain - output_gain_db),
+        );
+    }
+    comments
+        .save_to_path(path, WriteOptions::new())
+        .map_err(|_| AppError::WriteTagError)
+}
+
+/// Adjusts the ReplayGain tags of a file whose audio was amplified by
+/// `applied_db`, so players that honor them do not apply the gain twice.
+pub fn offset_gains(path: &Path, applied_db: f64) -> Result<()> {
+    let mut tagged_file = Probe::open(path)?.read()?;
+    let tag = match tagged_file.primary_tag_mut() {
+        Some(tag) => tag,
+        None => return Ok(()),
+    };
+    let scale = 10f64.powf(applied_db / 20.0);
+    let mut changed = false;
+    for key in [ItemKey::ReplayGainTrackGain, ItemKey::ReplayGainAlbumGain] {
+        if let Some(gain) = tag.get_string(key).and_then(parse_gain) {
+            tag.insert_text(key, format!("{:.2} dB", gain - applied_db));
+            changed = true;
+        }
+    }
+    for key in [ItemKey::ReplayGainTrackPeak, ItemKey::ReplayGainAlbumPeak] {
+        if let Some(peak) = tag.get_string(key).and_then(parse_peak) {
+            tag.insert_text(key, format!("{:.6}", (peak * scale).min(1.0)));
+            changed = true;
+        }
+    }
+    if !changed {
+        return Ok(());
+    }
+    tag.save_to_path(path, WriteOptions::new())
+        .map_err(|_| AppError::WriteTagError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_gain, parse_peak, parse_r128, r128_gain};
+
+    #[test]
+    fn parses_replaygain_values() {
+        assert_eq!(parse_gain("-6.54 dB"), Some(-6.54));
+        assert_eq!(parse_gain("+1.20 db"), Some(1.2));
+        assert_eq!(parse_gain("3"), Some(3.0));
+        assert_eq!(parse_gain("loud"), None);
+        assert_eq!(parse_peak(" 0.988312 "), Some(0.988312));
+    }
+
+    #[test]
+    fn r128_gain_is_q7_8_relative_to_minus_23_lufs() {
+        // A track at -18 LUFS needs no ReplayGain but is 5 dB above R128
+        assert_eq!(r128_gain(0.0), "-1280");
+        assert_eq!(r128_gain(5.0), "0");
+        assert_eq!(r128_gain(-3.21), "-2102");
+        assert_eq!(r128_gain(200.0), "32767");
+    }
+
+    #[test]
+    fn parses_r128_gain_back_to_replaygain() {
+        assert_eq!(parse_r128("-1280"), Some(0.0));
+        assert_eq!(parse_r128(&r128_gain(-3.5)), Some(-3.5));
+        assert_eq!(parse_r128("loud"), None);
+    }
+}
@@ -0,0 +1,481 @@
+use colored::*;
+use lofty::config::WriteOptions;
+use lofty::file::TaggedFileExt;
+use lofty::probe::Probe;
+use lofty::tag::{ItemKey, ItemValue, Tag, TagExt, TagItem, TagType};
+use regex::Regex;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use crate::fs_utils::{canonicalize_path, glob_pattern, relative_path_string};
+use crate::query::{load_library_tracks, select, Filter};
+
+/// Extensions of the files `loot tag` edits, the same formats `normalize` handles.
+const TAG_EXTENSIONS: &[&str] = &["flac", "opus", "m4a", "mp3"];
+
+/// Friendly field names accepted in addition to format-specific keys.
+const FIELD_NAMES: &[(&str, ItemKey)] = &[
+    ("title", ItemKey::TrackTitle),
+    ("artist", ItemKey::TrackArtist),
+    ("album", ItemKey::AlbumTitle),
+    ("albumartist", ItemKey::AlbumArtist),
+    ("track", ItemKey::TrackNumber),
+    ("tracknumber", ItemKey::TrackNumber),
+    ("tracktotal", ItemKey::TrackTotal),
+    ("disc", ItemKey::DiscNumber),
+    ("discnumber", ItemKey::DiscNumber),
+    ("disctotal", ItemKey::DiscTotal),
+    ("date", ItemKey::RecordingDate),
+    ("year", ItemKey::Year),
+    ("genre", ItemKey::Genre),
+    ("composer", ItemKey::Composer),
+    ("comment", ItemKey::Comment),
+    ("compilation", ItemKey::FlagCompilation),
+    ("label", ItemKey::Label),
+];
+
+/// A tag field, named as the user wrote it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub key: ItemKey,
+}
+
+impl Field {
+    /// Resolves a friendly name such as `albumartist`, or a Vorbis, ID3v2 or
+    /// MP4 key such as `MUSICBRAINZ_ALBUMID`, `TPE2` or `aART`.
+    pub fn parse(name: &str) -> std::result::Result<Field, String> {
+        let lower = name.to_lowercase();
+        let key = FIELD_NAMES
+            .iter()
+            .find(|(friendly, _)| *friendly == lower)
+            .map(|(_, key)| *key)
+            .or_else(|| ItemKey::from_key(TagType::VorbisComments, &name.to_uppercase()))
+            .or_else(|| ItemKey::from_key(TagType::Id3v2, name))
+            .or_else(|| ItemKey::from_key(TagType::Mp4Ilst, name))
+            .ok_or_else(|| format!("unknown field `{}`", name))?;
+        Ok(Field {
+            name: name.to_owned(),
+            key,
+        })
+    }
+}
+
+/// One change applied to the tag of every selected file.
+#[derive(Clone, Debug)]
+pub enum Edit {
+    /// Replaces the values of `to` with those of `from`.
+    Copy { from: Field, to: Field },
+    /// Moves the values of `from` to `to`.
+    Rename { from: Field, to: Field },
+    /// Replaces regex matches in each value of `field`.
+    Replace {
+        field: Field,
+        pattern: Regex,
+        replacement: String,
+    },
+    /// Replaces the values of `field` with `value`.
+    Set { field: Field, value: String },
+    /// Removes every value of `field`.
+    Remove { field: Field },
+}
+
+/// Splits `FIELD=VALUE` at the first `=`.
+fn split_assignment(assignment: &str) -> std::result::Result<(&str, &str), String> {
+    assignment
+        .split_once('=')
+        .filter(|(field, _)| !field.is_empty())
+        .ok_or_else(|| format!("expected FIELD=VALUE, got `{}`", assignment))
+}
+
+impl Edit {
+    /// Parses `FIELD=VALUE`.
+    pub fn set(assignment: &str) -> std::result::Result<Edit, String> {
+        let (field, value) = split_assignment(assignment)?;
+        Ok(Edit::Set {
+            field: Field::parse(field)?,
+            value: value.to_owned(),
+        })
+    }
+
+    pub fn remove(field: &str) -> std::result::Result<Edit, String> {
+        Ok(Edit::Remove {
+            field: Field::parse(field)?,
+        })
+    }
+
+    /// Parses `OLD=NEW`.
+    pub fn rename(assignment: &str) -> std::result::Result<Edit, String> {
+        let (from, to) = split_assignment(assignment)?;
+        Ok(Edit::Rename {
+            from: Field::parse(from)?,
+            to: Field::parse(to)?,
+        })
+    }
+
+    /// Parses `FROM=TO`.
+    pub fn copy(assignment: &str) -> std::result::Result<Edit, String> {
+        let (from, to) = split_assignment(assignment)?;
+        Ok(Edit::Copy {
+            from: Field::parse(from)?,
+            to: Field::parse(to)?,
+        })
+    }
+
+    pub fn replace(
+        field: &str,
+        pattern: &str,
+        replacement: &str,
+    ) -> std::result::Result<Edit, String> {
+        Ok(Edit::Replace {
+            field: Field::parse(field)?,
+            pattern: Regex::new(pattern).map_err(|e| e.to_string())?,
+            replacement: replacement.to_owned(),
+        })
+    }
+
+    fn fields(&self) -> Vec<&Field> {
+        match self {
+            Edit::Copy { from, to } | Edit::Rename { from, to } => vec![from, to],
+            Edit::Replace { field, .. } | Edit::Set { field, .. } | Edit::Remove { field } => {
+                vec![field]
+            }
+        }
+    }
+}
+
+/// Options controlling a `loot tag` run.
+#[derive(Clone, Debug, Default)]
+pub struct TagEditOptions {
+    /// Only edit files matching this glob, relative to the directory.
+    pub glob: Option<String>,
+    /// Only edit files matching this query against the directory's index.
+    pub query: Option<String>,
+    /// Print the changes without writing them.
+    pub dry_run: bool,
+}
+
+/// The values of one field before and after editing a file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    pub field: String,
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+fn strings(tag: &Tag, key: ItemKey) -> Vec<String> {
+    tag.get_strings(key).map(str::to_owned).collect()
+}
+
+/// Fails when the tag's format cannot store `field`, which `Tag::push` would
+/// otherwise silently drop.
+fn check_supported(tag: &Tag, field: &Field) -> std::result::Result<(), String> {
+    let mut scratch = Tag::new(tag.tag_type());
+    if scratch.push(TagItem::new(field.key, ItemValue::Text(String::new()))) {
+        Ok(())
+    } else {
+        Err(format!(
+            "{:?} tags cannot store {}",
+            tag.tag_type(),
+            field.name
+        ))
+    }
+}
+
+fn replace_strings(
+    tag: &mut Tag,
+    field: &Field,
+    values: Vec<String>,
+) -> std::result::Result<(), String> {
+    check_supported(tag, field)?;
+    tag.remove_key(field.key);
+    for value in values {
+        tag.push(TagItem::new(field.key, ItemValue::Text(value)));
+    }
+    Ok(())
+}
+
+/// Applies `edits` in order and returns the fields whose values changed.
+///
+/// Fails without writing anything when a field cannot be stored in the tag,
+/// so a rename never drops its source values.
+pub fn apply(tag: &mut Tag, edits: &[Edit]) -> std::result::Result<Vec<Change>, String> {
+    let mut touched = Vec::<&Field>::new();
+    for field in edits.iter().flat_map(Edit::fields) {
+        if !touched.iter().any(|seen| seen.key == field.key) {
+            touched.push(field);
+        }
+    }
+    let before = touched
+        .iter()
+        .map(|field| strings(tag, field.key))
+        .collect::<Vec<_>>();
+
+    for edit in edits {
+        match edit {
+            Edit::Copy { from, to } => {
+                let values = strings(tag, from.key);
+                if !values.is_empty() {
+                    replace_strings(tag, to, values)?;
+                }
+            }
+            Edit::Rename { from, to } => {
+                check_supported(tag, to)?;
+                let values = tag.take_strings(from.key).collect::<Vec<_>>();
+                if !values.is_empty() {
+                    replace_strings(tag, to, values)?;
+                }
+            }
+            Edit::Replace {
+                field,
+                pattern,
+                replacement,
+            } => {
+                let values = strings(tag, field.key)
+                    .iter()
+                    .map(|value| {
+                        pattern
+                            .replace_all(value, replacement.as_str())
+                            .into_owned()
+                    })
+                    .collect();
+                replace_strings(tag, field, values)?;
+            }
+            Edit::Set { field, value } => {
+                replace_strings(tag, field, vec![value.clone()])?;
+            }
+            Edit::Remove { field } => tag.remove_key(field.key),
+        }
+    }
+
+    Ok(touched
+        .iter()
+        .zip(before)
+        .map(|(field, before)| Change {
+            field: field.name.clone(),
+            before,
+            after: strings(tag, field.key),
+        })
+        .filter(|change| change.before != change.after)
+        .collect())
+}
+
+/// Edits the primary tag of one file, creating it if needed, and returns
+/// the changes made.
+fn edit_file(
+    path: &Path,
+    edits: &[Edit],
+    dry_run: bool,
+) -> std::result::Result<Vec<Change>, String> {
+    let mut tagged_file = Probe::open(path)
+        .and_then(|probe| probe.read())
+        .map_err(|e| e.to_string())?;
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+    let tag = tagged_file
+        .primary_tag_mut()
+        .ok_or_else(|| "no tag could be created".to_owned())?;
+    let changes = apply(tag, edits)?;
+    if !changes.is_empty() && !dry_run {
+        tag.save_to_path(path, WriteOptions::new())
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(changes)
+}
+
+fn has_tag_extension(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| {
+            TAG_EXTENSIONS
+                .iter()
+                .any(|tagged| extension.to_string_lossy().eq_ignore_ascii_case(tagged))
+        })
+        .unwrap_or(false)
+}
+
+/// Finds the files below `root` selected by the glob and query options.
+fn select_files(
+    root: &Path,
+    options: &TagEditOptions,
+) -> std::result::Result<Vec<PathBuf>, String> {
+    let pattern = match &options.glob {
+        Some(glob) => format!("{}/{}", root.to_string_lossy(), glob),
+        None => glob_pattern(root, TAG_EXTENSIONS),
+    };
+    let mut files = globwalk::glob(&pattern)
+        .map_err(|e| e.to_string())?
+        .filter_map(std::result::Result::ok)
+        .map(|entry| entry.into_path())
+        .filter(|path| path.is_file() && has_tag_extension(path))
+        .collect::<BTreeSet<_>>();
+
+    if let Some(expression) = &options.query {
+        let filter = Filter::parse(expression).map_err(|e| format!("invalid query: {}", e))?;
+        let tracks = load_library_tracks(root)?;
+        let matched = select(&tracks, &filter, &[], None)
+            .into_iter()
+            .map(|(path, _)| root.join(path))
+            .collect::<BTreeSet<_>>();
+        files = files.intersection(&matched).cloned().collect();
+    }
+    Ok(files.into_iter().collect())
+}
+
+fn print_changes(display: &str, changes: &[Change]) {
+    println!("{}", display.bright_white().bold());
+    for change in changes {
+        if !change.before.is_empty() {
+            println!(
+                "  {} {}: {}",
+                "-".red(),
+                change.field,
+                change.before.join("; ").red()
+            );
+        }
+        if !change.after.is_empty() {
+            println!(
+                "  {} {}: {}",
+                "+".green(),
+                change.field,
+                change.after.join("; ").green()
+            );
+        }
+    }
+}
+
+/// Applies `edits` to the tags of the selected files in `path`, which may
+/// also be a single file, and prints what changed.
+pub fn tag(path: &str, edits: &[Edit], options: &TagEditOptions) {
+    let target = canonicalize_path(path);
+    let (root, files) = if target.is_file() {
+        let root = target.parent().unwrap_or(&target).to_path_buf();
+        (root, vec![target])
+    } else {
+        match select_files(&target, options) {
+            Ok(files) => (target, files),
+            Err(e) => {
+                eprintln!("{} {}", "✗".red().bold(), e);
+                return;
+            }
+        }
+    };
+    if options.dry_run {
+        println!(
+            "{} {} Showing changes without writing them",
+            "🔍".bright_blue(),
+            "DRY RUN:".bright_yellow().bold()
+        );
+    }
+
+    let mut changed = 0;
+    for file in &files {
+        let display =
+            relative_path_string(&root, file).unwrap_or_else(|_| file.display().to_string());
+        match edit_file(file, edits, options.dry_run) {
+            Ok(changes) if changes.is_empty() => {}
+            Ok(changes) => {
+                changed += 1;
+                print_changes(&display, &changes);
+            }
+            Err(e) => eprintln!("{} {}: {}", "✗".red().bold(), display, e),
+        }
+    }
+    println!(
+        "{} {} {} of {} files",
+        "💾".bright_green(),
+        if options.dry_run {
+            "Would change"
+        } else {
+            "Changed"
+        },
+        changed.to_string().bright_yellow().bold(),
+        files.len().to_string().bright_white()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use lofty::tag::{ItemKey, Tag, TagType};
+
+    use super::{apply, has_tag_extension, Change, Edit, Field};
+
+    fn tag() -> Tag {
+        let mut tag = Tag::new(TagType::VorbisComments);
+        tag.insert_text(ItemKey::TrackArtist, "The Band feat. Someone".to_owned());
+        tag.insert_text(ItemKey::AlbumTitle, "Album (Remastered)".to_owned());
+        tag.insert_text(ItemKey::Comment, "ripped".to_owned());
+        tag
+    }
+
+    #[test]
+    fn matches_tag_extensions_in_any_case() {
+        assert!(has_tag_extension(Path::new("Band/Album/01.FLAC")));
+        assert!(has_tag_extension(Path::new("Band/Album/01.Opus")));
+        assert!(!has_tag_extension(Path::new("Band/Album/cover.jpg")));
+    }
+
+    #[test]
+    fn resolves_friendly_and_native_field_names() {
+        assert_eq!(
+            Field::parse("AlbumArtist").expect("field").key,
+            ItemKey::AlbumArtist
+        );
+        assert_eq!(
+            Field::parse("musicbrainz_albumid").expect("field").key,
+            ItemKey::MusicBrainzReleaseId
+        );
+        assert_eq!(
+            Field::parse("TPE2").expect("field").key,
+            ItemKey::AlbumArtist
+        );
+        assert!(Field::parse("nonsense").is_err());
+        assert!(Edit::set("=value").is_err());
+        assert!(Edit::replace("title", "(", "").is_err());
+    }
+
+    #[test]
+    fn applies_edits_in_order_and_reports_changes() {
+        let mut tag = tag();
+        let edits = vec![
+            Edit::copy("artist=albumartist").expect("edit"),
+            Edit::replace("artist", r" feat\. .*$", "").expect("edit"),
+            Edit::replace("album", r" \(Remastered\)", "").expect("edit"),
+            Edit::rename("comment=description").expect("edit"),
+            Edit::set("genre=Rock").expect("edit"),
+            Edit::remove("title").expect("edit"),
+        ];
+
+        let changes = apply(&mut tag, &edits).expect("apply");
+
+        let change = |field: &str, before: &[&str], after: &[&str]| Change {
+            field: field.to_owned(),
+            before: before.iter().map(|value| value.to_string()).collect(),
+            after: after.iter().map(|value| value.to_string()).collect(),
+        };
+        assert_eq!(
+            changes,
+            vec![
+                change("artist", &["The Band feat. Someone"], &["The Band"]),
+                change("albumartist", &[], &["The Band feat. Someone"]),
+                change("album", &["Album (Remastered)"], &["Album"]),
+                change("comment", &["ripped"], &[]),
+                change("description", &[], &["ripped"]),
+                change("genre", &[], &["Rock"]),
+            ]
+        );
+        assert!(apply(&mut tag, &edits[1..3]).expect("apply").is_empty());
+    }
+
+    #[test]
+    fn fails_edits_the_tag_format_cannot_store() {
+        let mut tag = Tag::new(TagType::Id3v1);
+        tag.insert_text(ItemKey::TrackTitle, "Song".to_owned());
+
+        assert!(apply(&mut tag, &[Edit::rename("title=composer").expect("edit")]).is_err());
+        assert_eq!(tag.get_string(ItemKey::TrackTitle), Some("Song"));
+    }
+}
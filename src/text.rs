@@ -1,42 +1,311 @@
-use regex::Regex;
-use std::sync::OnceLock;
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
 
-fn slash_regex() -> &'static Regex {
-    static SLASH_REGEX: OnceLock<Regex> = OnceLock::new();
-    SLASH_REGEX.get_or_init(|| Regex::new(r#"[/\\]+"#).expect("valid slash regex"))
+/// Characters `tidy_string` removes by default.
+pub const DEFAULT_REMOVE: &str = "\"&,;.'(){}|:*!?#><-";
+
+/// Characters `tidy_string` replaces with the separator by default; runs of
+/// them become a single separator.
+pub const DEFAULT_REPLACE: &str = " ~";
+
+/// Names Windows reserves for devices, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Filesystem whose naming rules path components must satisfy.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsProfile {
+    /// Linux filesystems: anything but `/` and NUL, up to 255 bytes.
+    #[default]
+    Ext4,
+    /// FAT32 long names: no `"*/:<>?\|` or control characters, no trailing
+    /// dots or spaces, no device names, up to 255 UTF-16 units.
+    Fat32,
+    /// exFAT, with the same restrictions as FAT32 when used from Windows.
+    Exfat,
+    /// NTFS as seen through Windows, with the same restrictions as FAT32.
+    Ntfs,
 }
 
-fn remove_regex() -> &'static Regex {
-    static REMOVE_REGEX: OnceLock<Regex> = OnceLock::new();
-    REMOVE_REGEX.get_or_init(|| Regex::new(r#"["&,;.'(){}|:*!?#><-]"#).expect("valid remove regex"))
+impl FsProfile {
+    /// Returns whether the filesystem follows Windows naming rules, which
+    /// also make names case-insensitive.
+    pub fn is_windows(self) -> bool {
+        self != FsProfile::Ext4
+    }
+
+    fn is_forbidden(self, c: char) -> bool {
+        match self {
+            FsProfile::Ext4 => c == '/' || c == '\0',
+            _ => c.is_control() || "\"*/:<>?\\|".contains(c),
+        }
+    }
+
+    /// Length of `name` in the units the filesystem limits.
+    pub(crate) fn length(self, name: &str) -> usize {
+        if self.is_windows() {
+            name.encode_utf16().count()
+        } else {
+            name.len()
+        }
+    }
+
+    /// Longest name the filesystem allows.
+    pub fn max_length(self) -> usize {
+        255
+    }
+
+    /// Makes one path component valid on this filesystem: forbidden
+    /// characters become `replacement`, merging with the replacements around
+    /// them, names are truncated to `max_length` units keeping their
+    /// extension, and on Windows filesystems trailing dots and spaces are
+    /// dropped and device names are suffixed. A name left empty becomes
+    /// `replacement`.
+    pub fn sanitize_component(self, name: &str, replacement: char, max_length: usize) -> String {
+        let mut sanitized = String::with_capacity(name.len());
+        let mut replaced = false;
+        for c in name.chars() {
+            if self.is_forbidden(c) {
+                if !sanitized.ends_with(replacement) {
+                    sanitized.push(replacement);
+                }
+                replaced = true;
+            } else if !(replaced && c == replacement) {
+                sanitized.push(c);
+                replaced = false;
+            }
+        }
+
+        let max_length = max_length.min(self.max_length());
+        let mut truncated = self.truncate(&sanitized, max_length);
+        if self.is_windows() {
+            truncated = trim_trailing_dots(&truncated);
+            if is_reserved(&truncated) {
+                // Leave room for the suffix, which may no longer be needed
+                // once the stem is shorter
+                let budget =
+                    max_length.saturating_sub(self.length(replacement.encode_utf8(&mut [0; 4])));
+                truncated = trim_trailing_dots(&self.truncate(&sanitized, budget));
+                if is_reserved(&truncated) {
+                    let stem = truncated.split('.').next().unwrap_or_default().len();
+                    truncated.insert(stem, replacement);
+                }
+            }
+        }
+        if truncated.is_empty() && !name.is_empty() {
+            truncated.push(replacement);
+        }
+        truncated
+    }
+
+    /// Truncates `name` to `max_length` units, keeping short extensions such
+    /// as `.flac` intact.
+    fn truncate(self, name: &str, max_length: usize) -> String {
+        if self.length(name) <= max_length {
+            return name.to_owned();
+        }
+        let (stem, extension) = match name.rfind('.') {
+            Some(dot) if dot > 0 && name.len() - dot <= 8 => name.split_at(dot),
+            _ => (name, ""),
+        };
+        let budget = max_length.saturating_sub(self.length(extension));
+        let mut truncated = String::new();
+        for c in stem.chars() {
+            if self.length(&truncated) + self.length(c.encode_utf8(&mut [0; 4])) > budget {
+                break;
+            }
+            truncated.push(c);
+        }
+        truncated.push_str(extension);
+        truncated
+    }
 }
 
-fn replace_regex() -> &'static Regex {
-    static REPLACE_REGEX: OnceLock<Regex> = OnceLock::new();
-    REPLACE_REGEX.get_or_init(|| Regex::new(r#"[ ~]+"#).expect("valid replace regex"))
+/// Drops the trailing dots and spaces Windows strips from names.
+fn trim_trailing_dots(name: &str) -> String {
+    name.trim_end_matches(['.', ' ']).to_owned()
 }
 
-/// Converts a free-form string into the normalized file-system-safe style used
-/// by the normalization workflow.
-pub fn tidy_string(value: &str) -> String {
-    let tidied = value.trim();
-    let no_diacritics = deunicode::deunicode_with_tofu(tidied, "_").to_lowercase();
-    let escaped_slashes = slash_regex().replace_all(&no_diacritics, " ").into_owned();
-    let removed = remove_regex()
-        .replace_all(&escaped_slashes, "")
-        .into_owned();
+/// Returns whether the part of `name` before its first dot is a device name.
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default();
+    RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+}
+
+/// Articles stripped from artist names by default.
+pub const DEFAULT_ARTICLES: &[&str] = &["the"];
+
+/// What happens to a leading article of an artist name.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum ArticleMode {
+    /// `The Beatles` becomes `beatles`.
+    #[default]
+    Drop,
+    /// `The Beatles` becomes `beatles_the`.
+    Move,
+}
+
+/// How free-form tag values are turned into path components.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TidyPolicy {
+    /// Transliterate to ASCII; otherwise keep Unicode, NFC-normalized.
+    pub transliterate: bool,
+    pub lowercase: bool,
+    /// Characters dropped from names.
+    pub remove: String,
+    /// Characters replaced with `separator`. Slashes are always replaced.
+    pub replace: String,
+    pub separator: char,
+    /// Filesystem the names must be valid on.
+    pub filesystem: FsProfile,
+    /// Maximum component length, capped at the filesystem's limit.
+    pub max_length: usize,
+    /// Leading articles of artist names, matched in any case. Elided
+    /// articles such as `l'` end with their apostrophe.
+    pub articles: Vec<String>,
+    pub article_mode: ArticleMode,
+}
+
+impl Default for TidyPolicy {
+    fn default() -> Self {
+        TidyPolicy {
+            transliterate: true,
+            lowercase: true,
+            remove: DEFAULT_REMOVE.to_owned(),
+            replace: DEFAULT_REPLACE.to_owned(),
+            separator: '_',
+            filesystem: FsProfile::Ext4,
+            max_length: 255,
+            articles: DEFAULT_ARTICLES.iter().map(|a| (*a).to_owned()).collect(),
+            article_mode: ArticleMode::Drop,
+        }
+    }
+}
+
+impl TidyPolicy {
+    /// Converts a free-form string into a name following this policy.
+    pub fn tidy(&self, value: &str) -> String {
+        let tidied = value.trim();
+        let converted = if self.transliterate {
+            deunicode::deunicode_with_tofu(tidied, "_")
+        } else {
+            tidied.nfc().collect()
+        };
+        let cased = if self.lowercase {
+            converted.to_lowercase()
+        } else {
+            converted
+        };
+
+        let mut result = String::with_capacity(cased.len());
+        let mut pending_separator = false;
+        for c in cased.chars() {
+            // Runs of replaced characters, including slashes that would
+            // otherwise split the component, become one separator
+            if self.replace.contains(c) || c == '/' || c == '\\' {
+                pending_separator = true;
+                continue;
+            }
+            if self.remove.contains(c) {
+                continue;
+            }
+            if pending_separator {
+                result.push(self.separator);
+                pending_separator = false;
+            }
+            result.push(c);
+        }
+        if pending_separator {
+            result.push(self.separator);
+        }
+        result
+    }
+
+    /// Makes a complete path component, such as a file name built from
+    /// tidied parts, valid on the target filesystem.
+    pub fn component(&self, name: &str) -> String {
+        self.filesystem
+            .sanitize_component(name, self.separator, self.max_length)
+    }
+
+    fn is_article(&self, word: &str) -> bool {
+        let word = word.to_lowercase();
+        self.articles
+            .iter()
+            .any(|article| article.to_lowercase() == word)
+    }
+
+    /// Splits a leading article from `name`, returning it and the rest.
+    fn split_article<'a>(&self, name: &'a str) -> Option<(&'a str, &'a str)> {
+        let name = name.trim();
+        let elided = self.articles.iter().find_map(|article| {
+            if !article.ends_with(['\'', '’']) {
+                return None;
+            }
+            let head = name.get(..article.len())?;
+            let rest = &name[article.len()..];
+            (head.to_lowercase() == article.to_lowercase() && !rest.trim().is_empty())
+                .then_some((head, rest.trim()))
+        });
+        elided.or_else(|| {
+            let (first, rest) = name.split_once(char::is_whitespace)?;
+            (self.is_article(first) && !rest.trim().is_empty()).then_some((first, rest.trim()))
+        })
+    }
+
+    /// Splits the article from `name` as the sort name `sort` does, such as
+    /// `Beatles, The` for `The Beatles`. Returns `None` when the sort name
+    /// does not move a known article, so `La Roux` sorted as `La Roux` keeps
+    /// its name and `Bowie, David` is not mistaken for one.
+    fn split_sort_article<'a>(&self, name: &'a str, sort: &str) -> Option<(&'a str, &'a str)> {
+        let (rest, article) = sort.trim().rsplit_once(',')?;
+        let (article, rest) = (article.trim(), rest.trim());
+        let name = name.trim();
+        let head = name.get(..article.len())?;
+        let tail = name[article.len()..].trim();
+        (self.is_article(article)
+            && head.to_lowercase() == article.to_lowercase()
+            && tail.to_lowercase() == rest.to_lowercase())
+        .then_some((head, tail))
+    }
 
-    replace_regex().replace_all(&removed, "_").into_owned()
+    /// Tidies an artist name, dropping or moving its leading article. A sort
+    /// name from `ALBUMARTISTSORT` or `ARTISTSORT` decides whether there is
+    /// one; without it, the first word is checked against `articles`.
+    pub fn artist(&self, name: &str, sort: Option<&str>) -> String {
+        let split = match sort {
+            Some(sort) if !sort.trim().is_empty() => self.split_sort_article(name, sort),
+            _ => self.split_article(name),
+        };
+        match (split, self.article_mode) {
+            (None, _) => self.tidy(name),
+            (Some((_, rest)), ArticleMode::Drop) => self.tidy(rest),
+            (Some((article, rest)), ArticleMode::Move) => {
+                format!(
+                    "{}{}{}",
+                    self.tidy(rest),
+                    self.separator,
+                    self.tidy(article)
+                )
+            }
+        }
+    }
 }
 
-/// Removes a leading `the_` prefix from normalized artist directory names.
-pub fn strip_leading_the(value: &str) -> String {
-    value.strip_prefix("the_").unwrap_or(value).to_owned()
+/// Converts a free-form string into the normalized file-system-safe style used
+/// by the normalization workflow.
+pub fn tidy_string(value: &str) -> String {
+    TidyPolicy::default().tidy(value)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{strip_leading_the, tidy_string};
+    use super::{tidy_string, ArticleMode, FsProfile, TidyPolicy};
 
     #[test]
     fn tidy_string_escapes_forward_slashes() {
@@ -54,8 +323,117 @@ mod tests {
     }
 
     #[test]
-    fn strip_leading_the_removes_normalized_article_prefix() {
-        assert_eq!(strip_leading_the("the_beatles"), "beatles");
-        assert_eq!(strip_leading_the("beatles"), "beatles");
+    fn policy_can_keep_unicode_and_case() {
+        let policy = TidyPolicy {
+            transliterate: false,
+            lowercase: false,
+            ..Default::default()
+        };
+        assert_eq!(policy.tidy("Sigur Ro\u{301}s"), "Sigur_R\u{f3}s");
+        assert_eq!(policy.artist("The Band", None), "Band");
+    }
+
+    #[test]
+    fn artist_articles_are_configurable() {
+        let default = TidyPolicy::default();
+        assert_eq!(default.artist("The Beatles", None), "beatles");
+        assert_eq!(default.artist("Beatles", None), "beatles");
+
+        let policy = TidyPolicy {
+            articles: vec!["the".to_owned(), "les".to_owned(), "l'".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.artist("Les Négresses Vertes", None),
+            "negresses_vertes"
+        );
+        assert_eq!(policy.artist("L'Impératrice", None), "imperatrice");
+        assert_eq!(policy.artist("The", None), "the");
+        assert_eq!(policy.artist("Die Ärzte", None), "die_arzte");
+
+        let moved = TidyPolicy {
+            article_mode: ArticleMode::Move,
+            ..policy
+        };
+        assert_eq!(moved.artist("The Beatles", None), "beatles_the");
+    }
+
+    #[test]
+    fn sort_names_decide_the_article() {
+        let policy = TidyPolicy {
+            articles: vec!["the".to_owned(), "la".to_owned()],
+            article_mode: ArticleMode::Move,
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.artist("The Beatles", Some("Beatles, The")),
+            "beatles_the"
+        );
+        assert_eq!(policy.artist("La Roux", Some("La Roux")), "la_roux");
+        assert_eq!(
+            policy.artist("David Bowie", Some("Bowie, David")),
+            "david_bowie"
+        );
+    }
+
+    #[test]
+    fn windows_profiles_sanitize_components() {
+        assert_eq!(
+            FsProfile::Fat32.sanitize_component("What? Now: live...", '_', 255),
+            "What_ Now_ live"
+        );
+        // Replacements merge with each other and with the separators around them
+        assert_eq!(
+            FsProfile::Fat32.sanitize_component("Live: <Take 2>", ' ', 255),
+            "Live Take 2"
+        );
+        assert_eq!(
+            FsProfile::Fat32.sanitize_component("a_*?b", '_', 255),
+            "a_b"
+        );
+        assert_eq!(
+            FsProfile::Ntfs.sanitize_component("con.flac", '_', 255),
+            "con_.flac"
+        );
+        assert_eq!(
+            FsProfile::Ext4.sanitize_component("con.flac", '_', 255),
+            "con.flac"
+        );
+    }
+
+    #[test]
+    fn sanitize_component_truncates_keeping_extension() {
+        assert_eq!(
+            FsProfile::Ext4.sanitize_component("abcdefghij.flac", '_', 9),
+            "abcd.flac"
+        );
+        // FAT32 counts UTF-16 units rather than bytes
+        assert_eq!(FsProfile::Fat32.sanitize_component("ééééé", '_', 4), "éééé");
+        assert_eq!(FsProfile::Ext4.sanitize_component("ééééé", '_', 4), "éé");
+    }
+
+    #[test]
+    fn sanitize_component_keeps_device_name_suffix_within_the_limit() {
+        assert_eq!(
+            FsProfile::Ntfs.sanitize_component("con.flac", '_', 9),
+            "con_.flac"
+        );
+        // Truncation that ends on a device name makes room for the suffix
+        assert_eq!(
+            FsProfile::Fat32.sanitize_component("COM1 live", '_', 5),
+            "COM1_"
+        );
+        // A stem shortened past the device name needs no suffix
+        assert_eq!(
+            FsProfile::Ntfs.sanitize_component("con.flac", '_', 8),
+            "co.flac"
+        );
+    }
+
+    #[test]
+    fn sanitize_component_never_returns_an_empty_name() {
+        assert_eq!(FsProfile::Fat32.sanitize_component("...", '_', 255), "_");
+        assert_eq!(FsProfile::Ntfs.sanitize_component(" . ", '-', 255), "-");
+        assert_eq!(FsProfile::Ext4.sanitize_component("", '_', 255), "");
     }
 }
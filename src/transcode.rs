@@ -1,17 +1,25 @@
+use colored::*;
 use filetime::FileTime;
-use globwalk::DirEntry;
+use globwalk::GlobWalkerBuilder;
 use rayon::prelude::*;
+use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime};
 
-use crate::fs_utils::{canonicalize_path, glob_pattern, modified_time};
+use crate::dest_names::DestNames;
+use crate::fs_utils::{absolute_path, canonicalize_path, glob_pattern, modified_time};
+use crate::playlist::remap_m3u;
+use crate::replaygain::{album_files, album_key, measure_album};
 use crate::tag;
+use crate::text::TidyPolicy;
 use indicatif::{ProgressBar, ProgressStyle};
 use std::sync::Arc;
 
 /// Supported lossy output formats for the transcode workflow.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub enum TranscodeFormat {
     Aac {
         mode: AacBitrateMode,
@@ -23,13 +31,36 @@ pub enum TranscodeFormat {
     Mp3,
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
 pub enum AacBitrateMode {
+    #[default]
     Vbr,
     Cbr,
 }
 
-fn target_path(dest_path: &Path, relative: &Path, format: TranscodeFormat) -> PathBuf {
+/// Loudness normalization baked into transcoded files, for players that
+/// ignore gain tags.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GainMode {
+    #[default]
+    Off,
+    /// Apply each track's own gain.
+    Track,
+    /// Apply the gain of the album, the directory containing the track.
+    Album,
+}
+
+/// Linear output ceiling of the limiter applied when a gain boosts the audio.
+const LIMITER_CEILING: f64 = 0.98;
+
+/// Source file extensions picked up by `transcode`.
+pub const SOURCE_EXTENSIONS: &[&str] = &["flac", "opus"];
+
+/// Returns where `transcode` writes the file at `relative` below a source
+/// root, once mapped through the destination's `DestNames`.
+pub fn target_path(dest_path: &Path, relative: &Path, format: TranscodeFormat) -> PathBuf {
     match format {
         TranscodeFormat::Aac { .. } => dest_path.join(relative).with_extension("m4a"),
         TranscodeFormat::Opus { .. } => dest_path.join(relative).with_extension("opus"),
@@ -70,11 +101,106 @@ fn aac_encoder_args(mode: AacBitrateMode, bitrate_kbps: u32) -> Vec<String> {
     ]
 }
 
-fn transcode_file(source: &Path, dest: &Path, format: TranscodeFormat) -> std::io::Result<()> {
+/// ffmpeg audio filter applying `gain_db`, limited to avoid clipping when
+/// the gain is positive.
+fn gain_filter(gain_db: f64) -> String {
+    if gain_db > 0.0 {
+        format!(
+            "volume={:.2}dB,alimiter=limit={}:level=disabled",
+            gain_db, LIMITER_CEILING
+        )
+    } else {
+        format!("volume={:.2}dB", gain_db)
+    }
+}
+
+/// CRC-32 used by Ogg pages: polynomial 0x04c11db7, no reflection, zero
+/// initial value and no final xor.
+fn ogg_crc(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0u32, |crc, byte| {
+        (0..8).fold(crc ^ (u32::from(*byte) << 24), |crc, _| {
+            if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Sets the output gain field of the OpusHead header, which every Opus
+/// decoder applies, and updates the checksum of the page holding it.
+fn set_opus_output_gain(path: &Path, gain_db: f64) -> std::io::Result<()> {
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "not an Ogg Opus file");
+    let mut bytes = fs::read(path)?;
+    if bytes.len() < 27 || &bytes[..4] != b"OggS" {
+        return Err(invalid());
+    }
+    // The first page holds only the OpusHead packet
+    let segments = usize::from(bytes[26]);
+    let header_len = 27 + segments;
+    let body_len = bytes
+        .get(27..header_len)
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|size| usize::from(*size))
+        .sum::<usize>();
+    let page_len = header_len + body_len;
+    if bytes.len() < page_len || body_len < 19 || &bytes[header_len..header_len + 8] != b"OpusHead"
+    {
+        return Err(invalid());
+    }
+    let gain = (gain_db * 256.0)
+        .round()
+        .clamp(f64::from(i16::MIN), f64::from(i16::MAX)) as i16;
+    bytes[header_len + 16..header_len + 18].copy_from_slice(&gain.to_le_bytes());
+    bytes[22..26].copy_from_slice(&[0; 4]);
+    let crc = ogg_crc(&bytes[..page_len]);
+    bytes[22..26].copy_from_slice(&crc.to_le_bytes());
+    fs::write(path, bytes)
+}
+
+/// Decodes `source` with `gain_db` applied into a float WAV file for
+/// encoders that cannot filter audio themselves.
+fn apply_gain_to_wav(source: &Path, dest: &Path, gain_db: f64) -> io::Result<()> {
+    let status = std::process::Command::new("ffmpeg")
+        .arg("-y")
+        .arg("-loglevel")
+        .arg("quiet")
+        .arg("-i")
+        .arg(source)
+        .arg("-map")
+        .arg("0:a:0")
+        .arg("-af")
+        .arg(gain_filter(gain_db))
+        .arg("-c:a")
+        .arg("pcm_f32le")
+        .arg("-f")
+        .arg("wav")
+        .arg(dest)
+        .status()?;
+    if !status.success() {
+        fs::remove_file(dest).ok();
+        return Err(io::Error::other(format!(
+            "ffmpeg exited with {} applying gain",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Transcodes `source` to `dest`, baking in `gain_db` when it is given.
+fn transcode_file(
+    source: &Path,
+    dest: &Path,
+    format: TranscodeFormat,
+    gain_db: Option<f64>,
+) -> std::io::Result<()> {
     fs::remove_file(dest).ok();
     let mut tmp = PathBuf::from(dest);
     tmp.set_extension("tmp");
     let source_meta = fs::metadata(source)?;
+    let mut gain_input = None;
 
     let child = match format {
         TranscodeFormat::Opus { bitrate_kbps } => std::process::Command::new("ffmpeg")
@@ -92,57 +218,94 @@ fn transcode_file(source: &Path, dest: &Path, format: TranscodeFormat) -> std::i
             .arg("-f")
             .arg("opus")
             .arg(tmp.as_path())
-            .spawn()
-            .expect("failed to execute child"),
+            .spawn()?,
         TranscodeFormat::Aac { mode, bitrate_kbps } => {
+            let input = match gain_db {
+                Some(gain_db) => {
+                    let wav = tmp.with_extension("gain.wav");
+                    apply_gain_to_wav(source, &wav, gain_db)?;
+                    gain_input.insert(wav).clone()
+                }
+                None => source.to_path_buf(),
+            };
             let mut command = std::process::Command::new("afconvert");
             command.arg("-d").arg("aac").arg("-f").arg("m4af");
             for arg in aac_encoder_args(mode, bitrate_kbps) {
                 command.arg(arg);
             }
+            command.arg(input).arg(tmp.as_path()).spawn()?
+        }
+        TranscodeFormat::Mp3 => {
+            let mut command = std::process::Command::new("ffmpeg");
             command
+                .arg("-y")
+                .arg("-loglevel")
+                .arg("quiet")
+                .arg("-i")
                 .arg(source)
+                .arg("-map_metadata")
+                .arg("0")
+                .arg("-id3v2_version")
+                .arg("3")
+                .arg("-map")
+                .arg("0")
+                .arg("-map")
+                .arg("-0:1");
+            if let Some(gain_db) = gain_db {
+                command.arg("-af").arg(gain_filter(gain_db));
+            }
+            command
+                .arg("-q:a")
+                .arg("5")
+                .arg("-f")
+                .arg("mp3")
                 .arg(tmp.as_path())
-                .spawn()
-                .expect("failed to execute child")
+                .spawn()?
         }
-        TranscodeFormat::Mp3 => std::process::Command::new("ffmpeg")
-            .arg("-y")
-            .arg("-loglevel")
-            .arg("quiet")
-            .arg("-i")
-            .arg(source)
-            .arg("-map_metadata")
-            .arg("0")
-            .arg("-id3v2_version")
-            .arg("3")
-            .arg("-map")
-            .arg("0")
-            .arg("-map")
-            .arg("-0:1")
-            .arg("-q:a")
-            .arg("5")
-            .arg("-f")
-            .arg("mp3")
-            .arg(tmp.as_path())
-            .spawn()
-            .expect("failed to execute child"),
     };
-    child.wait_with_output().expect("failed to wait on child");
-    fs::create_dir_all(dest.parent().unwrap()).expect("Error making dest dir");
-    fs::rename(tmp.as_path(), dest).expect("Error moving file");
-    match format {
-        TranscodeFormat::Aac { mode, .. } => {
-            tag::copy(source, dest, mode == AacBitrateMode::Cbr).expect("Error copying tag")
+    let output = child.wait_with_output();
+    if let Some(wav) = gain_input {
+        fs::remove_file(wav).ok();
+    }
+    let status = output?.status;
+    if !status.success() {
+        fs::remove_file(&tmp).ok();
+        return Err(io::Error::other(format!("encoder exited with {}", status)));
+    }
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(tmp.as_path(), dest)?;
+    if let TranscodeFormat::Aac { mode, .. } = format {
+        tag::copy(source, dest, mode == AacBitrateMode::Cbr)
+            .map_err(|e| io::Error::other(format!("error copying tags: {:?}", e)))?
+    }
+    let gain_result = match (format, gain_db) {
+        // Opus players apply the header gain and R128 gains, not the
+        // ReplayGain tags ffmpeg copies over
+        (TranscodeFormat::Opus { .. }, gain_db) => {
+            if let Some(gain_db) = gain_db {
+                set_opus_output_gain(dest, gain_db)?;
+            }
+            tag::convert_opus_gains(dest, gain_db.unwrap_or(0.0))
         }
-        _ => (),
+        (_, Some(gain_db)) => tag::offset_gains(dest, gain_db),
+        (_, None) => Ok(()),
+    };
+    if let Err(e) = gain_result {
+        eprintln!(
+            "{} Error updating gain tags of {}: {:?}",
+            "⚠️".bright_yellow(),
+            dest.display(),
+            e
+        );
     }
 
     let mtime = FileTime::from_last_modification_time(&source_meta);
     filetime::set_file_mtime(dest, mtime)?;
     touch_parents(dest)?;
 
-    return Ok(());
+    Ok(())
 }
 
 fn extract_cover(source: &Path, dest: &Path) -> std::io::Result<()> {
@@ -158,11 +321,89 @@ fn extract_cover(source: &Path, dest: &Path) -> std::io::Result<()> {
         .spawn()
         .expect("Failed to execute");
     child.wait_with_output().expect("Failed to wait");
-    return Ok(());
+    Ok(())
+}
+
+/// Rewrites the M3U playlists below `source_root` into `dest_path`, pointing
+/// their entries at the transcoded files.
+fn transcode_playlists(
+    source_root: &Path,
+    dest_path: &Path,
+    dry_run: bool,
+    format: TranscodeFormat,
+    names: &mut DestNames,
+) {
+    let pattern = glob_pattern(source_root, &["m3u", "m3u8"]);
+    let mut playlists = globwalk::glob(&pattern)
+        .expect("glob error")
+        .filter_map(Result::ok)
+        .map(|entry| entry.into_path())
+        .collect::<Vec<_>>();
+    playlists.sort();
+
+    let dest_root = absolute_path(dest_path);
+    for source in playlists {
+        let relative = source.strip_prefix(source_root).expect("Not a prefix");
+        let contents = match fs::read_to_string(&source) {
+            Ok(contents) => contents,
+            Err(e) => {
+                eprintln!("skipping playlist {}: {}", relative.display(), e);
+                continue;
+            }
+        };
+        let playlist_dir = source.parent().unwrap_or(source_root);
+        // In a dry run nothing has been transcoded yet, so trust the sources
+        let remapped = remap_m3u(
+            &contents,
+            playlist_dir,
+            source_root,
+            &dest_root,
+            format,
+            names,
+            |target| target.exists() || dry_run,
+        );
+        for entry in &remapped.dropped {
+            eprintln!(
+                "playlist {}: dropping missing entry {}",
+                relative.display(),
+                entry
+            );
+        }
+
+        // Recorded like the tracks so `prune` can find the source of a
+        // sanitized playlist name
+        let target = dest_root.join(names.assign(relative));
+        if fs::read_to_string(&target).ok().as_deref() == Some(remapped.contents.as_str()) {
+            continue;
+        }
+        println!("playlist {}", relative.display());
+        if !dry_run {
+            let written = fs::create_dir_all(target.parent().unwrap_or(&dest_root))
+                .and_then(|_| fs::write(&target, &remapped.contents));
+            if let Err(e) = written {
+                eprintln!(
+                    "{} Error writing playlist {}: {}",
+                    "✗".red().bold(),
+                    target.display(),
+                    e
+                );
+            }
+        }
+    }
 }
 
-/// Transcodes supported source files into a destination tree while preserving metadata.
-pub fn transcode(source_paths: &[String], dest_dir: &str, dry_run: bool, format: TranscodeFormat) {
+/// Transcodes supported source files into a destination tree while preserving
+/// metadata, baking in track or album gain unless `gain` is `Off`. Names are
+/// made valid on the filesystem of `dest_policy` with its separator and
+/// maximum length, or with the ones recorded in the destination.
+pub fn transcode(
+    source_paths: &[String],
+    dest_dir: &str,
+    dry_run: bool,
+    format: TranscodeFormat,
+    gain: GainMode,
+    dest_policy: Option<&TidyPolicy>,
+) {
     let canonicals = source_paths
         .iter()
         .map(canonicalize_path)
@@ -174,93 +415,238 @@ pub fn transcode(source_paths: &[String], dest_dir: &str, dry_run: bool, format:
     }
 
     let dest_path = Path::new(dest_dir);
+    let mut names = DestNames::open(dest_path, dest_policy);
     for canonical_path in canonicals {
-        let pattern = glob_pattern(&canonical_path, &["flac", "opus"]);
-        let mut matches = globwalk::glob(&pattern)
-            .expect("glob error")
-            .filter_map(Result::ok)
-            .into_iter()
-            .collect::<Vec<DirEntry>>();
-        matches.sort_by(|a, b| a.path().cmp(b.path()));
-
-        // Collect all files to process
-        let files_to_process = matches;
-        let total = files_to_process.len() as u64;
-        let pb = Arc::new(ProgressBar::new(total));
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template(
-                    "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
-                )
-                .unwrap()
-                .progress_chars("█▉▊▋▌▍▎▏  "),
+        let sources = find_sources(&canonical_path);
+        transcode_sources(
+            &canonical_path,
+            sources,
+            dest_path,
+            dry_run,
+            format,
+            gain,
+            &mut names,
         );
-        pb.set_message("Transcoding...");
-
-        let pb_clone = pb.clone();
-        files_to_process.into_par_iter().for_each(|entry| {
-            let source_meta = modified_time(entry.path());
-            let relative = entry
-                .path()
-                .strip_prefix(&canonical_path)
-                .expect("Not a prefix");
-            let cover = dest_path
-                .join(relative)
-                .with_file_name("cover")
-                .with_extension("jpg");
-            let cover_meta = modified_time(&cover);
-            match (source_meta, cover_meta) {
-                (Some(source_time), Some(target_time)) if source_time > target_time => {
-                    extract_cover(entry.path(), &cover).ok();
-                }
-                (Some(_), None) => {
-                    extract_cover(entry.path(), &cover).ok();
-                }
-                _ => {
-                    // nothing
-                }
+    }
+    names.finish(dest_path, dry_run);
+}
+
+/// Transcodes only the source files at or below the given paths, relative to
+/// `source_root`, and refreshes the playlists of the root.
+pub fn transcode_paths<S: AsRef<Path>>(
+    source_root: &Path,
+    relative_paths: &[S],
+    dest_path: &Path,
+    dry_run: bool,
+    format: TranscodeFormat,
+    gain: GainMode,
+    dest_policy: Option<&TidyPolicy>,
+) {
+    let mut sources = Vec::new();
+    for relative in relative_paths {
+        let path = source_root.join(relative);
+        if path.is_dir() {
+            sources.extend(find_sources(&path));
+        } else if is_source_file(&path) && path.exists() {
+            sources.push(path);
+        }
+    }
+    sources.sort();
+    sources.dedup();
+    let mut names = DestNames::open(dest_path, dest_policy);
+    transcode_sources(
+        source_root,
+        sources,
+        dest_path,
+        dry_run,
+        format,
+        gain,
+        &mut names,
+    );
+    names.finish(dest_path, dry_run);
+}
+
+/// Returns whether `path` has one of the `SOURCE_EXTENSIONS`, in any case.
+pub fn is_source_file(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| {
+            SOURCE_EXTENSIONS
+                .iter()
+                .any(|source| extension.to_string_lossy().eq_ignore_ascii_case(source))
+        })
+        .unwrap_or(false)
+}
+
+fn find_sources(dir: &Path) -> Vec<PathBuf> {
+    let pattern = format!("**/*.{{{}}}", SOURCE_EXTENSIONS.join(","));
+    let mut matches = GlobWalkerBuilder::from_patterns(dir, &[pattern])
+        .case_insensitive(true)
+        .build()
+        .expect("glob error")
+        .filter_map(Result::ok)
+        .map(|entry| entry.into_path())
+        .collect::<Vec<_>>();
+    matches.sort();
+    matches
+}
+
+/// Returns whether `target` is missing or older than `source`.
+fn is_outdated(source: &Path, target: &Path) -> bool {
+    match (modified_time(source), modified_time(target)) {
+        (Some(source_time), Some(target_time)) => round_time(source_time) > round_time(target_time),
+        (Some(_), None) => true,
+        _ => false,
+    }
+}
+
+/// Looks up the gain to bake into each source: from its gain tags when it
+/// has them, otherwise by measuring it, or its whole album, with ffmpeg.
+fn resolve_gains(sources: &[&PathBuf], gain: GainMode) -> BTreeMap<PathBuf, f64> {
+    let album = match gain {
+        GainMode::Off => return BTreeMap::new(),
+        GainMode::Track => false,
+        GainMode::Album => true,
+    };
+    let mut gains = BTreeMap::new();
+    let mut untagged = BTreeMap::<PathBuf, Vec<PathBuf>>::new();
+    for source in sources {
+        match tag::read_gain(source, album) {
+            Some(gain_db) => {
+                gains.insert((*source).clone(), gain_db);
             }
-            let target = target_path(dest_path, relative, format);
-            let target_meta = modified_time(&target);
-            let file_display = relative.to_string_lossy();
-            match (source_meta, target_meta) {
-                (Some(source_time), Some(target_time))
-                    if round_time(source_time) > round_time(target_time) =>
-                {
-                    if dry_run {
-                        pb_clone.set_message(format!("{}", file_display));
-                        pb_clone.inc(1);
-                    } else {
-                        pb_clone.set_message(format!("{}", file_display));
-                        transcode_file(entry.path(), &target, format).expect("Error transcoding");
-                        pb_clone.inc(1);
-                    }
-                }
-                (Some(_), None) => {
-                    if dry_run {
-                        pb_clone.set_message(format!("{}", file_display));
-                        pb_clone.inc(1);
-                    } else {
-                        pb_clone.set_message(format!("{}", file_display));
-                        transcode_file(entry.path(), &target, format).expect("Error transcoding");
-                        pb_clone.inc(1);
+            None => untagged
+                .entry(album_key(source))
+                .or_default()
+                .push((*source).clone()),
+        }
+    }
+
+    for (dir, missing) in untagged {
+        // An album gain needs every track of the album, not just those
+        // being transcoded
+        let measured = if album {
+            album_files(&dir)
+        } else {
+            missing.clone()
+        };
+        println!(
+            "{} Measuring loudness of {} files in {}",
+            "🔊".bright_blue(),
+            measured.len().to_string().bright_yellow().bold(),
+            dir.display()
+        );
+        match measure_album(&measured) {
+            Ok(album_gains) => {
+                for (file, gains_of_file) in measured.iter().zip(album_gains) {
+                    if missing.contains(file) {
+                        let gain_db = if album {
+                            gains_of_file.album_gain
+                        } else {
+                            gains_of_file.track_gain
+                        };
+                        gains.insert(file.clone(), gain_db);
                     }
                 }
-                _ => {
-                    // nothing
-                }
             }
-        });
-        pb.finish_with_message("Done");
+            Err(e) => eprintln!(
+                "{} Skipping normalization of {}: {}",
+                "⚠️".bright_yellow(),
+                dir.display(),
+                e
+            ),
+        }
     }
+    gains
+}
+
+fn transcode_sources(
+    source_root: &Path,
+    sources: Vec<PathBuf>,
+    dest_path: &Path,
+    dry_run: bool,
+    format: TranscodeFormat,
+    gain: GainMode,
+    names: &mut DestNames,
+) {
+    let targets = sources
+        .iter()
+        .map(|source| {
+            let relative = source.strip_prefix(source_root).expect("Not a prefix");
+            let target = target_path(dest_path, &names.assign(relative), format);
+            (source.clone(), target)
+        })
+        .collect::<BTreeMap<_, _>>();
+    let gains = if dry_run {
+        BTreeMap::new()
+    } else {
+        let pending = sources
+            .iter()
+            .filter(|source| is_outdated(source, &targets[*source]))
+            .collect::<Vec<_>>();
+        resolve_gains(&pending, gain)
+    };
+
+    let total = sources.len() as u64;
+    let pb = Arc::new(ProgressBar::new(total));
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}")
+            .unwrap()
+            .progress_chars("█▉▊▋▌▍▎▏  "),
+    );
+    pb.set_message("Transcoding...");
+
+    let pb_clone = pb.clone();
+    sources.into_par_iter().for_each(|source| {
+        let source_meta = modified_time(&source);
+        let relative = source.strip_prefix(source_root).expect("Not a prefix");
+        let target = &targets[&source];
+        let cover = target.with_file_name("cover.jpg");
+        let cover_meta = modified_time(&cover);
+        match (source_meta, cover_meta) {
+            (Some(source_time), Some(target_time)) if source_time > target_time => {
+                extract_cover(&source, &cover).ok();
+            }
+            (Some(_), None) => {
+                extract_cover(&source, &cover).ok();
+            }
+            _ => {
+                // nothing
+            }
+        }
+        if is_outdated(&source, target) {
+            pb_clone.set_message(format!("{}", relative.to_string_lossy()));
+            if !dry_run {
+                if let Err(e) = transcode_file(&source, target, format, gains.get(&source).copied())
+                {
+                    eprintln!(
+                        "{} Error transcoding {}: {}",
+                        "✗".red().bold(),
+                        relative.display(),
+                        e
+                    );
+                }
+            }
+            pb_clone.inc(1);
+        }
+    });
+    pb.finish_with_message("Done");
+
+    transcode_playlists(source_root, dest_path, dry_run, format, names);
 }
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
     use std::path::Path;
     use std::time::{Duration, SystemTime};
 
-    use super::{aac_encoder_args, round_time, target_path, AacBitrateMode, TranscodeFormat};
+    use tempfile::tempdir;
+
+    use super::{
+        aac_encoder_args, find_sources, gain_filter, is_source_file, ogg_crc, round_time,
+        set_opus_output_gain, target_path, AacBitrateMode, TranscodeFormat,
+    };
 
     #[test]
     fn target_path_uses_expected_extension_for_each_format() {
@@ -288,6 +674,25 @@ mod tests {
         );
     }
 
+    #[test]
+    fn source_extensions_match_in_any_case() {
+        let dir = tempdir().expect("tempdir");
+        for name in ["a.flac", "b.FLAC", "c.Opus", "d.mp3"] {
+            fs::write(dir.path().join(name), b"audio").expect("write");
+        }
+
+        assert!(is_source_file(Path::new("Album/01.FLAC")));
+        assert!(!is_source_file(Path::new("Album/01.mp3")));
+        assert_eq!(
+            find_sources(dir.path()),
+            vec![
+                dir.path().join("a.flac"),
+                dir.path().join("b.FLAC"),
+                dir.path().join("c.Opus")
+            ]
+        );
+    }
+
     #[test]
     fn round_time_returns_epoch_milliseconds() {
         let time = SystemTime::UNIX_EPOCH + Duration::from_millis(1234);
@@ -315,4 +720,38 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn gain_filter_limits_only_boosts() {
+        assert_eq!(gain_filter(-6.5), "volume=-6.50dB");
+        assert_eq!(
+            gain_filter(3.0),
+            "volume=3.00dB,alimiter=limit=0.98:level=disabled"
+        );
+    }
+
+    #[test]
+    fn sets_opus_header_gain_and_page_checksum() {
+        assert_eq!(ogg_crc(b"123456789"), 0x89a1_897f);
+
+        // OpusHead: version 1, stereo, pre-skip 312, 48kHz, gain 0, family 0
+        let mut head = b"OpusHead".to_vec();
+        head.extend([1, 2, 0x38, 0x01, 0x80, 0xbb, 0, 0, 0, 0, 0]);
+        let mut page = b"OggS".to_vec();
+        page.extend([0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+        page.extend([0, 0, 0, 0, 1, head.len() as u8]);
+        page.extend(&head);
+        let dir = tempdir().expect("tempdir");
+        let path = dir.path().join("track.opus");
+        fs::write(&path, &page).expect("write");
+
+        set_opus_output_gain(&path, -3.5).expect("set gain");
+
+        let bytes = fs::read(&path).expect("read");
+        assert_eq!(i16::from_le_bytes([bytes[44], bytes[45]]), -896);
+        let mut unsummed = bytes.clone();
+        unsummed[22..26].copy_from_slice(&[0; 4]);
+        assert_eq!(bytes[22..26], ogg_crc(&unsummed).to_le_bytes());
+        assert!(set_opus_output_gain(&dir.path().join("missing.opus"), 1.0).is_err());
+    }
 }
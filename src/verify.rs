@@ -0,0 +1,426 @@
+use colored::*;
+use indicatif::{ProgressBar, ProgressStyle};
+use lofty::config::ParseOptions;
+use lofty::flac::FlacFile;
+use lofty::prelude::*;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+use crate::fs_utils::{canonicalize_path, glob_pattern, relative_path_string};
+use crate::index::{configure_thread_pool, update_stored_tracks, AUDIO_EXTENSIONS};
+use crate::query::load_library_tracks;
+use crate::schema::{FileStamp, Track};
+
+/// Options controlling a `verify` run.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct VerifyOptions {
+    /// Compare file checksums with those stored in the index and record
+    /// missing ones.
+    pub checksums: bool,
+    /// Report problems without recording checksums.
+    pub dry_run: bool,
+}
+
+/// A problem found with a single file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    /// ffmpeg reported errors while decoding the audio.
+    Decode(String),
+    /// The decoded audio does not match the MD5 in the FLAC STREAMINFO block.
+    Md5Mismatch { expected: String, actual: String },
+    /// The file contents changed although its modification time and size did not.
+    ChecksumMismatch { expected: String, actual: String },
+    /// The file could not be read to compute its checksum.
+    Unreadable(String),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Decode(error) => write!(f, "decode error: {}", error),
+            Problem::Md5Mismatch { expected, actual } => write!(
+                f,
+                "audio MD5 mismatch: STREAMINFO {}, decoded {}",
+                expected, actual
+            ),
+            Problem::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "contents changed without a modification: indexed checksum {}, now {}",
+                expected, actual
+            ),
+            Problem::Unreadable(error) => write!(f, "unreadable: {}", error),
+        }
+    }
+}
+
+/// How a file's checksum compares with its index entry.
+#[derive(Clone, Debug, PartialEq)]
+enum ChecksumCheck {
+    Match,
+    /// The entry is current but has no checksum yet.
+    Record,
+    /// The file changed since it was indexed, so the entry cannot be trusted.
+    Stale,
+    Unindexed,
+    Mismatch {
+        expected: String,
+    },
+}
+
+struct FileResult {
+    relative: String,
+    problems: Vec<Problem>,
+    checksum: Option<(ChecksumCheck, String)>,
+}
+
+fn check_checksum(track: Option<&Track>, stamp: &FileStamp, actual: &str) -> ChecksumCheck {
+    let track = match track {
+        Some(track) => track,
+        None => return ChecksumCheck::Unindexed,
+    };
+    if !track.stamp().is_unchanged(stamp, false) {
+        return ChecksumCheck::Stale;
+    }
+    match &track.checksum {
+        None => ChecksumCheck::Record,
+        Some(expected) if expected == actual => ChecksumCheck::Match,
+        Some(expected) => ChecksumCheck::Mismatch {
+            expected: expected.clone(),
+        },
+    }
+}
+
+/// Returns the SHA-256 of a file's contents as lowercase hex.
+fn file_checksum(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Returns the STREAMINFO MD5 and bit depth of a FLAC file, if it has one.
+fn flac_signature(path: &Path) -> Option<(String, u8)> {
+    let mut file = File::open(path).ok()?;
+    let flac = FlacFile::read_from(&mut file, ParseOptions::new().read_tags(false)).ok()?;
+    let properties = flac.properties();
+    // Encoders that skip the MD5 leave it zeroed
+    match properties.signature() {
+        0 => None,
+        signature => Some((format!("{:032x}", signature), properties.bit_depth())),
+    }
+}
+
+/// PCM codec producing the sample layout FLAC hashes for a bit depth.
+fn pcm_codec(bit_depth: u8) -> Option<&'static str> {
+    match bit_depth {
+        8 => Some("pcm_s8"),
+        16 => Some("pcm_s16le"),
+        24 => Some("pcm_s24le"),
+        32 => Some("pcm_s32le"),
+        _ => None,
+    }
+}
+
+fn parse_md5_output(stdout: &str) -> Option<String> {
+    stdout
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("MD5="))
+        .map(|md5| md5.to_lowercase())
+}
+
+/// Decodes the first audio stream of a file, returning the MD5 of the decoded
+/// samples when a PCM codec is given.
+fn decode(path: &Path, pcm_codec: Option<&str>) -> Result<Option<String>, String> {
+    let mut command = Command::new("ffmpeg");
+    command
+        .arg("-nostdin")
+        .arg("-v")
+        .arg("error")
+        .arg("-i")
+        .arg(path)
+        .arg("-map")
+        .arg("0:a:0");
+    match pcm_codec {
+        Some(codec) => command.arg("-c:a").arg(codec).arg("-f").arg("md5"),
+        None => command.arg("-f").arg("null"),
+    };
+    let output = command
+        .arg("-")
+        .output()
+        .map_err(|e| format!("failed to run ffmpeg: {}", e))?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() || !stderr.trim().is_empty() {
+        let message = stderr.trim().lines().next().unwrap_or_default();
+        return Err(if message.is_empty() {
+            format!("ffmpeg exited with {}", output.status)
+        } else {
+            message.to_owned()
+        });
+    }
+    Ok(parse_md5_output(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn verify_file(
+    path: &Path,
+    relative: String,
+    track: Option<&Track>,
+    options: VerifyOptions,
+) -> FileResult {
+    let mut problems = Vec::new();
+
+    let is_flac = path
+        .extension()
+        .map(|extension| extension.eq_ignore_ascii_case("flac"))
+        .unwrap_or(false);
+    let signature = if is_flac { flac_signature(path) } else { None };
+    let codec = signature.as_ref().and_then(|(_, bits)| pcm_codec(*bits));
+    match decode(path, codec) {
+        Ok(Some(actual)) => {
+            if let Some((expected, _)) = signature {
+                if expected != actual {
+                    problems.push(Problem::Md5Mismatch { expected, actual });
+                }
+            }
+        }
+        Ok(None) => {}
+        Err(e) => problems.push(Problem::Decode(e)),
+    }
+
+    let mut checksum = None;
+    if options.checksums {
+        match fs::metadata(path).and_then(|metadata| Ok((metadata, file_checksum(path)?))) {
+            Ok((metadata, actual)) => {
+                let check = check_checksum(track, &FileStamp::from_metadata(&metadata), &actual);
+                if let ChecksumCheck::Mismatch { expected } = &check {
+                    problems.push(Problem::ChecksumMismatch {
+                        expected: expected.clone(),
+                        actual: actual.clone(),
+                    });
+                }
+                checksum = Some((check, actual));
+            }
+            Err(e) => problems.push(Problem::Unreadable(e.to_string())),
+        }
+    }
+
+    FileResult {
+        relative,
+        problems,
+        checksum,
+    }
+}
+
+/// Decodes every audio file below `directory` and reports decode errors and
+/// FLAC MD5 mismatches, optionally checking file checksums against the index.
+pub fn verify(directory: &str, options: VerifyOptions) {
+    configure_thread_pool();
+    let root = canonicalize_path(directory);
+    println!(
+        "{} {}",
+        "🔎".bright_blue(),
+        format!("Verifying directory: {}", root.display())
+            .bright_white()
+            .bold()
+    );
+
+    if Command::new("ffmpeg").arg("-version").output().is_err() {
+        eprintln!(
+            "{} ffmpeg is required to decode files but could not be run",
+            "✗".red().bold()
+        );
+        return;
+    }
+
+    let tracks = if options.checksums {
+        match load_library_tracks(&root) {
+            Ok(tracks) => tracks,
+            Err(e) => {
+                eprintln!(
+                    "{} Error loading index: {} (run `loot index` first)",
+                    "✗".red().bold(),
+                    e
+                );
+                return;
+            }
+        }
+    } else {
+        BTreeMap::new()
+    };
+
+    let pattern = glob_pattern(&root, AUDIO_EXTENSIONS);
+    let mut files = globwalk::glob(&pattern)
+        .expect("glob error")
+        .filter_map(Result::ok)
+        .map(|entry| entry.into_path())
+        .collect::<Vec<_>>();
+    files.sort();
+
+    let pb = ProgressBar::new(files.len() as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}")
+            .unwrap()
+            .progress_chars("█▉▊▋▌▍▎▏  "),
+    );
+    pb.set_message("🔎 Decoding audio files...");
+    let results = files
+        .par_iter()
+        .filter_map(|path| {
+            let relative = relative_path_string(&root, path).ok()?;
+            let result = verify_file(path, relative.clone(), tracks.get(&relative), options);
+            pb.inc(1);
+            Some(result)
+        })
+        .collect::<Vec<_>>();
+    pb.finish_and_clear();
+
+    let mut problem_count = 0;
+    let mut stale = 0;
+    let mut to_record = BTreeMap::new();
+    for result in results {
+        for problem in &result.problems {
+            problem_count += 1;
+            println!("{} {}: {}", "✗".red().bold(), result.relative, problem);
+        }
+        match result.checksum {
+            Some((ChecksumCheck::Record, checksum)) => {
+                to_record.insert(result.relative, checksum);
+            }
+            Some((ChecksumCheck::Stale, _)) | Some((ChecksumCheck::Unindexed, _)) => stale += 1,
+            _ => {}
+        }
+    }
+
+    if stale > 0 {
+        println!(
+            "{} {} files changed or were added since the last index run; run `loot index`, then \
+             `loot verify --checksums` again to record their checksums",
+            "⚠️".bright_yellow(),
+            stale.to_string().bright_yellow().bold()
+        );
+    }
+    if !to_record.is_empty() {
+        if options.dry_run {
+            println!(
+                "{} {} Would record {} new checksums",
+                "🔍".bright_blue(),
+                "DRY RUN:".bright_yellow().bold(),
+                to_record.len().to_string().bright_yellow().bold()
+            );
+        } else {
+            let stored = update_stored_tracks(&root, &to_record, |track, checksum| {
+                track.checksum = Some(checksum.clone())
+            });
+            match stored {
+                Ok(path) => println!(
+                    "{} Recorded {} new checksums in {}",
+                    "💾".bright_green(),
+                    to_record.len().to_string().bright_yellow().bold(),
+                    path.display().to_string().bright_white()
+                ),
+                Err(e) => eprintln!("{} Error recording checksums: {}", "✗".red().bold(), e),
+            }
+        }
+    }
+
+    if problem_count == 0 {
+        println!(
+            "{} Verified {} files, no problems found",
+            "✓".bright_green().bold(),
+            files.len().to_string().bright_yellow().bold()
+        );
+    } else {
+        println!(
+            "{} Verified {} files, {} problems found",
+            "✗".red().bold(),
+            files.len().to_string().bright_yellow().bold(),
+            problem_count.to_string().bright_red().bold()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::{check_checksum, file_checksum, parse_md5_output, pcm_codec, ChecksumCheck};
+    use crate::schema::{FileStamp, Track};
+
+    fn stamp(mtime_ns: u64, size: u64) -> FileStamp {
+        FileStamp {
+            mtime: Some(mtime_ns / 1_000_000_000),
+            mtime_ns: Some(mtime_ns),
+            size: Some(size),
+            ..Default::default()
+        }
+    }
+
+    fn indexed(checksum: Option<&str>) -> Track {
+        let mut track = Track {
+            checksum: checksum.map(str::to_owned),
+            ..Default::default()
+        };
+        track.set_stamp(stamp(5_000_000_001, 10));
+        track
+    }
+
+    #[test]
+    fn hashes_file_contents() {
+        let dir = tempdir().expect("tempdir");
+        let path = dir.path().join("a.flac");
+        fs::write(&path, b"abc").expect("write");
+
+        assert_eq!(
+            file_checksum(&path).expect("checksum"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn flags_changed_contents_only_when_stamp_is_unchanged() {
+        let current = stamp(5_000_000_001, 10);
+        let track = indexed(Some("aaa"));
+
+        assert_eq!(
+            check_checksum(Some(&track), &current, "aaa"),
+            ChecksumCheck::Match
+        );
+        assert_eq!(
+            check_checksum(Some(&track), &current, "bbb"),
+            ChecksumCheck::Mismatch {
+                expected: "aaa".to_owned()
+            }
+        );
+        assert_eq!(
+            check_checksum(Some(&track), &stamp(6_000_000_000, 10), "bbb"),
+            ChecksumCheck::Stale
+        );
+        assert_eq!(
+            check_checksum(Some(&indexed(None)), &current, "bbb"),
+            ChecksumCheck::Record
+        );
+        assert_eq!(
+            check_checksum(None, &current, "bbb"),
+            ChecksumCheck::Unindexed
+        );
+    }
+
+    #[test]
+    fn reads_ffmpeg_md5_output() {
+        assert_eq!(
+            parse_md5_output("MD5=0123ABCD\n"),
+            Some("0123abcd".to_owned())
+        );
+        assert_eq!(parse_md5_output(""), None);
+        assert_eq!(pcm_codec(24), Some("pcm_s24le"));
+        assert_eq!(pcm_codec(20), None);
+    }
+}
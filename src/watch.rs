@@ -0,0 +1,370 @@
+use colored::*;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+use crate::fs_utils::canonicalize_path;
+use crate::index::{self, is_audio_file, IndexFormat, IndexOptions};
+use crate::prune;
+use crate::schema::FieldSet;
+use crate::text::{FsProfile, TidyPolicy};
+use crate::transcode::{self, AacBitrateMode, GainMode, TranscodeFormat};
+
+/// Quiet period after the last filesystem event before a batch is processed.
+const DEFAULT_DEBOUNCE_MS: u64 = 2000;
+
+/// Configuration file read by `loot watch`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchConfig {
+    /// Source roots to watch.
+    pub sources: Vec<PathBuf>,
+    /// Keep an index in each source root up to date.
+    #[serde(default)]
+    pub index: Option<WatchIndex>,
+    /// Keep a transcoded copy of the sources up to date.
+    #[serde(default)]
+    pub transcode: Option<WatchTranscode>,
+    /// Milliseconds without events to wait for before processing a batch.
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchIndex {
+    #[serde(default)]
+    pub fields: FieldSet,
+    #[serde(default)]
+    pub format: IndexFormat,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchTranscode {
+    /// Destination tree shared by all sources.
+    pub dest: PathBuf,
+    pub format: WatchCodec,
+    /// Target bitrate in kbps for AAC and Opus.
+    #[serde(default = "default_bitrate")]
+    pub bitrate: u32,
+    /// AAC bitrate mode.
+    #[serde(default)]
+    pub mode: AacBitrateMode,
+    /// Loudness normalization baked into the transcoded files.
+    #[serde(default)]
+    pub normalize: GainMode,
+    /// Filesystem of the destination, whose names must be valid on it.
+    #[serde(default)]
+    pub dest_fs: Option<FsProfile>,
+    /// Replaces the characters `dest_fs` forbids.
+    #[serde(default = "default_dest_separator")]
+    pub dest_separator: char,
+    /// Maximum length of each destination path component.
+    #[serde(default = "default_dest_max_length")]
+    pub dest_max_length: usize,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchCodec {
+    Aac,
+    Mp3,
+    Opus,
+}
+
+fn default_debounce_ms() -> u64 {
+    DEFAULT_DEBOUNCE_MS
+}
+
+fn default_bitrate() -> u32 {
+    128
+}
+
+fn default_dest_separator() -> char {
+    TidyPolicy::default().separator
+}
+
+fn default_dest_max_length() -> usize {
+    TidyPolicy::default().max_length
+}
+
+impl WatchConfig {
+    /// Reads a configuration file.
+    pub fn load(path: &Path) -> Result<WatchConfig, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        serde_json::from_str(&contents).map_err(|e| e.to_string())
+    }
+}
+
+impl WatchTranscode {
+    fn transcode_format(&self) -> TranscodeFormat {
+        match self.format {
+            WatchCodec::Aac => TranscodeFormat::Aac {
+                mode: self.mode,
+                bitrate_kbps: self.bitrate,
+            },
+            WatchCodec::Mp3 => TranscodeFormat::Mp3,
+            WatchCodec::Opus => TranscodeFormat::Opus {
+                bitrate_kbps: self.bitrate,
+            },
+        }
+    }
+
+    fn dest_policy(&self) -> Option<TidyPolicy> {
+        self.dest_fs.map(|filesystem| TidyPolicy {
+            filesystem,
+            separator: self.dest_separator,
+            max_length: self.dest_max_length,
+            ..Default::default()
+        })
+    }
+}
+
+/// Changed paths of one debounced batch, relative to each source root.
+type Batch = BTreeMap<usize, BTreeSet<String>>;
+
+/// Watches the configured sources and keeps their index and transcoded copy
+/// up to date until interrupted.
+pub fn watch(config_path: &Path, dry_run: bool) {
+    let config = match WatchConfig::load(config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!(
+                "{} Error reading {}: {}",
+                "✗".red().bold(),
+                config_path.display(),
+                e
+            );
+            return;
+        }
+    };
+    let roots = config
+        .sources
+        .iter()
+        .map(canonicalize_path)
+        .collect::<Vec<_>>();
+    let source_dirs = roots
+        .iter()
+        .map(|root| root.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+
+    // Catch up with changes made while nothing was watching
+    for root in &roots {
+        if let Some(index) = &config.index {
+            index::index_directory(&root.to_string_lossy(), index_options(index, dry_run));
+        }
+    }
+    if let Some(target) = &config.transcode {
+        let dest = target.dest.to_string_lossy();
+        prune::prune(&source_dirs, &dest, dry_run);
+        transcode::transcode(
+            &source_dirs,
+            &dest,
+            dry_run,
+            target.transcode_format(),
+            target.normalize,
+            target.dest_policy().as_ref(),
+        );
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(tx) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            eprintln!("{} Error starting watcher: {}", "✗".red().bold(), e);
+            return;
+        }
+    };
+    for root in &roots {
+        if let Err(e) = watcher.watch(root, RecursiveMode::Recursive) {
+            eprintln!(
+                "{} Error watching {}: {}",
+                "✗".red().bold(),
+                root.display(),
+                e
+            );
+            return;
+        }
+        println!("{} Watching {}", "👀".bright_blue(), root.display());
+    }
+
+    let debounce = Duration::from_millis(config.debounce_ms);
+    while let Ok(event) = rx.recv() {
+        let mut batch = Batch::new();
+        add_event(&mut batch, &roots, event);
+        // Wait for a quiet period so bulk copies are handled as one batch
+        loop {
+            match rx.recv_timeout(debounce) {
+                Ok(event) => add_event(&mut batch, &roots, event),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+        for (root_index, paths) in batch {
+            process_batch(&config, &roots[root_index], &source_dirs, &paths, dry_run);
+        }
+    }
+}
+
+fn index_options(index: &WatchIndex, dry_run: bool) -> IndexOptions {
+    IndexOptions {
+        dry_run,
+        fields: index.fields,
+        format: index.format,
+        ..Default::default()
+    }
+}
+
+fn add_event(batch: &mut Batch, roots: &[PathBuf], event: notify::Result<Event>) {
+    let event = match event {
+        Ok(event) => event,
+        Err(e) => {
+            eprintln!("{} Watch error: {}", "⚠️".bright_yellow(), e);
+            return;
+        }
+    };
+    // Reads, including our own while transcoding, change nothing
+    if let EventKind::Access(_) = event.kind {
+        return;
+    }
+    for path in &event.paths {
+        if let Some((root_index, relative)) = changed_path(roots, path) {
+            batch.entry(root_index).or_default().insert(relative);
+        }
+    }
+}
+
+/// Maps an event path to its source root and relative path, ignoring files
+/// that cannot affect the index or the transcoded copy.
+fn changed_path(roots: &[PathBuf], path: &Path) -> Option<(usize, String)> {
+    let (root_index, relative) = roots
+        .iter()
+        .enumerate()
+        .find_map(|(root_index, root)| Some((root_index, path.strip_prefix(root).ok()?)))?;
+    if relative.as_os_str().is_empty() {
+        return None;
+    }
+    // Hidden and temporary files, including the index's own temporary files
+    let hidden = relative.components().any(|component| match component {
+        Component::Normal(name) => name.to_string_lossy().starts_with('.'),
+        _ => false,
+    });
+    // The index and its journal live in the source root
+    let index_file =
+        relative.components().count() == 1 && relative.to_string_lossy().starts_with("index.");
+    if hidden || index_file {
+        return None;
+    }
+    // Existing files other than audio and playlists are irrelevant; paths
+    // that are gone or directories may hide removed or moved audio files
+    let playlist = matches!(
+        path.extension().and_then(|extension| extension.to_str()),
+        Some("m3u") | Some("m3u8")
+    );
+    if path.is_file() && !is_audio_file(path) && !playlist {
+        return None;
+    }
+    Some((root_index, relative.to_string_lossy().into_owned()))
+}
+
+fn process_batch(
+    config: &WatchConfig,
+    root: &Path,
+    source_dirs: &[String],
+    paths: &BTreeSet<String>,
+    dry_run: bool,
+) {
+    println!(
+        "{} {} changed paths in {}",
+        "🔄".bright_blue(),
+        paths.len().to_string().bright_yellow().bold(),
+        root.display()
+    );
+    if let Some(index) = &config.index {
+        index::update_index(root, paths, index_options(index, dry_run));
+    }
+    if let Some(target) = &config.transcode {
+        let paths = paths.iter().collect::<Vec<_>>();
+        prune::prune_paths(source_dirs, &target.dest, &paths, dry_run);
+        transcode::transcode_paths(
+            root,
+            &paths,
+            &target.dest,
+            dry_run,
+            target.transcode_format(),
+            target.normalize,
+            target.dest_policy().as_ref(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use tempfile::tempdir;
+
+    use super::{changed_path, WatchCodec, WatchConfig, DEFAULT_DEBOUNCE_MS};
+    use crate::index::IndexFormat;
+    use crate::transcode::{AacBitrateMode, GainMode};
+
+    #[test]
+    fn parses_config_with_defaults() {
+        let config: WatchConfig = serde_json::from_str(
+            r#"{
+                "sources": ["/music/a"],
+                "index": {"format": "sqlite"},
+                "transcode": {"dest": "/music/opus", "format": "opus"}
+            }"#,
+        )
+        .expect("parse");
+
+        assert_eq!(config.sources, vec![PathBuf::from("/music/a")]);
+        assert_eq!(config.index.expect("index").format, IndexFormat::Sqlite);
+        let transcode = config.transcode.expect("transcode");
+        assert_eq!(transcode.format, WatchCodec::Opus);
+        assert_eq!(transcode.bitrate, 128);
+        assert_eq!(transcode.mode, AacBitrateMode::Vbr);
+        assert_eq!(transcode.normalize, GainMode::Off);
+        assert_eq!(transcode.dest_fs, None);
+        assert_eq!(transcode.dest_separator, '_');
+        assert_eq!(transcode.dest_max_length, 255);
+        assert_eq!(config.debounce_ms, DEFAULT_DEBOUNCE_MS);
+    }
+
+    #[test]
+    fn ignores_index_hidden_and_unrelated_files() {
+        let dir = tempdir().expect("tempdir");
+        let root = dir.path().to_path_buf();
+        for name in ["cover.jpg", "01.flac", "mix.m3u8", "index.json"] {
+            fs::write(root.join(name), b"data").expect("write");
+        }
+        let roots = vec![root.clone()];
+
+        assert_eq!(
+            changed_path(&roots, &root.join("01.flac")),
+            Some((0, "01.flac".to_owned()))
+        );
+        assert_eq!(
+            changed_path(&roots, &root.join("mix.m3u8")),
+            Some((0, "mix.m3u8".to_owned()))
+        );
+        assert_eq!(
+            changed_path(&roots, &root.join("Gone/Album")),
+            Some((0, "Gone/Album".to_owned()))
+        );
+        assert_eq!(changed_path(&roots, &root.join("cover.jpg")), None);
+        assert_eq!(changed_path(&roots, &root.join("index.json")), None);
+        assert_eq!(changed_path(&roots, &root.join(".tmpXYZ")), None);
+        assert_eq!(
+            changed_path(&roots, &PathBuf::from("/elsewhere/01.flac")),
+            None
+        );
+    }
+}